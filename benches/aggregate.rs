@@ -0,0 +1,44 @@
+//! Folding a huge file list into the aggregate one file at a time versus
+//! through one buffer (`HashOptions::aggregate_via_buffer`).
+
+mod common;
+
+use b3hash::{aggregate_hash_with_options, HashOptions, HashedFile};
+use common::bench;
+
+fn main() {
+    for count in [1_000, 100_000, 1_000_000] {
+        let files: Vec<HashedFile> = (0..count)
+            .map(|i| {
+                let path = format!("dir{:04}/file{:07}.txt", i / 1_000, i);
+                HashedFile {
+                    hash: blake3::hash(path.as_bytes()),
+                    path,
+                    size: i,
+                    modified: None,
+                    size_only: false,
+                }
+            })
+            .collect();
+        let options = |aggregate_via_buffer| HashOptions {
+            aggregate_via_buffer,
+            ..Default::default()
+        };
+        let (incremental, buffered) = (options(false), options(true));
+        assert_eq!(
+            aggregate_hash_with_options(&files, &incremental),
+            aggregate_hash_with_options(&files, &buffered)
+        );
+        let incremental = bench(&format!("{} files, incremental", count), 10, || {
+            aggregate_hash_with_options(&files, &incremental)
+        });
+        let buffered = bench(&format!("{} files, via buffer", count), 10, || {
+            aggregate_hash_with_options(&files, &buffered)
+        });
+        println!(
+            "{:<40} {:>12.2}x",
+            "speedup",
+            incremental.as_secs_f64() / buffered.as_secs_f64()
+        );
+    }
+}
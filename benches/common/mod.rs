@@ -0,0 +1,18 @@
+//! Just enough of a harness for timing things with only std.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+/// Runs `f` `runs` times and prints (then returns) the fastest run.
+pub fn bench<R>(name: &str, runs: usize, mut f: impl FnMut() -> R) -> Duration {
+    let best = (0..runs)
+        .map(|_| {
+            let start = Instant::now();
+            black_box(f());
+            start.elapsed()
+        })
+        .min()
+        .expect("at least one run");
+    println!("{:<40} {:>12.3?}", name, best);
+    best
+}
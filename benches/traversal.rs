@@ -0,0 +1,38 @@
+//! Walking a huge tree of empty files (so there's next to nothing to hash)
+//! on one thread versus across the rayon pool (`HashOptions::parallel_traversal`).
+
+mod common;
+
+use b3hash::{hash_directory_with_options, HashOptions};
+use common::bench;
+
+fn main() {
+    let tree = tempfile::tempdir().expect("tempdir");
+    // 400 folders, 4 deep, of 50 files each.
+    for dir in 0..400 {
+        let dir_path =
+            tree.path()
+                .join(format!("a{}/b{}/c{}/d{}", dir % 5, dir % 20, dir % 80, dir));
+        std::fs::create_dir_all(&dir_path).expect("create_dir_all");
+        for file in 0..50 {
+            std::fs::write(dir_path.join(format!("f{}", file)), "").expect("write");
+        }
+    }
+    let tree_path = tree.path().to_str().expect("utf8 tempdir");
+    println!("threads: {}", rayon::current_num_threads());
+    let walk = |parallel_traversal| {
+        let options = HashOptions {
+            parallel_traversal,
+            ..Default::default()
+        };
+        move || hash_directory_with_options(tree_path, &options).expect("hash")
+    };
+    assert_eq!(walk(false)().hash, walk(true)().hash);
+    let sequential = bench("20000 files, sequential walk", 10, walk(false));
+    let parallel = bench("20000 files, parallel walk", 10, walk(true));
+    println!(
+        "{:<40} {:>12.2}x",
+        "speedup",
+        sequential.as_secs_f64() / parallel.as_secs_f64()
+    );
+}
@@ -0,0 +1,39 @@
+//! Validating a hashfile of many tiny files with records parsed right before
+//! hashing each file versus all up front (`HashOptions::two_phase_validation`).
+
+mod common;
+
+use b3hash::{create_hashfile, validate_hashfile_with_options, HashOptions};
+use common::bench;
+
+fn main() {
+    let scratch = tempfile::tempdir().expect("tempdir");
+    // Hashfiles are always read from (and written to) the current directory.
+    std::env::set_current_dir(scratch.path()).expect("chdir");
+    for dir in 0..200 {
+        let dir_path = format!("tree/d{}", dir);
+        std::fs::create_dir_all(&dir_path).expect("create_dir_all");
+        for file in 0..250 {
+            std::fs::write(format!("{}/f{}", dir_path, file), file.to_string()).expect("write");
+        }
+    }
+    create_hashfile("tree").expect("create_hashfile");
+    println!("threads: {}", rayon::current_num_threads());
+    let validate = |two_phase_validation| {
+        let options = HashOptions {
+            two_phase_validation,
+            ..Default::default()
+        };
+        move || {
+            let report = validate_hashfile_with_options("tree", &options).expect("validate");
+            assert!(report.is_valid());
+        }
+    };
+    let interleaved = bench("50000 files, interleaved", 10, validate(false));
+    let two_phase = bench("50000 files, two phase", 10, validate(true));
+    println!(
+        "{:<40} {:>12.2}x",
+        "speedup",
+        interleaved.as_secs_f64() / two_phase.as_secs_f64()
+    );
+}
@@ -0,0 +1,128 @@
+use std::num::NonZeroU64;
+use std::sync::{Condvar, Mutex, PoisonError};
+
+/// Files smaller than this are read into a small buffer instead of being
+/// memory mapped (roughly where blake3 draws the line), so they barely
+/// touch the page cache and never need to wait on the budget.
+const SMALL_FILE_LEN: u64 = 16 * 1024;
+
+/// Caps the combined size of the files being hashed at any one time,
+/// shared by every rayon worker hashing the same directory.
+///
+/// This is a counting semaphore where each file takes as many permits as
+/// it has bytes. A file larger than the whole budget is still let through
+/// once nothing else is in flight, since otherwise it'd wait forever.
+pub struct MemoryBudget {
+    limit: NonZeroU64,
+    in_flight: Mutex<u64>,
+    released: Condvar,
+}
+
+/// Holds a file's share of a `MemoryBudget` until dropped.
+pub struct BudgetGuard<'a> {
+    budget: &'a MemoryBudget,
+    bytes: u64,
+}
+
+impl MemoryBudget {
+    #[inline]
+    pub fn new(limit: NonZeroU64) -> Self {
+        Self {
+            limit,
+            in_flight: Mutex::new(0),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Reserves `bytes` of the budget, blocking the current thread
+    /// until enough of it is free.
+    pub fn acquire(&self, bytes: u64) -> BudgetGuard<'_> {
+        let bytes = match bytes < SMALL_FILE_LEN {
+            true => 0,
+            false => bytes,
+        };
+        if bytes > 0 {
+            let mut in_flight = self
+                .in_flight
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            while *in_flight > 0 && in_flight.saturating_add(bytes) > self.limit.get() {
+                in_flight = self
+                    .released
+                    .wait(in_flight)
+                    .unwrap_or_else(PoisonError::into_inner);
+            }
+            *in_flight += bytes;
+        }
+        BudgetGuard {
+            budget: self,
+            bytes,
+        }
+    }
+}
+
+impl Drop for BudgetGuard<'_> {
+    #[inline]
+    fn drop(&mut self) {
+        if self.bytes > 0 {
+            *self
+                .budget
+                .in_flight
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner) -= self.bytes;
+            self.budget.released.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::time::Duration;
+
+    #[test]
+    fn bytes_in_flight_never_exceed_the_limit() {
+        const LIMIT: u64 = 100 * 1024;
+        let budget = MemoryBudget::new(NonZeroU64::new(LIMIT).unwrap());
+        // Tracked separately from the budget, by whoever holds a share of it.
+        let held = AtomicU64::new(0);
+        let peak = AtomicU64::new(0);
+        let oversized_alone = AtomicU64::new(0);
+        std::thread::scope(|scope| {
+            for thread in 0..8u64 {
+                let (budget, held, peak, oversized_alone) =
+                    (&budget, &held, &peak, &oversized_alone);
+                scope.spawn(move || {
+                    for i in 0..20 {
+                        let bytes = match (thread, i) {
+                            // Larger than the whole budget, so it has to go alone.
+                            (0, 10) => 2 * LIMIT,
+                            _ => SMALL_FILE_LEN + (thread * 7 + i * 13) % 5 * 8 * 1024,
+                        };
+                        let _guard = budget.acquire(bytes);
+                        let now = held.fetch_add(bytes, Ordering::SeqCst) + bytes;
+                        if bytes > LIMIT {
+                            assert_eq!(now, bytes);
+                            oversized_alone.fetch_add(1, Ordering::SeqCst);
+                        } else {
+                            assert!(now <= LIMIT, "{} bytes in flight", now);
+                        }
+                        peak.fetch_max(now, Ordering::SeqCst);
+                        std::thread::sleep(Duration::from_millis(1));
+                        held.fetch_sub(bytes, Ordering::SeqCst);
+                    }
+                });
+            }
+        });
+        assert_eq!(oversized_alone.load(Ordering::SeqCst), 1);
+        // Files really were in flight together, so the limit was what held them back.
+        let peak = peak.load(Ordering::SeqCst);
+        assert!(peak > SMALL_FILE_LEN + 4 * 8 * 1024, "{}", peak);
+
+        // Small files never wait on (or count towards) the budget at all.
+        let _full = budget.acquire(LIMIT);
+        let small = budget.acquire(SMALL_FILE_LEN - 1);
+        assert_eq!(small.bytes, 0);
+    }
+}
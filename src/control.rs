@@ -0,0 +1,168 @@
+use crate::IOResult;
+use std::io::{Error, ErrorKind};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Condvar, Mutex, PoisonError};
+
+const RUNNING: u8 = 0;
+const PAUSED: u8 = 1;
+const CANCELLED: u8 = 2;
+
+/// Lets another thread (e.g. a GUI) pause, resume or cancel hashing
+/// while it's running, see `HashOptions::control`.
+///
+/// Every worker checks in before starting on each file, so a file that's
+/// already being hashed always finishes first. While paused, workers block
+/// until resumed. Once cancelled, hashing fails with
+/// `ErrorKind::Interrupted`, and resuming doesn't undo that.
+#[derive(Default)]
+pub struct HashControl {
+    state: AtomicU8,
+    // Only for waiting on, the state itself is always read atomically
+    // so running workers never have to take the lock.
+    lock: Mutex<()>,
+    changed: Condvar,
+}
+
+impl HashControl {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Has workers stop before their next file until `resume` is called.
+    #[inline]
+    pub fn pause(&self) {
+        let _ = self
+            .state
+            .compare_exchange(RUNNING, PAUSED, Ordering::Relaxed, Ordering::Relaxed);
+    }
+
+    /// Lets paused workers carry on.
+    #[inline]
+    pub fn resume(&self) {
+        let _guard = self.lock.lock().unwrap_or_else(PoisonError::into_inner);
+        if self
+            .state
+            .compare_exchange(PAUSED, RUNNING, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+        {
+            self.changed.notify_all();
+        }
+    }
+
+    /// Has workers give up before their next file, including paused ones.
+    #[inline]
+    pub fn cancel(&self) {
+        let _guard = self.lock.lock().unwrap_or_else(PoisonError::into_inner);
+        self.state.store(CANCELLED, Ordering::Relaxed);
+        self.changed.notify_all();
+    }
+
+    #[inline]
+    pub fn is_paused(&self) -> bool {
+        self.state.load(Ordering::Relaxed) == PAUSED
+    }
+
+    #[inline]
+    pub fn is_cancelled(&self) -> bool {
+        self.state.load(Ordering::Relaxed) == CANCELLED
+    }
+
+    /// Blocks the current thread while paused, failing once cancelled.
+    pub(crate) fn checkpoint(&self) -> IOResult<()> {
+        if self.is_paused() {
+            let mut guard = self.lock.lock().unwrap_or_else(PoisonError::into_inner);
+            // Re-checked with the lock held, so a resume
+            // in between can't be missed.
+            while self.is_paused() {
+                guard = self
+                    .changed
+                    .wait(guard)
+                    .unwrap_or_else(PoisonError::into_inner);
+            }
+        }
+        match self.is_cancelled() {
+            true => Err(Error::new(ErrorKind::Interrupted, "Hashing was cancelled.")),
+            false => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::Scratch;
+    use crate::{hash_directory_with_options, HashOptions};
+    use std::sync::atomic::AtomicU64;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    const FILES: u64 = 64;
+
+    /// Hashes a tree of `FILES` files in the background, pausing it after
+    /// the first one, and hands `while_paused` the control and the number
+    /// of files hashed so far.
+    fn hash_paused<F>(while_paused: F) -> (IOResult<u64>, u64)
+    where
+        F: FnOnce(&HashControl, &AtomicU64),
+    {
+        let scratch = Scratch::new();
+        for i in 0..FILES {
+            scratch.write(&format!("tree/{:02}.txt", i), i.to_string());
+        }
+        let control = Arc::new(HashControl::new());
+        let files_done = Arc::new(AtomicU64::new(0));
+        let options = HashOptions {
+            control: Some(Arc::clone(&control)),
+            progress: Some(Box::new({
+                let control = Arc::clone(&control);
+                let files_done = Arc::clone(&files_done);
+                move |_| {
+                    if files_done.fetch_add(1, Ordering::SeqCst) == 0 {
+                        control.pause();
+                    }
+                }
+            })),
+            ..Default::default()
+        };
+        let res = std::thread::scope(|scope| {
+            let hashing = scope.spawn(|| hash_directory_with_options("tree", &options));
+            while !control.is_paused() {
+                std::thread::yield_now();
+            }
+            while_paused(&control, &files_done);
+            hashing.join().unwrap()
+        });
+        let files_done = files_done.load(Ordering::SeqCst);
+        (res.map(|res| res.len() as u64), files_done)
+    }
+
+    #[test]
+    fn pausing_stops_hashing_until_resumed() {
+        let (res, files_done) = hash_paused(|control, files_done| {
+            // Files already being hashed when it paused still finish.
+            std::thread::sleep(Duration::from_millis(50));
+            let paused_at = files_done.load(Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(100));
+            assert_eq!(files_done.load(Ordering::SeqCst), paused_at);
+            assert!(paused_at <= rayon::current_num_threads() as u64);
+            control.resume();
+            assert!(!control.is_paused());
+        });
+        assert_eq!(res.unwrap(), FILES);
+        assert_eq!(files_done, FILES);
+    }
+
+    #[test]
+    fn cancelling_a_paused_hash_fails_it() {
+        let (res, files_done) = hash_paused(|control, _| {
+            control.cancel();
+            // Too late to undo it.
+            control.resume();
+            control.pause();
+            assert!(control.is_cancelled());
+        });
+        assert_eq!(res.unwrap_err().kind(), ErrorKind::Interrupted);
+        assert!(files_done < FILES);
+    }
+}
@@ -0,0 +1,203 @@
+use crate::options::HashOptions;
+use crate::report::{DirDiff, ValidationReport};
+use crate::types::{HashedDirectory, HashedFile};
+use crate::util::{hash_eq, hash_files, oi_vei};
+use crate::IOResult;
+use camino::Utf8Path;
+use core::cmp::Ordering;
+
+/// Relative paths of the files that differ between an old and a new set
+/// of `HashedFile`s, each in path order.
+pub struct FileDiff<'a> {
+    /// Present in both, but with different hashes.
+    pub changed: Vec<&'a str>,
+    /// Only present in the old files.
+    pub missing: Vec<&'a str>,
+    /// Only present in the new files.
+    pub added: Vec<&'a str>,
+}
+
+/// Diffs `old` against `new`, both of which must be sorted by path.
+///
+/// Since both sides are sorted this is a simple merge-join,
+/// so it's O(n + m) and never needs to build a map.
+pub fn diff_files<'a>(old: &'a [HashedFile], new: &'a [HashedFile]) -> FileDiff<'a> {
+    let mut diff = FileDiff {
+        changed: Vec::new(),
+        missing: Vec::new(),
+        added: Vec::new(),
+    };
+    let (mut old, mut new) = (old.iter().peekable(), new.iter().peekable());
+    loop {
+        match (old.peek(), new.peek()) {
+            (Some(o), Some(n)) => match o.path.cmp(&n.path) {
+                Ordering::Less => {
+                    diff.missing.push(&o.path);
+                    old.next();
+                }
+                Ordering::Greater => {
+                    diff.added.push(&n.path);
+                    new.next();
+                }
+                Ordering::Equal => {
+                    if !hash_eq(&o.hash, &n.hash) {
+                        diff.changed.push(&o.path);
+                    }
+                    old.next();
+                    new.next();
+                }
+            },
+            (Some(o), None) => {
+                diff.missing.push(&o.path);
+                old.next();
+            }
+            (None, Some(n)) => {
+                diff.added.push(&n.path);
+                new.next();
+            }
+            (None, None) => return diff,
+        }
+    }
+}
+
+/// Diffs files `a` against files `b` (see `diff_files`),
+/// with owned paths to outlive both.
+pub fn dir_diff(a: &[HashedFile], b: &[HashedFile]) -> DirDiff {
+    let diff = diff_files(a, b);
+    let owned = |paths: Vec<&str>| paths.into_iter().map(str::to_string).collect();
+    DirDiff {
+        only_in_a: owned(diff.missing),
+        only_in_b: owned(diff.added),
+        differing: owned(diff.changed),
+    }
+}
+
+impl HashedDirectory {
+    /// Diffs these results against `other`, with these as the first
+    /// directory and `other` as the second, e.g. an old and a new
+    /// `hash_directory` of the same directory.
+    ///
+    /// Both file lists are already sorted by path, so this is a single
+    /// merge-join over them, without building any maps.
+    #[inline]
+    pub fn diff(&self, other: &HashedDirectory) -> DirDiff {
+        dir_diff(&self.files, &other.files)
+    }
+
+    /// Re-hashes the live directory at `dir_path` and validates it against
+    /// these (presumably older) results, without needing a hashfile.
+    ///
+    /// Paths in the report are joined onto `dir_path`,
+    /// just like when validating against a hashfile.
+    pub fn validate_live(&self, dir_path: &str) -> IOResult<ValidationReport> {
+        let (live_files, _) = hash_files(dir_path, &HashOptions::default())?;
+        let diff = diff_files(&self.files, &live_files);
+        let dir_path = oi_vei(dir_path);
+        let join = |path: &str| Utf8Path::new(&dir_path).join(path).into_string();
+        let missing: Vec<String> = diff.missing.iter().map(|path| join(path)).collect();
+        let mut failed_files: Vec<String> = diff.changed.iter().map(|path| join(path)).collect();
+        failed_files.extend(missing.iter().cloned());
+        failed_files.sort_unstable();
+        Ok(ValidationReport {
+            failed_files,
+            missing,
+            added: diff.added.iter().map(|path| join(path)).collect(),
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::Scratch;
+    use std::collections::HashMap;
+
+    #[test]
+    fn validate_live_against_stale_results() {
+        let scratch = Scratch::new();
+        scratch.write("tree/kept.txt", "kept");
+        scratch.write("tree/sub/edited.txt", "before");
+        scratch.write("tree/removed.txt", "removed");
+        let stale = crate::hash_directory("tree").unwrap();
+        assert!(stale.validate_live("tree").unwrap().is_valid());
+
+        scratch.write("tree/sub/edited.txt", "after");
+        scratch.write("tree/added.txt", "added");
+        std::fs::remove_file("tree/removed.txt").unwrap();
+        let report = stale.validate_live("tree").unwrap();
+        assert_eq!(
+            report.failed_files,
+            ["tree/removed.txt", "tree/sub/edited.txt"]
+        );
+        assert_eq!(report.added, ["tree/added.txt"]);
+        // Exactly what validating against a hashfile of the stale results says.
+        std::fs::remove_file("tree/added.txt").unwrap();
+        scratch.write("tree/sub/edited.txt", "before");
+        scratch.write("tree/removed.txt", "removed");
+        crate::create_hashfile("tree").unwrap();
+        scratch.write("tree/sub/edited.txt", "after");
+        scratch.write("tree/added.txt", "added");
+        std::fs::remove_file("tree/removed.txt").unwrap();
+        let from_hashfile =
+            crate::validate_hashfile_with_options("tree", &Default::default()).unwrap();
+        assert_eq!(from_hashfile.failed_files, report.failed_files);
+        assert_eq!(from_hashfile.added, report.added);
+    }
+
+    #[test]
+    fn diff_matches_a_hash_map_reference() {
+        let scratch = Scratch::new();
+        for (path, contents) in [
+            ("a/same.txt", "same"),
+            ("a/edited.txt", "before"),
+            ("a/only_a.txt", "a"),
+            ("a/sub/deep.txt", "deep"),
+            ("a/z_last.txt", "last"),
+            ("b/same.txt", "same"),
+            ("b/edited.txt", "after!"),
+            ("b/only_b.txt", "b"),
+            ("b/sub/deep.txt", "deeper"),
+            ("b/sub/new/deepest.txt", "deepest"),
+            ("b/0_first.txt", "first"),
+        ] {
+            scratch.write(path, contents);
+        }
+        let a = crate::hash_directory("a").unwrap();
+        let b = crate::hash_directory("b").unwrap();
+
+        let map = |dir: &HashedDirectory| -> HashMap<String, blake3::Hash> {
+            dir.files
+                .iter()
+                .map(|file| (file.path.clone(), file.hash))
+                .collect()
+        };
+        let (a_map, b_map) = (map(&a), map(&b));
+        let sorted = |mut paths: Vec<String>| {
+            paths.sort_unstable();
+            paths
+        };
+        let only_in = |x: &HashMap<_, _>, y: &HashMap<_, _>| {
+            sorted(
+                x.keys()
+                    .filter(|path| !y.contains_key(*path))
+                    .cloned()
+                    .collect(),
+            )
+        };
+        let differing = sorted(
+            a_map
+                .iter()
+                .filter(|(path, hash)| b_map.get(*path).is_some_and(|other| other != *hash))
+                .map(|(path, _)| path.clone())
+                .collect(),
+        );
+
+        let diff = a.diff(&b);
+        assert_eq!(diff.only_in_a, only_in(&a_map, &b_map));
+        assert_eq!(diff.only_in_b, only_in(&b_map, &a_map));
+        assert_eq!(diff.differing, differing);
+        assert_eq!(diff.only_in_a, ["only_a.txt", "z_last.txt"]);
+        assert_eq!(diff.differing, ["edited.txt", "sub/deep.txt"]);
+    }
+}
@@ -1,56 +1,475 @@
-use crate::IOResult;
+use crate::{HashOptions, IOResult};
 use camino::{Utf8Path, Utf8PathBuf};
+use rayon::Scope;
+use std::collections::HashSet;
+use std::io::Error;
+use std::sync::{Mutex, PoisonError};
 
 /// Simultaneously store all visible files in LLVM 19 without reallocating.
 const STARTING_CAP_FILES: usize = 1 << 18;
 /// Simultaneously store all visible folders in LLVM 19 without reallocating.
 const STARTING_CAP_FOLDERS: usize = 1 << 14;
+/// Roughly how many files LLVM 19 has per folder, for scaling
+/// the folder capacity along with a caller's file capacity hint.
+const FILES_PER_FOLDER: usize = STARTING_CAP_FILES / STARTING_CAP_FOLDERS;
+
+/// Paths of all visible entries beneath a directory
+/// that aren't folders, split by their type.
+#[derive(Default)]
+pub struct Entries {
+    /// Regular files.
+    pub files: Vec<Utf8PathBuf>,
+    /// Special files: FIFOs, sockets, device files and the like.
+    pub special: Vec<Utf8PathBuf>,
+    /// Every folder visited, including the root itself.
+    pub folders: Vec<Utf8PathBuf>,
+    /// Symlinks, which are never followed while traversing.
+    pub symlinks: Vec<Utf8PathBuf>,
+    /// Folders that couldn't be read, and so were left out along with
+    /// everything beneath them. Always empty unless skipping was asked for.
+    pub unreadable: Vec<Utf8PathBuf>,
+    /// Number of hidden entries that were skipped. Only those directly
+    /// inside a visited folder count, since hidden folders aren't entered.
+    pub hidden: usize,
+}
+
+/// Which entries get skipped while traversing, beyond the hidden ones.
+///
+/// Precedence, from highest to lowest: names matching `force_include` are
+/// always traversed, then names in `exclude` are always skipped, then
+/// hidden entries are skipped and everything else is traversed.
+#[derive(Clone, Copy, Default)]
+pub struct EntryFilter<'a> {
+    /// See `HashOptions::exclude`.
+    pub exclude: Option<&'a HashSet<String>>,
+    /// See `HashOptions::force_include`.
+    pub force_include: Option<&'a [String]>,
+    /// Whether folders beneath the root that can't be read are recorded
+    /// and left out, rather than failing the whole traversal
+    /// (see `HashOptions::skip_unreadable_dirs`).
+    pub skip_unreadable: bool,
+}
+
+impl<'a> EntryFilter<'a> {
+    /// The filter `options` asks for.
+    #[inline]
+    pub fn new(options: &'a HashOptions) -> Self {
+        Self {
+            exclude: Some(&options.exclude),
+            force_include: Some(&options.force_include),
+            skip_unreadable: options.skip_unreadable_dirs,
+        }
+    }
+
+    #[inline]
+    fn is_forced(&self, name: &str) -> bool {
+        self.force_include
+            .is_some_and(|patterns| patterns.iter().any(|pattern| glob_matches(pattern, name)))
+    }
+
+    #[inline]
+    fn is_excluded(&self, name: &str) -> bool {
+        self.exclude.is_some_and(|names| names.contains(name))
+    }
+}
+
+/// Whether all of `name` matches `pattern`, where `*` matches any
+/// (possibly empty) run of characters and `?` any single character.
+fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Where the last `*` was, and how much of `name` it's swallowed so far.
+    let mut backtrack = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            // Have the last `*` swallow one more character and retry.
+            _ => match backtrack {
+                Some((star, swallowed)) => {
+                    backtrack = Some((star, swallowed + 1));
+                    p = star + 1;
+                    n = swallowed + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
 
 /// Builds a `Vec` containing the paths of all visible
 /// files beneath `dir_path`.
 ///
 /// The ordering of these paths is non-deterministic
 /// (we are at the mercy of the OS).
+///
+/// Entries are skipped (or not) according to `filter`.
+#[inline]
+pub fn get_files(dir_path: &Utf8Path, filter: EntryFilter) -> IOResult<Vec<Utf8PathBuf>> {
+    get_entries(dir_path, None, filter).map(|entries| entries.files)
+}
+
+/// Identical to `get_files`, but also returns the paths of any special
+/// files found, rather than ignoring them.
+///
+/// `file_capacity` is how many files to allocate room for up front
+/// (see `HashOptions::file_capacity_hint`). With `filter.skip_unreadable`,
+/// folders beneath `dir_path` that can't be read end up in
+/// `Entries::unreadable` instead.
 #[inline(never)]
-pub fn get_files(dir_path: &Utf8Path) -> IOResult<Vec<Utf8PathBuf>> {
-    let mut files = Vec::with_capacity(STARTING_CAP_FILES);
-    let mut folders = Vec::with_capacity(STARTING_CAP_FOLDERS);
+pub fn get_entries(
+    dir_path: &Utf8Path,
+    file_capacity: Option<usize>,
+    filter: EntryFilter,
+) -> IOResult<Entries> {
+    check_root_readable(dir_path, filter)?;
+    let (file_capacity, folder_capacity) = capacities(file_capacity);
+    let mut entries = Entries {
+        files: Vec::with_capacity(file_capacity),
+        ..Default::default()
+    };
+    let mut folders = Vec::with_capacity(folder_capacity);
     // Seed first .pop() with root directory.
     folders.push(dir_path.to_path_buf());
     while let Some(cur_folder) = folders.pop() {
-        push_entries(cur_folder.as_path(), &mut files, &mut folders)?;
+        if push_entries(&cur_folder, filter, &mut entries, &mut folders)? {
+            entries.folders.push(cur_folder);
+        }
+    }
+    Ok(entries)
+}
+
+/// Identical to `get_entries`, but reads multiple folders concurrently
+/// on the rayon threadpool.
+///
+/// On trees with millions of tiny files spread over deep directories, the
+/// traversal itself becomes the bottleneck while the hashing cores sit
+/// idle. The returned paths are just as non-deterministically ordered
+/// (and get sorted later anyway), so this is purely a speedup.
+#[inline(never)]
+pub fn get_entries_parallel(
+    dir_path: &Utf8Path,
+    file_capacity: Option<usize>,
+    filter: EntryFilter,
+) -> IOResult<Entries> {
+    check_root_readable(dir_path, filter)?;
+    let entries = Mutex::new(Entries {
+        files: Vec::with_capacity(capacities(file_capacity).0),
+        ..Default::default()
+    });
+    let error = Mutex::new(None);
+    rayon::scope(|scope| {
+        let root = dir_path.to_path_buf();
+        visit_folder(scope, root, filter, &entries, &error);
+    });
+    match error.into_inner().unwrap_or_else(PoisonError::into_inner) {
+        Some(e) => Err(e),
+        None => Ok(entries.into_inner().unwrap_or_else(PoisonError::into_inner)),
     }
-    Ok(files)
 }
 
-/// Pushes all files and folders beneath `dir_path` into
+/// Paths of the visible files and folders directly inside `dir_path`,
+/// without descending any further. Ordering is just as non-deterministic.
+#[inline]
+pub fn get_top_level(
+    dir_path: &Utf8Path,
+    filter: EntryFilter,
+) -> IOResult<(Vec<Utf8PathBuf>, Vec<Utf8PathBuf>)> {
+    let mut entries = Entries::default();
+    let mut folders = Vec::new();
+    // There's nothing to skip beneath the root here, so never skip the root itself.
+    let filter = EntryFilter {
+        skip_unreadable: false,
+        ..filter
+    };
+    push_entries(dir_path, filter, &mut entries, &mut folders)?;
+    Ok((entries.files, folders))
+}
+
+/// Only folders beneath the root are ever skipped for being unreadable,
+/// since there'd be nothing left to traverse otherwise. Checked up front
+/// (only when skipping), so the root doesn't need special casing later.
+#[inline]
+fn check_root_readable(dir_path: &Utf8Path, filter: EntryFilter) -> IOResult<()> {
+    if filter.skip_unreadable {
+        dir_path.read_dir_utf8()?;
+    }
+    Ok(())
+}
+
+/// Capacities for the file and folder `Vec`s, given an optional hint for
+/// the number of files. The defaults apply when there's no hint.
+#[inline]
+fn capacities(file_capacity: Option<usize>) -> (usize, usize) {
+    match file_capacity {
+        // Always room for at least the root folder.
+        Some(files) => (files, (files / FILES_PER_FOLDER).max(1)),
+        None => (STARTING_CAP_FILES, STARTING_CAP_FOLDERS),
+    }
+}
+
+/// Pushes all entries directly inside `folder` into `entries`, then spawns
+/// a task visiting each of its subfolders. Only the first error
+/// encountered is kept, and it stops any further folders being visited.
+fn visit_folder<'scope>(
+    scope: &Scope<'scope>,
+    folder: Utf8PathBuf,
+    filter: EntryFilter<'scope>,
+    entries: &'scope Mutex<Entries>,
+    error: &'scope Mutex<Option<Error>>,
+) {
+    if error
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .is_some()
+    {
+        return;
+    }
+    // Collect locally first, so the shared Vecs are only locked once per folder.
+    let mut folder_entries = Entries::default();
+    let mut subfolders = Vec::new();
+    let readable = match push_entries(&folder, filter, &mut folder_entries, &mut subfolders) {
+        Ok(readable) => readable,
+        Err(e) => {
+            error
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .get_or_insert(e);
+            return;
+        }
+    };
+    let mut shared = entries.lock().unwrap_or_else(PoisonError::into_inner);
+    shared.files.extend(folder_entries.files);
+    shared.special.extend(folder_entries.special);
+    shared.symlinks.extend(folder_entries.symlinks);
+    shared.unreadable.extend(folder_entries.unreadable);
+    shared.hidden += folder_entries.hidden;
+    if readable {
+        shared.folders.push(folder);
+    }
+    drop(shared);
+    for subfolder in subfolders {
+        scope.spawn(move |scope| visit_folder(scope, subfolder, filter, entries, error));
+    }
+}
+
+/// Pushes all files, special files and folders beneath `dir_path` into
 /// their respective `Vec`.
 ///
 /// Any entry that is marked as hidden is completely skipped.
 /// Visible files within hidden folders are just as hidden as files
-/// that themselves are hidden. Any other entry type is ignored.
+/// that themselves are hidden. Symlinks are never followed.
+/// Entries named any of `filter.exclude` are skipped the same way, unless
+/// their names match any of `filter.force_include`, which even has hidden
+/// entries (and everything beneath them) traversed as if they were visible.
+///
+/// With `filter.skip_unreadable`, a `dir_path` that can't be read is pushed
+/// into `entries.unreadable` instead of failing. Returns whether
+/// `dir_path` was actually read.
 #[inline]
 fn push_entries(
     dir_path: &Utf8Path,
-    files: &mut Vec<Utf8PathBuf>,
+    filter: EntryFilter,
+    entries: &mut Entries,
     folders: &mut Vec<Utf8PathBuf>,
-) -> IOResult<()> {
+) -> IOResult<bool> {
     const HIDDEN_ENTRY_PREFIX: char = '.';
-    for entry in dir_path.read_dir_utf8()? {
+    let read_dir = match dir_path.read_dir_utf8() {
+        Ok(read_dir) => read_dir,
+        Err(_) if filter.skip_unreadable => {
+            entries.unreadable.push(dir_path.to_path_buf());
+            return Ok(false);
+        }
+        Err(e) => return Err(e),
+    };
+    for entry in read_dir {
         let entry = entry?;
+        let name = entry.file_name();
         // Only consider visible entries.
-        if !entry.file_name().starts_with(HIDDEN_ENTRY_PREFIX) {
+        let forced = filter.is_forced(name);
+        if !forced && name.starts_with(HIDDEN_ENTRY_PREFIX) {
+            entries.hidden += 1;
+        } else if forced || !filter.is_excluded(name) {
             // Retrieve type first, since Utf8PathBuf
             // doesn't store this information.
             let entry_type = entry.file_type()?;
             // Utf8PathBuf is significantly smaller than Utf8DirEntry.
             let entry = entry.into_path();
             if entry_type.is_file() {
-                files.push(entry);
+                entries.files.push(entry);
             } else if entry_type.is_dir() {
                 folders.push(entry);
+            } else if entry_type.is_symlink() {
+                entries.symlinks.push(entry);
+            } else {
+                entries.special.push(entry);
             }
         }
     }
-    Ok(())
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::Scratch;
+
+    #[cfg(unix)]
+    #[test]
+    #[ignore = "needs a non-root user, since root can read the locked folder anyway"]
+    fn get_files_leaves_out_unreadable_folders_when_skipping() {
+        use std::fs::Permissions;
+        use std::os::unix::fs::PermissionsExt;
+
+        let scratch = Scratch::new();
+        scratch.write("tree/kept.txt", "kept");
+        scratch.write("tree/locked/secret.txt", "secret");
+        let locked = Utf8Path::new("tree/locked");
+        std::fs::set_permissions(locked, Permissions::from_mode(0o000)).unwrap();
+        let readable = locked.read_dir_utf8().is_ok();
+        let skipping = EntryFilter {
+            skip_unreadable: true,
+            ..Default::default()
+        };
+        let skipped = get_files(Utf8Path::new("tree"), skipping);
+        let strict = get_files(Utf8Path::new("tree"), EntryFilter::default());
+        std::fs::set_permissions(locked, Permissions::from_mode(0o755)).unwrap();
+        assert!(
+            !readable,
+            "the folder was still readable, so nothing was skipped"
+        );
+        assert_eq!(skipped.unwrap(), [Utf8PathBuf::from("tree/kept.txt")]);
+        assert!(strict.is_err());
+    }
+
+    #[test]
+    fn glob_matches_whole_names() {
+        assert!(glob_matches(".gitignore", ".gitignore"));
+        assert!(!glob_matches(".gitignore", ".gitignore.bak"));
+        assert!(glob_matches(".env*", ".env"));
+        assert!(glob_matches(".env*", ".env.local"));
+        assert!(glob_matches("*.sig", "release.tar.sig"));
+        assert!(!glob_matches("*.sig", "release.sig.tar"));
+        assert!(glob_matches("v?.*.txt", "v1.notes.txt"));
+        assert!(!glob_matches("v?.txt", "v10.txt"));
+        assert!(glob_matches("a*b*c", "abbbxc"));
+        assert!(!glob_matches("a*b*c", "acb"));
+        assert!(glob_matches("*", ""));
+    }
+
+    #[test]
+    fn force_include_patterns_pull_in_hidden_entries() {
+        let scratch = Scratch::new();
+        scratch.write("tree/.env", "a");
+        scratch.write("tree/.env.local", "b");
+        scratch.write("tree/.secret", "c");
+        scratch.write("tree/.config/app.toml", "d");
+        let patterns = [".env*".to_string(), ".conf?g".to_string()];
+        let filter = EntryFilter {
+            force_include: Some(&patterns),
+            ..Default::default()
+        };
+        let mut files = get_files(Utf8Path::new("tree"), filter).unwrap();
+        files.sort_unstable();
+        assert_eq!(
+            files,
+            ["tree/.config/app.toml", "tree/.env", "tree/.env.local"]
+        );
+    }
+
+    #[test]
+    fn parallel_traversal_finds_exactly_the_same_entries() {
+        let scratch = Scratch::new();
+        for dir in 0..12 {
+            for file in 0..(dir * 3) {
+                let nested = "deeper/".repeat(dir % 4);
+                scratch.write(&format!("tree/d{}/{}f{}", dir, nested, file), "");
+            }
+        }
+        scratch.write("tree/.hidden", "");
+        scratch.write("tree/.git/config", "");
+        scratch.write("tree/target/build.log", "");
+        scratch.write("tree/empty/.keep", "");
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("d1", "tree/link").unwrap();
+        let exclude = HashSet::from(["target".to_string()]);
+        let filter = EntryFilter {
+            exclude: Some(&exclude),
+            ..Default::default()
+        };
+        let sorted = |mut entries: Entries| {
+            for paths in [
+                &mut entries.files,
+                &mut entries.special,
+                &mut entries.folders,
+                &mut entries.symlinks,
+                &mut entries.unreadable,
+            ] {
+                paths.sort_unstable();
+            }
+            entries
+        };
+        let sequential = sorted(get_entries(Utf8Path::new("tree"), None, filter).unwrap());
+        let parallel = crate::with_threads(4, || {
+            get_entries_parallel(Utf8Path::new("tree"), None, filter)
+        });
+        let parallel = sorted(parallel.unwrap());
+        assert_eq!(
+            sequential.files.len(),
+            (0..12).map(|dir| dir * 3).sum::<usize>()
+        );
+        assert_eq!(parallel.files, sequential.files);
+        assert_eq!(parallel.special, sequential.special);
+        assert_eq!(parallel.folders, sequential.folders);
+        assert_eq!(parallel.symlinks, sequential.symlinks);
+        assert_eq!(parallel.unreadable, sequential.unreadable);
+        assert_eq!(parallel.hidden, sequential.hidden);
+        assert_eq!(sequential.hidden, 3);
+    }
+
+    #[test]
+    fn capacity_hints_only_change_allocation() {
+        let scratch = Scratch::new();
+        for i in 0..5 {
+            scratch.write(&format!("tree/sub{}/f", i % 2), "");
+        }
+        scratch.write("tree/a", "");
+        scratch.write("tree/b", "");
+        let tree = Utf8Path::new("tree");
+        let sorted = |hint| {
+            let mut entries = get_entries(tree, hint, EntryFilter::default()).unwrap();
+            entries.files.sort_unstable();
+            entries
+        };
+        let tiny = sorted(Some(3));
+        let default = sorted(None);
+        assert_eq!(tiny.files, default.files);
+        assert_eq!(sorted(Some(0)).files, default.files);
+        // No room for a huge tree reserved up front, just what was asked for.
+        assert!(tiny.files.capacity() < STARTING_CAP_FILES);
+        assert!(default.files.capacity() >= STARTING_CAP_FILES);
+        assert_eq!(capacities(Some(0)), (0, 1));
+        assert_eq!(
+            capacities(Some(10 * FILES_PER_FOLDER)),
+            (10 * FILES_PER_FOLDER, 10)
+        );
+        let hinted = crate::HashOptions {
+            file_capacity_hint: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(
+            crate::hash_directory_with_options("tree", &hinted)
+                .unwrap()
+                .hash,
+            crate::hash_directory("tree").unwrap().hash
+        );
+    }
 }
@@ -0,0 +1,1091 @@
+use crate::options::{HashEncoding, HashMode, HashOptions};
+use crate::types::HashedFile;
+use crate::util::{parent_dir, serialize_hashed_files_to};
+use crate::{IOResult, HASH_HEX_LEN};
+use blake3::{Hash, Hasher};
+use camino::{Utf8Path, Utf8PathBuf};
+use data_encoding::{BASE32_NOPAD, BASE64URL_NOPAD};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Error, ErrorKind, IntoInnerError, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Every header line starts with this, which can never be confused with
+/// the start of a file record (those always start with a hex digit, or
+/// the size-only marker).
+const HEADER_PREFIX: char = '#';
+const DELIM: char = ' ';
+const NEWLINE: char = '\n';
+/// Stands in for the root directory in per-directory records,
+/// since its relative path is just the empty string.
+const ROOT_DIR: &str = ".";
+
+const KEY_DIR_COUNT: &str = "dir";
+const KEY_SHARD: &str = "shard";
+const KEY_COLUMN: &str = "column";
+const KEY_MODE: &str = "mode";
+const KEY_STAMP: &str = "stamp";
+const KEY_SEPARATOR: &str = "separator";
+const KEY_ENCODING: &str = "encoding";
+const KEY_META: &str = "meta";
+const KEY_AGGREGATE: &str = "aggregate";
+const KEY_PREFIX: &str = "prefix";
+const KEY_SALT: &str = "salt";
+const KEY_NAME: &str = "name";
+const KEY_FROM: &str = "from";
+const KEY_UNTIL: &str = "until";
+
+const ENCODING_HEX: &str = "hex";
+const ENCODING_BASE64URL: &str = "base64url";
+const ENCODING_BASE32: &str = "base32";
+/// Separator used in paths internally, and in hashfiles by default.
+const CANONICAL_SEPARATOR: char = '/';
+
+const MODE_PLAIN: &str = "plain";
+const MODE_KEYED: &str = "keyed";
+const MODE_DERIVE_KEY: &str = "derive-key";
+/// Context for deriving the key ids recorded for keyed hashfiles.
+const KEY_ID_CONTEXT: &str = "b3hash v1 key id";
+/// Bytes of the derived key id actually recorded,
+/// which is plenty for telling keys apart.
+const KEY_ID_LEN: usize = 8;
+
+const COLUMN_MTIME: &str = "mtime";
+const COLUMN_SIZE: &str = "size";
+/// Written in place of a timestamp the platform couldn't give us.
+const MISSING: &str = "-";
+/// Marks a record of a file too large to have its contents hashed,
+/// in place of its hash: `size:<bytes> <path>`.
+pub const SIZE_ONLY_PREFIX: &str = "size:";
+
+/// Everything a hashfile records besides the per-file records themselves.
+///
+/// Header lines always come before any file records and have the form
+/// `#<key> <value>`. Keys we don't recognize are ignored, so hashfiles
+/// written by newer versions can still be validated by older ones.
+#[derive(Default)]
+pub struct Header {
+    /// Number of files directly inside each directory, keyed by
+    /// the directory's path relative to the hashed directory.
+    pub dir_counts: Option<BTreeMap<String, usize>>,
+    /// File names of the shards this hashfile is split into, relative to
+    /// the directory containing this hashfile. A hashfile with shards is
+    /// just a manifest, and doesn't contain any file records of its own.
+    pub shards: Vec<String>,
+    /// Whether each record has an extra column containing the file's
+    /// modification time, in seconds since the Unix epoch. This is purely
+    /// for humans (and change-time reporting) and is never validated.
+    pub mtime: bool,
+    /// Whether each record has an extra column (after any mtime column)
+    /// containing the file's size in bytes, which validation uses to tell
+    /// suspicious same-size changes apart from the rest.
+    pub size: bool,
+    /// The blake3 mode the files were hashed with, as recorded in the
+    /// hashfile (see `describe_mode`). `None` means plain mode, which
+    /// keeps hashfiles created in the default mode unchanged.
+    pub mode: Option<String>,
+    /// Stamp of each directory's subtree (see `subtree_stamps`), keyed
+    /// by the directory's path relative to the hashed directory.
+    pub stamps: Option<BTreeMap<String, Hash>>,
+    /// Separator the recorded paths are written with, when it isn't `/`.
+    /// Paths held by the header itself always use `/`, regardless.
+    pub separator: Option<char>,
+    /// Encoding of every hash in the records.
+    pub encoding: HashEncoding,
+    /// Arbitrary user metadata, which is only ever recorded for humans.
+    pub metadata: BTreeMap<String, String>,
+    /// Aggregate hash of the whole directory, i.e. `HashedDirectory::hash`
+    /// (see `HashOptions::record_aggregate`). Only ever in the manifest.
+    pub aggregate: Option<Hash>,
+    /// Directory every recorded path is prefixed with (see
+    /// `HashOptions::root_prefix`), without any trailing separator.
+    /// Always `/`-separated, even when written to the hashfile.
+    pub prefix: Option<String>,
+    /// Random salt fed into the hasher ahead of every file's contents
+    /// (see `HashOptions::salted`), so validation has to use it too.
+    pub salt: Option<[u8; 32]>,
+    /// Name of the directory the hashfile was created for (see
+    /// `HashOptions::record_dir_name`). Only ever in the manifest.
+    pub dir_name: Option<String>,
+    /// Path of the first file in this shard's range of (sorted) paths,
+    /// `None` for the first shard, whose range is open at the start.
+    /// Only ever in shards, never in the manifest.
+    pub from: Option<String>,
+    /// Path of the first file past the end of this shard's range (i.e. the
+    /// first file of the next shard), `None` for the last shard.
+    pub until: Option<String>,
+}
+
+/// A single file record, borrowed from a line of a hashfile.
+///
+/// Records have the form `<hash> [<mtime>] [<size>] <path>`, where the optional
+/// columns are only present when the header says so. Files that were too
+/// large to content hash have `size:<bytes>` in place of their hash. The path always
+/// comes last, since it's the only column that may contain spaces.
+pub struct Record<'a> {
+    pub hash: &'a str,
+    /// Always `/`-separated, whatever separator the hashfile uses.
+    pub path: Cow<'a, str>,
+    /// Recorded modification time, in whole seconds since the Unix epoch.
+    /// `None` when there's no mtime column or the time wasn't known.
+    pub mtime: Option<u64>,
+    /// Recorded size in bytes, `None` when there's no size column.
+    pub size: Option<u64>,
+}
+
+impl Header {
+    /// Appends the serialized header to `buf`.
+    pub fn write(&self, buf: &mut Vec<u8>) {
+        for (key, value) in &self.metadata {
+            push_line(buf, KEY_META, &format!("{}{}{}", key, DELIM, value));
+        }
+        match self.encoding {
+            // Left out by default, so default hashfiles stay unchanged.
+            HashEncoding::Hex => {}
+            HashEncoding::Base64Url => push_line(buf, KEY_ENCODING, ENCODING_BASE64URL),
+            HashEncoding::Base32 => push_line(buf, KEY_ENCODING, ENCODING_BASE32),
+        }
+        if let Some(separator) = self.separator {
+            push_line(buf, KEY_SEPARATOR, separator.encode_utf8(&mut [0; 4]));
+        }
+        if let Some(prefix) = &self.prefix {
+            push_line(buf, KEY_PREFIX, prefix);
+        }
+        if let Some(dir_name) = &self.dir_name {
+            push_line(buf, KEY_NAME, dir_name);
+        }
+        if let Some(dir_counts) = &self.dir_counts {
+            // BTreeMap iterates in path order, so the output is deterministic.
+            for (dir, count) in dir_counts {
+                let dir = match dir.is_empty() {
+                    true => ROOT_DIR,
+                    false => dir,
+                };
+                let dir = self.written_path(dir);
+                push_line(buf, KEY_DIR_COUNT, &format!("{}{}{}", count, DELIM, dir));
+            }
+        }
+        if let Some(stamps) = &self.stamps {
+            for (dir, stamp) in stamps {
+                let dir = match dir.is_empty() {
+                    true => ROOT_DIR,
+                    false => dir,
+                };
+                let dir = self.written_path(dir);
+                push_line(
+                    buf,
+                    KEY_STAMP,
+                    &format!("{}{}{}", stamp.to_hex(), DELIM, dir),
+                );
+            }
+        }
+        for shard in &self.shards {
+            push_line(buf, KEY_SHARD, shard);
+        }
+        if let Some(from) = &self.from {
+            push_line(buf, KEY_FROM, &self.written_path(from));
+        }
+        if let Some(until) = &self.until {
+            push_line(buf, KEY_UNTIL, &self.written_path(until));
+        }
+        if self.mtime {
+            push_line(buf, KEY_COLUMN, COLUMN_MTIME);
+        }
+        if self.size {
+            push_line(buf, KEY_COLUMN, COLUMN_SIZE);
+        }
+        if let Some(mode) = &self.mode {
+            push_line(buf, KEY_MODE, mode);
+        }
+        if let Some(salt) = &self.salt {
+            push_line(buf, KEY_SALT, &Hash::from_bytes(*salt).to_hex());
+        }
+        if let Some(aggregate) = &self.aggregate {
+            push_line(buf, KEY_AGGREGATE, &aggregate.to_hex());
+        }
+    }
+
+    /// A header with only the settings every shard of
+    /// a hashfile with this header needs to repeat.
+    #[inline]
+    pub fn for_shard(&self) -> Self {
+        Self {
+            mtime: self.mtime,
+            size: self.size,
+            mode: self.mode.clone(),
+            separator: self.separator,
+            encoding: self.encoding,
+            prefix: self.prefix.clone(),
+            salt: self.salt,
+            ..Default::default()
+        }
+    }
+
+    /// Whether the (canonical) file `path` falls within the range of paths
+    /// this header's records cover, which is every path unless it's a shard.
+    #[inline]
+    pub fn in_range(&self, path: &str) -> bool {
+        self.from.as_deref().is_none_or(|from| path >= from)
+            && self.until.as_deref().is_none_or(|until| path < until)
+    }
+
+    /// Fails if the files weren't hashed in `mode`, since
+    /// validating them would otherwise fail every single file.
+    pub fn check_mode(&self, mode: &HashMode) -> IOResult<()> {
+        let expected = describe_mode(mode);
+        match self.mode == expected {
+            true => Ok(()),
+            false => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Hashfile was created in '{}' mode, but validation is using '{}' mode.",
+                    self.mode.as_deref().unwrap_or(MODE_PLAIN),
+                    expected.as_deref().unwrap_or(MODE_PLAIN),
+                ),
+            )),
+        }
+    }
+
+    /// Appends the record for `file` to `buf`, with
+    /// whatever columns this header says are present.
+    pub fn write_record(&self, buf: &mut Vec<u8>, file: &HashedFile) {
+        match file.size_only {
+            true => {
+                buf.extend(SIZE_ONLY_PREFIX.bytes());
+                buf.extend(file.size.to_string().bytes());
+            }
+            false => self.write_hash(buf, &file.hash),
+        }
+        // The char constants used are represented as ascii values,
+        // so forcing them into u8's and pushing them is fine.
+        buf.push(DELIM as u8);
+        if self.mtime {
+            match mtime_secs(file) {
+                Some(secs) => buf.extend(secs.to_string().bytes()),
+                None => buf.extend(MISSING.bytes()),
+            }
+            buf.push(DELIM as u8);
+        }
+        if self.size {
+            buf.extend(file.size.to_string().bytes());
+            buf.push(DELIM as u8);
+        }
+        match &self.prefix {
+            Some(prefix) => {
+                let path = format!("{}{}{}", prefix, CANONICAL_SEPARATOR, file.path);
+                buf.extend(self.written_path(&path).bytes());
+            }
+            None => buf.extend(self.written_path(&file.path).bytes()),
+        }
+        buf.push(NEWLINE as u8);
+    }
+
+    /// Exact number of bytes `write_record` will write for `file`.
+    #[inline]
+    pub fn record_len(&self, file: &HashedFile) -> usize {
+        let hash_len = match file.size_only {
+            true => SIZE_ONLY_PREFIX.len() + decimal_len(file.size),
+            false => self.hash_len(),
+        };
+        let mut len = hash_len + 1 + file.path.len() + 1;
+        let mut separators = file.path.matches(CANONICAL_SEPARATOR).count();
+        if let Some(prefix) = &self.prefix {
+            len += prefix.len() + 1;
+            separators += prefix.matches(CANONICAL_SEPARATOR).count() + 1;
+        }
+        if let Some(separator) = self.separator {
+            // Every separator we write is ascii, but headers we read may not be.
+            len += separators * (separator.len_utf8() - 1);
+        }
+        if self.mtime {
+            len += match mtime_secs(file) {
+                Some(secs) => decimal_len(secs),
+                None => MISSING.len(),
+            } + 1;
+        }
+        if self.size {
+            len += decimal_len(file.size) + 1;
+        }
+        len
+    }
+
+    /// Splits a record `line` into its columns, returning `None`
+    /// if it doesn't contain every column this header says it should.
+    #[inline]
+    pub fn parse_record<'a>(&self, line: &'a str) -> Option<Record<'a>> {
+        let (hash, rest) = line.split_once(DELIM)?;
+        let (mtime, path) = match self.mtime {
+            true => {
+                let (mtime, path) = rest.split_once(DELIM)?;
+                (mtime.parse().ok(), path)
+            }
+            false => (None, rest),
+        };
+        let (size, path) = match self.size {
+            true => {
+                let (size, path) = path.split_once(DELIM)?;
+                (Some(size.parse().ok()?), path)
+            }
+            false => (None, path),
+        };
+        Some(Record {
+            hash,
+            path: self.unprefixed(self.canonical_path(path)),
+            mtime,
+            size,
+        })
+    }
+
+    /// Appends `hash` to `buf` in this header's encoding.
+    #[inline]
+    fn write_hash(&self, buf: &mut Vec<u8>, hash: &Hash) {
+        match self.encoding {
+            // Prefer to_hex() over to_string() because it avoids heap allocation.
+            HashEncoding::Hex => buf.extend(hash.to_hex().bytes()),
+            // Hex is the longest encoding, so this is always large enough.
+            HashEncoding::Base64Url | HashEncoding::Base32 => {
+                let encoding = match self.encoding {
+                    HashEncoding::Base64Url => &BASE64URL_NOPAD,
+                    _ => &BASE32_NOPAD,
+                };
+                let mut encoded = [0; HASH_HEX_LEN];
+                let encoded = &mut encoded[..encoding.encode_len(blake3::OUT_LEN)];
+                encoding.encode_mut(hash.as_bytes(), encoded);
+                buf.extend_from_slice(encoded);
+            }
+        }
+    }
+
+    /// Length of every hash written in this header's encoding.
+    #[inline]
+    fn hash_len(&self) -> usize {
+        match self.encoding {
+            HashEncoding::Hex => HASH_HEX_LEN,
+            HashEncoding::Base64Url => BASE64URL_NOPAD.encode_len(blake3::OUT_LEN),
+            HashEncoding::Base32 => BASE32_NOPAD.encode_len(blake3::OUT_LEN),
+        }
+    }
+
+    /// Decodes a `hash` written in this header's encoding.
+    pub fn parse_hash(&self, hash: &str) -> IOResult<Hash> {
+        let decoded = match self.encoding {
+            // HexError needs to be explicitly converted to IOError.
+            HashEncoding::Hex => {
+                return Hash::from_hex(hash).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+            }
+            HashEncoding::Base64Url => BASE64URL_NOPAD.decode(hash.as_bytes()),
+            HashEncoding::Base32 => BASE32_NOPAD.decode(hash.as_bytes()),
+        };
+        decoded
+            .ok()
+            .and_then(|bytes| <[u8; blake3::OUT_LEN]>::try_from(bytes).ok())
+            .map(Hash::from_bytes)
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "Failed to decode hash '{}' for the hashfile's encoding.",
+                        hash
+                    ),
+                )
+            })
+    }
+
+    /// Converts a `/`-separated `path` to the separator it's written with.
+    #[inline]
+    fn written_path<'a>(&self, path: &'a str) -> Cow<'a, str> {
+        match self.separator {
+            Some(separator) => {
+                Cow::Owned(path.replace(CANONICAL_SEPARATOR, separator.encode_utf8(&mut [0; 4])))
+            }
+            None => Cow::Borrowed(path),
+        }
+    }
+
+    /// Converts a `path` read from a hashfile back to being `/`-separated.
+    #[inline]
+    fn canonical_path<'a>(&self, path: &'a str) -> Cow<'a, str> {
+        match self.separator {
+            Some(separator) => Cow::Owned(path.replace(separator, "/")),
+            None => Cow::Borrowed(path),
+        }
+    }
+
+    /// Strips this header's prefix from a canonical record `path`. Paths
+    /// without the prefix are left as is, so validating them just fails.
+    #[inline]
+    fn unprefixed<'a>(&self, path: Cow<'a, str>) -> Cow<'a, str> {
+        let Some(prefix) = &self.prefix else {
+            return path;
+        };
+        let prefix_len = match path
+            .strip_prefix(prefix.as_str())
+            .and_then(|rest| rest.strip_prefix(CANONICAL_SEPARATOR))
+        {
+            Some(rest) => path.len() - rest.len(),
+            None => return path,
+        };
+        match path {
+            Cow::Borrowed(path) => Cow::Borrowed(&path[prefix_len..]),
+            Cow::Owned(path) => Cow::Owned(path[prefix_len..].to_string()),
+        }
+    }
+
+    /// Splits `data` into its header and the remaining file records.
+    pub fn parse(data: &str) -> IOResult<(Self, &str)> {
+        let mut header = Self::default();
+        let mut records = data;
+        while records.starts_with(HEADER_PREFIX) {
+            let (line, rest) = records.split_once(NEWLINE).unwrap_or((records, ""));
+            records = rest;
+            let (key, value) = line[1..].split_once(DELIM).unwrap_or((&line[1..], ""));
+            if key == KEY_META {
+                let (key, value) = value.split_once(DELIM).unwrap_or((value, ""));
+                header.metadata.insert(key.to_string(), value.to_string());
+            } else if key == KEY_ENCODING {
+                header.encoding = match value {
+                    ENCODING_HEX => HashEncoding::Hex,
+                    ENCODING_BASE64URL => HashEncoding::Base64Url,
+                    ENCODING_BASE32 => HashEncoding::Base32,
+                    // Unlike unknown keys, there's no way to read
+                    // hashes in an encoding we don't know about.
+                    _ => return Err(invalid_header(line)),
+                };
+            } else if key == KEY_SEPARATOR {
+                let mut chars = value.chars();
+                header.separator = match (chars.next(), chars.next()) {
+                    (Some(CANONICAL_SEPARATOR), None) => None,
+                    (Some(separator), None) => Some(separator),
+                    _ => return Err(invalid_header(line)),
+                };
+            } else if key == KEY_SHARD {
+                header.shards.push(value.to_string());
+            } else if key == KEY_FROM {
+                header.from = Some(value.to_string());
+            } else if key == KEY_UNTIL {
+                header.until = Some(value.to_string());
+            } else if key == KEY_COLUMN {
+                match value {
+                    COLUMN_MTIME => header.mtime = true,
+                    COLUMN_SIZE => header.size = true,
+                    // Unlike unknown keys, there's no way to skip past
+                    // a column we don't know about.
+                    _ => return Err(invalid_header(line)),
+                }
+            } else if key == KEY_MODE {
+                // Plain mode is never written, but accept it anyway.
+                header.mode = (value != MODE_PLAIN).then(|| value.to_string());
+            } else if key == KEY_PREFIX {
+                header.prefix = Some(value.to_string());
+            } else if key == KEY_NAME {
+                header.dir_name = Some(value.to_string());
+            } else if key == KEY_SALT {
+                let salt = Hash::from_hex(value).map_err(|_| invalid_header(line))?;
+                header.salt = Some(*salt.as_bytes());
+            } else if key == KEY_AGGREGATE {
+                header.aggregate = Some(Hash::from_hex(value).map_err(|_| invalid_header(line))?);
+            } else if key == KEY_STAMP {
+                let (stamp, dir) = value
+                    .split_once(DELIM)
+                    .and_then(|(stamp, dir)| Some((Hash::from_hex(stamp).ok()?, dir)))
+                    .ok_or_else(|| invalid_header(line))?;
+                let dir = match dir == ROOT_DIR {
+                    true => "",
+                    false => dir,
+                };
+                header
+                    .stamps
+                    .get_or_insert_with(BTreeMap::new)
+                    .insert(dir.to_string(), stamp);
+            } else if key == KEY_DIR_COUNT {
+                let (count, dir) = value
+                    .split_once(DELIM)
+                    .and_then(|(count, dir)| Some((count.parse().ok()?, dir)))
+                    .ok_or_else(|| invalid_header(line))?;
+                let dir = match dir == ROOT_DIR {
+                    true => "",
+                    false => dir,
+                };
+                header
+                    .dir_counts
+                    .get_or_insert_with(BTreeMap::new)
+                    .insert(dir.to_string(), count);
+            }
+        }
+        // Directory lines might have come before the separator line.
+        if header.separator.is_some() {
+            if let Some(dir_counts) = header.dir_counts.take() {
+                header.dir_counts = Some(
+                    dir_counts
+                        .into_iter()
+                        .map(|(dir, count)| (header.canonical_path(&dir).into_owned(), count))
+                        .collect(),
+                );
+            }
+            header.from =
+                (header.from.take()).map(|from| header.canonical_path(&from).into_owned());
+            header.until =
+                (header.until.take()).map(|until| header.canonical_path(&until).into_owned());
+            if let Some(stamps) = header.stamps.take() {
+                header.stamps = Some(
+                    stamps
+                        .into_iter()
+                        .map(|(dir, stamp)| (header.canonical_path(&dir).into_owned(), stamp))
+                        .collect(),
+                );
+            }
+        }
+        Ok((header, records))
+    }
+}
+
+/// Fails with `ErrorKind::InvalidInput` if any of `metadata`
+/// couldn't be read back exactly as is from a header.
+pub fn check_metadata<'a, I>(metadata: I) -> IOResult<()>
+where
+    I: IntoIterator<Item = (&'a String, &'a String)>,
+{
+    for (key, value) in metadata {
+        if key.is_empty() || key.contains(char::is_whitespace) || value.contains(['\n', '\r']) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid hashfile metadata '{}' = '{}'.", key, value),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Reads the hashfile at `hashfile_path`, returning its header and the text
+/// of its file records. When the hashfile is a sharded manifest, the header
+/// and records of every shard are read too, as entries after the first.
+///
+/// The contents are checked to be valid utf8 unless `trusted` is set
+/// (see `HashOptions::trusted_hashfile`).
+pub fn read_hashfile(hashfile_path: &Utf8Path, trusted: bool) -> IOResult<Vec<(Header, String)>> {
+    let data = std::fs::read(hashfile_path)?;
+    let mut data = match trusted {
+        // SAFETY: Hashfile data is always valid utf8 because we
+        // serialize into valid utf8, and the caller vouched for
+        // this one not having been corrupted or edited since.
+        true => unsafe { String::from_utf8_unchecked(data) },
+        false => String::from_utf8(data).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Hashfile '{}' isn't valid utf8 (corrupted?): {}",
+                    hashfile_path,
+                    e.utf8_error()
+                ),
+            )
+        })?,
+    };
+    let (header, records) = Header::parse(&data)?;
+    let header_len = data.len() - records.len();
+    data.drain(..header_len);
+    // Shards are always relative to their manifest.
+    let shards_dir = hashfile_path.parent().unwrap_or(Utf8Path::new("."));
+    let shard_paths: Vec<_> = header
+        .shards
+        .iter()
+        .map(|shard| shards_dir.join(shard))
+        .collect();
+    let mut blocks = vec![(header, data)];
+    for shard_path in shard_paths {
+        blocks.extend(read_hashfile(&shard_path, trusted)?);
+    }
+    Ok(blocks)
+}
+
+/// Opens the hashfile at `hashfile_path` for reading its file records one
+/// line at a time (see `RecordLines`), returning its header and the lines.
+///
+/// Unlike `read_hashfile`, only the header is read up front, so the records
+/// never all have to be held in memory. Records are always checked to be
+/// valid utf8, since they're read line by line anyway.
+pub fn open_records(hashfile_path: &Utf8Path) -> IOResult<(Header, RecordLines)> {
+    let (header, reader, next) = open_header(hashfile_path)?;
+    // Shards are always relative to their manifest.
+    let shards_dir = hashfile_path.parent().unwrap_or(Utf8Path::new("."));
+    let shards = header
+        .shards
+        .iter()
+        .map(|shard| shards_dir.join(shard))
+        .collect::<Vec<_>>()
+        .into_iter();
+    let lines = RecordLines {
+        path: hashfile_path.to_path_buf(),
+        reader,
+        line: next,
+        buffered: true,
+        shards,
+    };
+    Ok((header, lines))
+}
+
+/// Opens the hashfile at `hashfile_path` and reads its header, returning it
+/// along with the reader and the first record line (empty if there isn't one).
+fn open_header(hashfile_path: &Utf8Path) -> IOResult<(Header, BufReader<File>, String)> {
+    let mut reader = BufReader::new(File::open(hashfile_path)?);
+    let mut text = String::new();
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if read_line(&mut reader, &mut line, hashfile_path)? == 0 {
+            break;
+        }
+        if !line.starts_with(HEADER_PREFIX) {
+            break;
+        }
+        text.push_str(&line);
+    }
+    let (header, _) = Header::parse(&text)?;
+    Ok((header, reader, line))
+}
+
+/// `BufRead::read_line`, but saying which hashfile wasn't valid utf8.
+#[inline]
+fn read_line(reader: &mut BufReader<File>, buf: &mut String, path: &Utf8Path) -> IOResult<usize> {
+    reader.read_line(buf).map_err(|e| match e.kind() {
+        ErrorKind::InvalidData => Error::new(
+            ErrorKind::InvalidData,
+            format!("Hashfile '{}' isn't valid utf8 (corrupted?): {}", path, e),
+        ),
+        _ => e,
+    })
+}
+
+/// The file records of a hashfile, read one line at a time (see
+/// `open_records`). For a sharded manifest, the records of each of its
+/// shards follow in order, with the shards' own headers skipped. They're
+/// meant to be parsed with the manifest's header, whose settings every
+/// shard repeats (see `Header::for_shard`).
+pub struct RecordLines {
+    path: Utf8PathBuf,
+    reader: BufReader<File>,
+    line: String,
+    // Whether `line` already holds the next line (or is empty at the end).
+    buffered: bool,
+    shards: std::vec::IntoIter<Utf8PathBuf>,
+}
+
+impl RecordLines {
+    /// The next record line, without its line ending, or `None`
+    /// once every line of every shard has been read.
+    pub fn next_line(&mut self) -> IOResult<Option<&str>> {
+        loop {
+            if !self.buffered {
+                self.line.clear();
+                read_line(&mut self.reader, &mut self.line, &self.path)?;
+            }
+            self.buffered = false;
+            if !self.line.is_empty() {
+                break;
+            }
+            let Some(shard_path) = self.shards.next() else {
+                return Ok(None);
+            };
+            let (_, reader, next) = open_header(&shard_path)?;
+            self.path = shard_path;
+            self.reader = reader;
+            self.line = next;
+            self.buffered = true;
+        }
+        let line = self.line.strip_suffix(NEWLINE).unwrap_or(&self.line);
+        Ok(Some(line.strip_suffix('\r').unwrap_or(line)))
+    }
+}
+
+/// Numbers the temporary files hashfiles are written to before being
+/// renamed into place, see `write_hashfile`.
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Writes a hashfile containing `header` and a record for each of `records`
+/// to `hashfile_path`, atomically replacing any existing hashfile.
+///
+/// Everything is first written to a temporary file next to the target,
+/// which is then renamed over it, so a crash mid-write leaves either the
+/// old complete hashfile or the new complete one, never a truncated one.
+/// The temporary file is always in the same directory as the target, so
+/// the rename never has to cross devices.
+///
+/// See `HashOptions::fsync` and `HashOptions::fsync_dir` for durability.
+pub fn write_hashfile(
+    hashfile_path: &Utf8Path,
+    header: &Header,
+    records: &[HashedFile],
+    options: &HashOptions,
+) -> IOResult<()> {
+    let mut header_data = Vec::new();
+    header.write(&mut header_data);
+    // Records are streamed into the file rather than serialized up front,
+    // so huge directories don't need a second copy of the hashfile in memory.
+    let write_to = |path: &Utf8Path| -> IOResult<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(&header_data)?;
+        serialize_hashed_files_to(records, header, &mut writer)?;
+        let file = writer.into_inner().map_err(IntoInnerError::into_error)?;
+        if options.fsync {
+            file.sync_all()?;
+        }
+        Ok(())
+    };
+
+    // Hidden, so it'll never be hashed even if it's inside the hashed
+    // directory, and unique per process and write, so concurrent runs
+    // (or threads) writing the same hashfile don't collide.
+    let file_name = hashfile_path
+        .file_name()
+        .unwrap_or(crate::HASH_RESULTS_FILENAME);
+    let tmp_path = hashfile_path.with_file_name(format!(
+        ".{}.{}.{}.tmp",
+        file_name.trim_start_matches('.'),
+        std::process::id(),
+        TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let res = write_to(&tmp_path).and_then(|_| std::fs::rename(&tmp_path, hashfile_path));
+    if res.is_err() {
+        let _ = std::fs::remove_file(&tmp_path);
+    }
+    res?;
+    if options.fsync_dir {
+        sync_parent_dir(hashfile_path)?;
+    }
+    Ok(())
+}
+
+/// Makes the directory entry of `path` itself durable (e.g. after a rename).
+#[inline]
+fn sync_parent_dir(path: &Utf8Path) -> IOResult<()> {
+    // Directories can't be opened (let alone synced) like this on Windows,
+    // where NTFS journals metadata changes like renames anyway.
+    if cfg!(unix) {
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_str().is_empty() => parent,
+            _ => Utf8Path::new("."),
+        };
+        File::open(parent)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Counts the files directly inside each directory, given the relative
+/// paths of all files (using `/` as the separator).
+pub fn count_files_per_dir<'a, I>(paths: I) -> BTreeMap<String, usize>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let mut counts = BTreeMap::new();
+    for path in paths {
+        *counts.entry(parent_dir(path).to_string()).or_insert(0) += 1;
+    }
+    counts
+}
+
+/// How `mode` is recorded in a hashfile header: `keyed <key id>` or
+/// `derive-key <context>`, or `None` for plain mode. The key id is
+/// derived from the key, so it identifies the key without revealing it.
+pub fn describe_mode(mode: &HashMode) -> Option<String> {
+    match mode {
+        HashMode::Plain => None,
+        HashMode::Keyed(key) => {
+            let key_id = blake3::derive_key(KEY_ID_CONTEXT, key);
+            let key_id: String = key_id[..KEY_ID_LEN]
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect();
+            Some(format!("{}{}{}", MODE_KEYED, DELIM, key_id))
+        }
+        HashMode::DeriveKey(context) => Some(format!("{}{}{}", MODE_DERIVE_KEY, DELIM, context)),
+    }
+}
+
+/// Computes the stamp of every directory's subtree, given the relative path
+/// (using `/` as the separator), size and modification time of all files,
+/// in path order. A subtree's stamp changes whenever any file beneath it
+/// is added, removed, renamed, resized or has its mtime changed.
+pub fn subtree_stamps<'a, I>(files: I) -> BTreeMap<String, Hash>
+where
+    I: IntoIterator<Item = (&'a str, u64, Option<SystemTime>)>,
+{
+    let mut hashers: BTreeMap<&str, Hasher> = BTreeMap::new();
+    for (path, size, modified) in files {
+        // Files whose mtime the platform can't give us all look the same,
+        // which just means size and path are all we have to go off of.
+        let nanos = modified
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |since_epoch| since_epoch.as_nanos());
+        // The root, then every ancestor directory of the file.
+        let dirs = std::iter::once("").chain(path.match_indices('/').map(|(i, _)| &path[..i]));
+        for dir in dirs {
+            let hasher = hashers.entry(dir).or_default();
+            // Length prefixed, so the path can't run into the numbers.
+            hasher.update(&(path.len() as u64).to_le_bytes());
+            hasher.update(path.as_bytes());
+            hasher.update(&size.to_le_bytes());
+            hasher.update(&nanos.to_le_bytes());
+        }
+    }
+    hashers
+        .into_iter()
+        .map(|(dir, hasher)| (dir.to_string(), hasher.finalize()))
+        .collect()
+}
+
+#[inline]
+fn decimal_len(n: u64) -> usize {
+    n.checked_ilog10().map_or(1, |digits| digits as usize + 1)
+}
+
+#[inline]
+fn mtime_secs(file: &HashedFile) -> Option<u64> {
+    file.modified
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|since_epoch| since_epoch.as_secs())
+}
+
+#[inline]
+fn push_line(buf: &mut Vec<u8>, key: &str, value: &str) {
+    buf.push(HEADER_PREFIX as u8);
+    buf.extend(key.bytes());
+    buf.push(DELIM as u8);
+    buf.extend(value.bytes());
+    buf.push(NEWLINE as u8);
+}
+
+#[inline]
+fn invalid_header(line: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("Failed to parse hashfile header line '{}'.", line),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::Scratch;
+    use crate::{create_hashfile_with_options, validate_hashfile_with_options};
+
+    #[test]
+    fn recorded_mode_has_to_match_when_validating() {
+        let scratch = Scratch::new();
+        scratch.write("tree/a.txt", "a");
+        let keyed = |key| HashOptions {
+            mode: HashMode::Keyed([key; blake3::KEY_LEN]),
+            ..Default::default()
+        };
+        create_hashfile_with_options("tree", &keyed(1)).unwrap();
+        let hashfile = scratch.hashfile();
+        let (header, _) = Header::parse(&hashfile).unwrap();
+        assert_eq!(header.mode, describe_mode(&keyed(1).mode));
+        assert!(header.mode.unwrap().starts_with("keyed"));
+
+        let plain = validate_hashfile_with_options("tree", &HashOptions::default());
+        assert_eq!(plain.err().unwrap().kind(), ErrorKind::InvalidInput);
+        let derive_key = HashOptions {
+            mode: HashMode::DeriveKey("b3hash tests".to_string()),
+            ..Default::default()
+        };
+        let derived = validate_hashfile_with_options("tree", &derive_key);
+        assert_eq!(derived.err().unwrap().kind(), ErrorKind::InvalidInput);
+        assert!(validate_hashfile_with_options("tree", &keyed(1))
+            .unwrap()
+            .is_valid());
+        // Only an id of the key is recorded, but that's enough to tell keys apart.
+        assert!(!hashfile.contains(&blake3::Hash::from([1; blake3::KEY_LEN]).to_hex()[..]));
+        let wrong_key = validate_hashfile_with_options("tree", &keyed(2));
+        assert_eq!(wrong_key.err().unwrap().kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn every_encoding_round_trips_through_validation() {
+        let scratch = Scratch::new();
+        scratch.write("tree/a.txt", "a");
+        scratch.write("tree/sub/b.txt", "b");
+        let expected = crate::hash_directory("tree").unwrap();
+        for (encoding, len) in [
+            (HashEncoding::Hex, 64),
+            (HashEncoding::Base64Url, 43),
+            (HashEncoding::Base32, 52),
+        ] {
+            let options = HashOptions {
+                hash_encoding: encoding,
+                ..Default::default()
+            };
+            create_hashfile_with_options("tree", &options).unwrap();
+            let hashfile = scratch.hashfile();
+            let (header, records) = Header::parse(&hashfile).unwrap();
+            for (line, file) in records.lines().zip(&expected.files) {
+                let record = header.parse_record(line).unwrap();
+                assert_eq!(record.hash.len(), len);
+                assert_eq!(header.parse_hash(record.hash).unwrap(), file.hash);
+            }
+            // The header says how to decode them, whatever `options` say.
+            let report = validate_hashfile_with_options("tree", &HashOptions::default()).unwrap();
+            assert!(report.is_valid());
+        }
+    }
+
+    #[test]
+    fn corrupted_utf8_in_a_hashfile_is_invalid_data() {
+        let scratch = Scratch::new();
+        scratch.write("tree/naïve.txt", "a");
+        crate::create_hashfile("tree").unwrap();
+        // Flip a bit in the middle of the two byte 'ï', leaving a lone lead byte.
+        let mut hashfile = scratch.hashfile().into_bytes();
+        let i = hashfile.iter().position(|&byte| byte == 0xC3).unwrap();
+        hashfile[i + 1] ^= 0x40;
+        std::fs::write(crate::HASH_RESULTS_FILENAME, hashfile).unwrap();
+
+        let err = validate_hashfile_with_options("tree", &HashOptions::default())
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(err.to_string().contains("isn't valid utf8"));
+    }
+
+    #[test]
+    fn mtime_column_round_trips() {
+        let scratch = Scratch::new();
+        scratch.write("tree/a.txt", "a");
+        scratch.write("tree/with space.txt", "spaced");
+        let modified = UNIX_EPOCH + std::time::Duration::from_secs(1_234_567_890);
+        File::options()
+            .write(true)
+            .open("tree/with space.txt")
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        let options = HashOptions {
+            record_mtime: true,
+            record_size: true,
+            ..Default::default()
+        };
+        create_hashfile_with_options("tree", &options).unwrap();
+        let hashfile = scratch.hashfile();
+        let (header, records) = Header::parse(&hashfile).unwrap();
+        assert!(header.mtime && header.size);
+        let records: Vec<_> = records
+            .lines()
+            .map(|line| header.parse_record(line).unwrap())
+            .collect();
+        assert_eq!(records[1].path, "with space.txt");
+        assert_eq!(records[1].mtime, Some(1_234_567_890));
+        assert_eq!(records[1].size, Some(6));
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        assert!(records[0].mtime.unwrap().abs_diff(now.as_secs()) < 60);
+        // The column is only for humans, so validation ignores it.
+        assert!(
+            validate_hashfile_with_options("tree", &HashOptions::default())
+                .unwrap()
+                .is_valid()
+        );
+    }
+
+    #[test]
+    fn hashfiles_are_replaced_atomically() {
+        let scratch = Scratch::new();
+        scratch.write("tree/a.txt", "a");
+        let files = crate::hash_directory("tree").unwrap().files;
+        let path = Utf8Path::new(crate::HASH_RESULTS_FILENAME);
+        let options = HashOptions::default();
+        write_hashfile(path, &Header::default(), &[], &options).unwrap();
+        let tmp_files = || {
+            std::fs::read_dir(".")
+                .unwrap()
+                .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("tmp".as_ref()))
+                .count()
+        };
+
+        // Threads writing the same hashfile at once never share a temporary
+        // file, so every write succeeds and one of them wins whole.
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| write_hashfile(path, &Header::default(), &files, &options).unwrap());
+            }
+        });
+        let written = scratch.hashfile();
+        assert_eq!(written.as_bytes(), crate::serialize_hashed_files(&files));
+        assert_eq!(tmp_files(), 0);
+
+        // A write that fails leaves the old hashfile exactly as it was.
+        let next = TMP_COUNTER.load(Ordering::Relaxed);
+        let blocker = format!(".b3hash_v1.{}.{}.tmp", std::process::id(), next);
+        std::fs::create_dir(&blocker).unwrap();
+        assert!(write_hashfile(path, &Header::default(), &[], &options).is_err());
+        assert_eq!(scratch.hashfile(), written);
+        std::fs::remove_dir(&blocker).unwrap();
+        assert_eq!(tmp_files(), 0);
+    }
+
+    #[test]
+    fn fsync_options_write_the_same_hashfile() {
+        let scratch = Scratch::new();
+        scratch.write("tree/a.txt", "a");
+        scratch.write("nested/dir/keep", "");
+        let files = crate::hash_directory("tree").unwrap().files;
+        let options = HashOptions {
+            fsync: true,
+            fsync_dir: true,
+            ..Default::default()
+        };
+        // Bare names have an empty parent, which has to sync `.` instead.
+        for path in [".b3hash_v1", "./.b3hash_v1", "nested/dir/.b3hash_v1"] {
+            write_hashfile(Utf8Path::new(path), &Header::default(), &files, &options).unwrap();
+            assert_eq!(
+                std::fs::read(path).unwrap(),
+                crate::serialize_hashed_files(&files)
+            );
+        }
+        create_hashfile_with_options("tree", &options).unwrap();
+        assert!(validate_hashfile_with_options("tree", &options)
+            .unwrap()
+            .is_valid());
+    }
+
+    #[test]
+    fn separators_only_change_the_written_paths() {
+        use crate::options::PathSeparator;
+
+        let scratch = Scratch::new();
+        scratch.write("tree/a.txt", "a");
+        scratch.write("tree/sub/deep/b.txt", "b");
+        let expected = crate::hash_directory("tree").unwrap();
+        for separator in [
+            PathSeparator::Slash,
+            PathSeparator::Backslash,
+            PathSeparator::Native,
+        ] {
+            let options = HashOptions {
+                path_separator: separator,
+                dir_counts: true,
+                record_aggregate: true,
+                ..Default::default()
+            };
+            create_hashfile_with_options("tree", &options).unwrap();
+            let hashfile = scratch.hashfile();
+            let written = ["sub", "deep", "b.txt"].join(&separator.as_char().to_string());
+            assert!(hashfile
+                .lines()
+                .any(|line| line.ends_with(&format!(" {}", written))));
+            assert!(hashfile.contains(&format!(
+                "#dir 1 {}",
+                ["sub", "deep"].join(&separator.as_char().to_string())
+            )));
+            // Nothing hashed ever sees the separator.
+            assert!(hashfile.contains(&expected.hash.to_hex()[..]));
+            // Validation reads them back with whatever the header says.
+            let counting = HashOptions {
+                dir_counts: true,
+                ..Default::default()
+            };
+            let report = validate_hashfile_with_options("tree", &counting).unwrap();
+            assert!(report.is_valid());
+            assert!(report.count_mismatches.is_empty());
+            assert!(crate::verify_aggregate("tree").unwrap());
+        }
+    }
+}
@@ -0,0 +1,139 @@
+use crate::types::{HashedDirectory, HashedFile};
+use crate::util::fold_hashed_files;
+use blake3::Hash;
+use std::collections::BTreeMap;
+
+/// Accumulates `HashedFile`s over time, then computes the directory
+/// aggregate on demand.
+///
+/// This is meant for long-running processes (like filesystem watchers)
+/// which learn about files one at a time and don't want to re-scan the
+/// whole directory to get an up-to-date `HashedDirectory`.
+///
+/// Files are kept sorted by path as they're added, so the aggregate
+/// folds them in exactly the same order `hash_directory` would,
+/// regardless of the order they were added in.
+pub struct DirectoryHasher {
+    dir_name: String,
+    files: BTreeMap<String, HashedFile>,
+}
+
+impl DirectoryHasher {
+    /// Creates an empty `DirectoryHasher` for a directory named `dir_name`.
+    pub fn new(dir_name: impl Into<String>) -> Self {
+        Self {
+            dir_name: dir_name.into(),
+            files: BTreeMap::new(),
+        }
+    }
+
+    /// Adds `file` to the collection, returning the previous
+    /// entry if one already existed with the same path.
+    #[inline]
+    pub fn add_file(&mut self, file: HashedFile) -> Option<HashedFile> {
+        self.files.insert(file.path.clone(), file)
+    }
+
+    /// Removes the file at the relative path `path`, if it's present.
+    #[inline]
+    pub fn remove_file(&mut self, path: &str) -> Option<HashedFile> {
+        self.files.remove(path)
+    }
+
+    /// Removes every file beneath the relative directory `dir_path`,
+    /// returning the removed files in path order.
+    pub fn remove_dir(&mut self, dir_path: &str) -> Vec<HashedFile> {
+        let prefix = format!("{}/", dir_path);
+        let removed: Vec<String> = self
+            .files
+            .range(prefix.clone()..)
+            .take_while(|(path, _)| path.starts_with(&prefix))
+            .map(|(path, _)| path.clone())
+            .collect();
+        removed
+            .iter()
+            .filter_map(|path| self.files.remove(path))
+            .collect()
+    }
+
+    /// Number of files currently in the collection.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    /// Whether the collection contains no files.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// Computes the aggregate over all collected files without consuming
+    /// the collection, so more files can still be added afterwards.
+    #[inline]
+    pub fn hash(&self) -> Hash {
+        fold_hashed_files(self.files.values()).0
+    }
+
+    /// Computes the aggregate over all collected files, producing the same
+    /// `HashedDirectory` that `hash_directory` would for the same files.
+    pub fn finalize(self) -> HashedDirectory {
+        let (hash, size) = fold_hashed_files(self.files.values());
+        HashedDirectory {
+            dir_name: self.dir_name,
+            files: self.files.into_values().collect(),
+            hash,
+            size,
+            skipped: Vec::new(),
+            unreadable_dirs: Vec::new(),
+            threads_used: 0,
+            started_at: None,
+            finished_at: None,
+        }
+    }
+}
+
+impl From<HashedDirectory> for DirectoryHasher {
+    fn from(dir: HashedDirectory) -> Self {
+        let mut hasher = Self::new(dir.dir_name);
+        for file in dir.files {
+            hasher.add_file(file);
+        }
+        hasher
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::Scratch;
+
+    #[test]
+    fn tracks_hash_directory_whatever_the_order_files_arrive_in() {
+        let scratch = Scratch::new();
+        scratch.write("tree/a.txt", "a");
+        scratch.write("tree/docs/b.txt", "b");
+        scratch.write("tree/docs/c.txt", "c");
+        let before = crate::hash_directory("tree").unwrap();
+        let mut hasher = DirectoryHasher::new(before.dir_name.clone());
+        for file in before.files.iter().rev().cloned() {
+            hasher.add_file(file);
+        }
+        assert_eq!(hasher.hash(), before.hash);
+
+        // Only the edited file gets handed over again.
+        scratch.write("tree/docs/b.txt", "edited");
+        let after = crate::hash_directory("tree").unwrap();
+        let edited = after.iter().find(|file| file.path == "docs/b.txt").unwrap();
+        assert!(hasher.add_file(edited.clone()).is_some());
+        assert_eq!(hasher.hash(), after.hash);
+
+        std::fs::remove_dir_all("tree/docs").unwrap();
+        let removed = hasher.remove_dir("docs");
+        assert_eq!(removed.len(), 2);
+        let finalized = hasher.finalize();
+        let live = crate::hash_directory("tree").unwrap();
+        assert_eq!(finalized.hash, live.hash);
+        assert_eq!(finalized.size, live.size);
+    }
+}
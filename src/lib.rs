@@ -2,72 +2,816 @@
 //!
 //!
 
+mod budget;
+mod control;
+mod diff;
 mod fs;
+mod hashfile;
+mod incremental;
+mod options;
+mod progress;
+mod report;
+#[cfg(test)]
+mod test_util;
+mod throttle;
 mod types;
 mod util;
+#[cfg(feature = "notify")]
+mod watch;
 
-use blake3::Hasher;
+use blake3::Hash;
 use camino::Utf8Path;
-use types::HashedDirectory;
+use diff::dir_diff;
+use fs::{get_top_level, EntryFilter};
+use hashfile::{
+    check_metadata, count_files_per_dir, describe_mode, open_records, read_hashfile,
+    subtree_stamps, write_hashfile, Header,
+};
+use rayon::prelude::*;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{BufRead, Error, ErrorKind, Write};
+use std::num::NonZeroUsize;
+use std::sync::mpsc::{self, Receiver};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use util::*;
 
+pub use control::HashControl;
+pub use incremental::DirectoryHasher;
+pub use options::{
+    ContentTransform, HashEncoding, HashMode, HashOptions, PathSeparator, SpecialFiles,
+};
+pub use progress::ProgressEvent;
+pub use report::{CountMismatch, DirDiff, ReadError, SyncPlan, ValidationReport};
+pub use types::{Digest, DirectorySummary, HashedDirectory, HashedFile, PathStats};
+#[cfg(feature = "notify")]
+pub use watch::{watch_directory, WatchUpdate};
+
 /// Convenience type for `std::io::Result` with more explicit name.
 pub type IOResult<Type> = std::io::Result<Type>;
 
-pub const HASH_RESULTS_FILENAME: &str = ".b3hash";
+/// Name of the hashfile written by `create_hashfile`. The suffix is the
+/// version of the hashfile format: optional `#`-prefixed header lines,
+/// followed by one `<hash> <path>` record per file.
+pub const HASH_RESULTS_FILENAME: &str = ".b3hash_v1";
+
+/// Name of the human-readable report `create_hashfile_with_report` writes
+/// next to the hashfile. Hidden like the hashfile, so it's never hashed.
+pub const REPORT_FILENAME: &str = ".b3hash_v1.txt";
+
+/// Length of the hex-encoded hash at the start of every hashfile record,
+/// unless the hashfile uses another `HashEncoding`.
+/// Records of files too large to content hash are the only exception,
+/// since they hold a size marker instead.
+pub const HASH_HEX_LEN: usize = 2 * blake3::OUT_LEN;
 
 /// TODO: docs
 #[inline(never)]
 pub fn hash_directory(dir_path: &str) -> IOResult<HashedDirectory> {
+    hash_directory_with_options(dir_path, &HashOptions::default())
+}
+
+/// Alias for `hash_directory`, but with the behavior controlled by `options`.
+#[inline(never)]
+pub fn hash_directory_with_options(
+    dir_path: &str,
+    options: &HashOptions,
+) -> IOResult<HashedDirectory> {
     // It is absolutely critical that the returned Vec always
     // returns the same ordering of file hashes, given the same root
     // directory. Otherwise, the overall directory hash will be random.
     // In our case this is solved by having hash_files_vec()
     // internally sort the Vec by file path before returning.
-    let hashed_files = hash_files(dir_path)?;
-    let mut total_bytes_hashed = 0;
-    let mut hasher = Hasher::new();
-
-    // It's slightly faster to fold the bytes of each file's hash and name
-    // into a Vec<u8>, then hash that, because the hasher is able to use
-    // vector instructions more consistently on larger [u8]'s.
-    // But the difference is insignificant for small directories,
-    // and for large directories the time spent here is miniscule
-    // compared to overall directory file hashing, so this simple
-    // and in-place implementation is prefered.
-    for file in &hashed_files {
-        hasher.update(file.hash.as_bytes());
-        hasher.update(file.path.as_bytes());
-        total_bytes_hashed += file.size;
+    let started_at = options.record_timestamps.then(SystemTime::now);
+    let (hashed_files, skipped) = hash_files(dir_path, options)?;
+    let (hash, size) = aggregate_with_options(&hashed_files, options);
+    Ok(HashedDirectory {
+        dir_name: dir_name(dir_path),
+        files: hashed_files,
+        hash,
+        size,
+        skipped: skipped.special,
+        unreadable_dirs: skipped.unreadable_dirs,
+        // Hashing always runs on the current pool, so this is exactly the
+        // pool installed by e.g. hash_directory_with_threads.
+        threads_used: rayon::current_num_threads(),
+        started_at,
+        finished_at: started_at.map(|_| SystemTime::now()),
+    })
+}
+
+/// Identical to `hash_directory(...).hash`, but without ever holding every
+/// `HashedFile` in memory at once: files are hashed a chunk at a time, and
+/// each chunk is folded into the aggregate and dropped before moving on.
+///
+/// For directories with tens of millions of files, this saves gigabytes
+/// when only the root hash matters. The (sorted) path list itself still
+/// needs to be held, and each chunk waits on its slowest file before the
+/// next can start, so this can be a little slower than `hash_directory`.
+#[inline(never)]
+pub fn hash_directory_root_only(dir_path: &str) -> IOResult<Hash> {
+    hash_root_in_chunks(dir_path, 1 << 14)
+}
+
+/// `hash_directory_root_only`, hashing `chunk_len` files at a time.
+fn hash_root_in_chunks(dir_path: &str, chunk_len: usize) -> IOResult<Hash> {
+    let mut hasher = blake3::Hasher::new();
+    hash_files_chunked(
+        dir_path,
+        &HashOptions::default(),
+        None,
+        chunk_len,
+        |chunk| {
+            update_with_hashed_files(&mut hasher, &chunk);
+        },
+    )?;
+    Ok(hasher.finalize())
+}
+
+/// Hashes `dir_path` into a single hash that only depends on what's inside
+/// it, so the same tree gives the same hash wherever it's mounted, on any
+/// machine or platform. This is `hash_directory(...).hash`, spelled out as
+/// a guarantee, except for paths being normalized (see below).
+///
+/// Exactly these go into the hash, for every visible regular file in path
+/// order: its plain blake3 hash, then its path relative to `dir_path`,
+/// always `/`-separated. Nothing else does: not `dir_path` itself (so not
+/// `HashedDirectory::dir_name` either), sizes, mtimes, permissions, owners,
+/// folders without files, or hidden entries and symlinks (which are never
+/// hashed by default).
+///
+/// Some filesystems (like macOS's) store names decomposed, so the same name
+/// can be different bytes on different machines. With the `unicode` feature
+/// paths are NFC normalized first (see `HashOptions::normalize_unicode`),
+/// which makes the hash hold up there too. Without it, a tree with any such
+/// name only hashes the same where the name is stored the same way.
+#[inline(never)]
+pub fn cross_machine_hash(dir_path: &str) -> IOResult<Hash> {
+    let options = HashOptions {
+        #[cfg(feature = "unicode")]
+        normalize_unicode: true,
+        ..Default::default()
+    };
+    hash_directory_with_options(dir_path, &options).map(|dir| dir.hash)
+}
+
+/// Hashes the single file at `file_path` while also writing its contents to
+/// each of `sinks` (e.g. hashers for other algorithms, as long as they
+/// implement `Write`), so every digest comes from one read of the file.
+///
+/// Since the contents have to pass through our own buffer to be shared, the
+/// file is streamed rather than memory mapped (see `read_buffer_size`). Only
+/// that and `HashOptions::mode` apply, so the blake3 hash is the same one
+/// `hash_directory` would record for the file in that mode.
+#[inline(never)]
+pub fn hash_file_tee(
+    file_path: &str,
+    sinks: &mut [&mut dyn Write],
+    options: &HashOptions,
+) -> IOResult<Hash> {
+    let mut hasher = options.mode.hasher();
+    let mut file = std::fs::File::open(file_path)?;
+    tee_reader(&mut hasher, sinks, &mut file, read_buffer_size(options))?;
+    for sink in sinks.iter_mut() {
+        sink.flush()?;
     }
+    Ok(hasher.finalize())
+}
+
+/// The aggregate hash of `files`, exactly as `hash_directory` computes it
+/// from its own: each file's hash then path, folded in path order.
+///
+/// The aggregate only means anything for files sorted by path, so `files`
+/// are sorted first (without copying them) unless they already are. Paths
+/// must be relative and `/`-separated like `HashedFile::path` always is
+/// (see `normalize_path`), and each one listed only once, to match.
+pub fn aggregate_hash(files: &[HashedFile]) -> Hash {
+    if files.windows(2).all(|pair| pair[0] <= pair[1]) {
+        return fold_hashed_files(files).0;
+    }
+    let mut sorted: Vec<&HashedFile> = files.iter().collect();
+    sorted.sort_unstable();
+    fold_hashed_files(sorted).0
+}
+
+/// Alias for `aggregate_hash`, but folding `files` whichever way `options`
+/// asks for (see `HashOptions::aggregate_sizes`), to match the aggregate of
+/// `hash_directory_with_options` too. Unsorted `files` are copied to sort them.
+pub fn aggregate_hash_with_options(files: &[HashedFile], options: &HashOptions) -> Hash {
+    if files.windows(2).all(|pair| pair[0] <= pair[1]) {
+        return aggregate_with_options(files, options).0;
+    }
+    let mut sorted = files.to_vec();
+    sorted.sort_unstable();
+    aggregate_with_options(&sorted, options).0
+}
+
+/// Links the root hash of a directory's `current` state onto the chained
+/// hash of all its `previous` states, for keeping a compact, tamper-evident
+/// history of snapshots where changing any earlier one changes every later
+/// link, like a hash chain.
+///
+/// The convention is to start with the first snapshot chained onto the
+/// all-zero hash, then chain each consecutive `hash_directory(...).hash` onto
+/// the previous link: `link[n] = chain_hash(&link[n - 1], &root[n])`. Storing
+/// every root hash plus the latest link is then enough to re-verify the
+/// whole history. Chaining is order-sensitive, so swapping the two
+/// arguments gives an unrelated hash.
+#[inline]
+pub fn chain_hash(previous: &Hash, current: &Hash) -> Hash {
+    // Domain separated from plain blake3, so a link can never collide
+    // with the hash of some file that happens to hold the same 64 bytes.
+    let mut hasher = blake3::Hasher::new_derive_key("b3hash v1 chain");
+    hasher.update(previous.as_bytes());
+    hasher.update(current.as_bytes());
+    hasher.finalize()
+}
 
+/// Hashes `dir_path` into a single hash of just the contents of its files,
+/// ignoring their paths entirely, so two directories holding the same set
+/// of files match even if every file was renamed or moved around.
+///
+/// Unlike `hash_directory(...).hash`, which covers each file's path too,
+/// this folds only the file hashes, sorted by the hashes themselves (since
+/// path order would depend on the names). Duplicate files still count once
+/// each, so the number of copies of a file does change this hash.
+#[inline(never)]
+pub fn content_only_hash(dir_path: &str) -> IOResult<Hash> {
+    let (hashed_files, _) = hash_files(dir_path, &HashOptions::default())?;
+    let mut digests: Vec<Digest> = hashed_files.iter().map(|file| file.hash.into()).collect();
+    digests.sort_unstable();
+    let mut hasher = blake3::Hasher::new();
+    for digest in &digests {
+        hasher.update(digest.as_bytes());
+    }
+    Ok(hasher.finalize())
+}
+
+/// Relative path and size of every file `hash_directory` would hash,
+/// in path order, without reading any of them. Only costs a stat per file,
+/// so it's far faster than hashing for finding out what's in a tree.
+#[inline(never)]
+pub fn inventory_directory(dir_path: &str) -> IOResult<Vec<(String, u64)>> {
+    let mut paths = relative_file_paths(dir_path, &HashOptions::default())?;
+    paths.par_sort_unstable();
+    paths
+        .into_par_iter()
+        .map(|path| {
+            let size = Utf8Path::new(dir_path).join(&path).metadata()?.len();
+            Ok((path, size))
+        })
+        .collect()
+}
+
+/// Identical to `hash_directory`, but hashing exactly the files at `paths`
+/// (relative to `dir_path`), e.g. from a build system's output manifest,
+/// rather than walking the whole directory tree to find them.
+///
+/// When `paths` lists every file `hash_directory` would find, the results
+/// are identical. Listed files are hashed even if they're hidden, and
+/// a listed file that doesn't exist is an error.
+#[inline(never)]
+pub fn hash_file_list(dir_path: &str, paths: &[&str]) -> IOResult<HashedDirectory> {
+    let hashed_files = hash_listed_files(dir_path, paths, &HashOptions::default())?;
+    let (hash, size) = fold_hashed_files(&hashed_files);
     Ok(HashedDirectory {
-        dir_name: Utf8Path::new(dir_path)
-            .file_name()
-            .unwrap_or(dir_path)
-            .to_string(),
+        dir_name: dir_name(dir_path),
         files: hashed_files,
-        hash: hasher.finalize(),
-        size: total_bytes_hashed,
+        hash,
+        size,
+        skipped: Vec::new(),
+        unreadable_dirs: Vec::new(),
+        threads_used: rayon::current_num_threads(),
+        started_at: None,
+        finished_at: None,
     })
 }
 
+/// Hashes every file beneath `dir_path` individually, skipping the sort
+/// that `hash_directory` needs, for callers that only care about the
+/// files themselves. The order is whatever the OS lists them in, and can
+/// change from one call to the next.
+///
+/// There's deliberately no aggregate hash: folding these files in this
+/// order wouldn't be deterministic. Sort them first if one is needed.
+#[inline(never)]
+pub fn hash_files_unsorted(dir_path: &str) -> IOResult<Vec<HashedFile>> {
+    util::hash_files_unsorted(dir_path, &HashOptions::default())
+}
+
+/// Identical to `hash_files_unsorted`, but handing back each file through
+/// the returned channel as soon as it's hashed, e.g. for uploading every
+/// file while the rest are still being hashed. Each file failing to hash
+/// is its own error, and every file after it still arrives.
+///
+/// Hashing runs on the global rayon pool, driven from a background thread.
+/// The channel closes once every file is done, and dropping the receiver
+/// early stops hashing the rest. Just like `hash_files_unsorted` there's
+/// no aggregate, which the files would have to be sorted for.
+pub fn hash_files_channel(dir_path: &str) -> Receiver<IOResult<HashedFile>> {
+    let (sender, receiver) = mpsc::channel();
+    let dir_path = dir_path.to_string();
+    std::thread::spawn(move || send_hashed_files(&dir_path, &HashOptions::default(), sender));
+    receiver
+}
+
+/// Computes a keyed MAC over all of `dir_path`, by folding the (regular,
+/// unkeyed) hash and path of every file into a keyed aggregate rather than
+/// a plain one. Any change to the contents or structure of the directory
+/// changes the MAC, and it can't be reproduced without `key`.
+#[inline(never)]
+pub fn hash_directory_mac(dir_path: &str, key: &[u8; blake3::KEY_LEN]) -> IOResult<Hash> {
+    let (hashed_files, _) = hash_files(dir_path, &HashOptions::default())?;
+    Ok(fold_hashed_files_into(blake3::Hasher::new_keyed(key), &hashed_files).0)
+}
+
+/// Recomputes the MAC of `dir_path` (see `hash_directory_mac`), returning
+/// whether it matches `mac`. The comparison is constant time.
+#[inline(never)]
+pub fn verify_directory_mac(
+    dir_path: &str,
+    key: &[u8; blake3::KEY_LEN],
+    mac: &Hash,
+) -> IOResult<bool> {
+    // Hash's own eq is guaranteed to be constant time on every platform,
+    // which matters here (unlike for content hashes) since MACs are secret.
+    Ok(hash_directory_mac(dir_path, key)? == *mac)
+}
+
+/// Hashes every directory listed in `reader`, one path per line.
+///
+/// Surrounding whitespace is trimmed and blank lines are skipped.
+/// Each directory gets its own result, so one directory failing doesn't
+/// abort the rest of the batch. An error reading from `reader` itself is
+/// recorded as the final result.
+pub fn hash_directories_from_reader<R: BufRead>(reader: R) -> Vec<IOResult<HashedDirectory>> {
+    let mut results = Vec::new();
+    for line in reader.lines() {
+        match line {
+            Ok(line) if line.trim().is_empty() => {}
+            Ok(line) => results.push(hash_directory(line.trim())),
+            Err(e) => {
+                results.push(Err(e));
+                break;
+            }
+        }
+    }
+    results
+}
+
 /// TODO: docs
 #[inline(never)]
 pub fn create_hashfile(dir_path: &str) -> IOResult<()> {
+    create_hashfile_with_options(dir_path, &HashOptions::default())
+}
+
+/// Identical to `create_hashfile`, but also writing a report of the results
+/// for humans next to the hashfile (see `REPORT_FILENAME`): the directory's
+/// summary followed by one line per file, i.e. the `HashedDirectory`'s
+/// `Display` output. The report is purely for documentation, and is never
+/// read back or validated.
+#[inline(never)]
+pub fn create_hashfile_with_report(dir_path: &str) -> IOResult<()> {
+    let hashed_dir = hash_directory(dir_path)?;
+    write_hashfiles(dir_path, &hashed_dir.files, &HashOptions::default(), None)?;
+    std::fs::write(
+        Utf8Path::new(".").join(REPORT_FILENAME),
+        hashed_dir.to_string(),
+    )
+}
+
+/// Alias for `create_hashfile`, but with the behavior controlled by `options`.
+#[inline(never)]
+pub fn create_hashfile_with_options(dir_path: &str, options: &HashOptions) -> IOResult<()> {
+    // Fail before spending any time hashing.
+    check_metadata(&options.metadata)?;
+    root_prefix(options)?;
+    let salt = options.salted.then(random_salt).transpose()?;
+    let (hashed_files, _) = hash_files_salted(dir_path, options, salt.as_ref())?;
+    write_hashfiles(dir_path, &hashed_files, options, salt)
+}
+
+/// Writes the hashfile (and any shards) for `hashed_files` of `dir_path`,
+/// which were hashed with `salt`.
+fn write_hashfiles(
+    dir_path: &str,
+    hashed_files: &[HashedFile],
+    options: &HashOptions,
+    salt: Option<[u8; 32]>,
+) -> IOResult<()> {
+    let header = Header {
+        dir_counts: options
+            .dir_counts
+            .then(|| count_files_per_dir(hashed_files.iter().map(|file| file.path.as_str()))),
+        mtime: options.record_mtime && !options.deterministic_hashfile,
+        size: options.record_size,
+        mode: describe_mode(&options.mode),
+        encoding: options.hash_encoding,
+        metadata: match options.deterministic_hashfile {
+            true => BTreeMap::new(),
+            false => options
+                .metadata
+                .iter()
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect(),
+        },
+        separator: Some(options.path_separator.as_char()).filter(|&separator| separator != '/'),
+        stamps: (options.subtree_stamps && !options.deterministic_hashfile).then(|| {
+            subtree_stamps(
+                hashed_files
+                    .iter()
+                    .map(|file| (file.path.as_str(), file.size, file.modified)),
+            )
+        }),
+        aggregate: options
+            .record_aggregate
+            .then(|| aggregate_with_options(hashed_files, options).0),
+        prefix: root_prefix(options)?,
+        salt,
+        dir_name: match options.record_dir_name {
+            true => Some(resolved_dir_name(dir_path)?),
+            false => None,
+        },
+        ..Default::default()
+    };
+    write_manifest(header, hashed_files, options.shards, options)
+}
+
+/// Rewrites the hashfile for the freshly hashed `hashed_files` of `dir_path`,
+/// keeping every setting recorded in the `old` manifest's header (columns,
+/// shards, salt and so on) rather than taking them from `options`. Only what's
+/// derived from the files themselves is recomputed, and only when the old
+/// header had it. The aggregate is recomputed with `options`, since how it
+/// was folded isn't recorded.
+fn rewrite_hashfiles(
+    dir_path: &str,
+    hashed_files: &mut [HashedFile],
+    old: &Header,
+    options: &HashOptions,
+) -> IOResult<()> {
+    // The files were only hashed with `options`, which needn't have asked for these.
+    if old.mtime || old.stamps.is_some() {
+        for file in hashed_files
+            .iter_mut()
+            .filter(|file| file.modified.is_none())
+        {
+            let path = Utf8Path::new(dir_path).join(&file.path);
+            file.modified = path.metadata().and_then(|meta| meta.modified()).ok();
+        }
+    }
+    let header = Header {
+        dir_counts: old
+            .dir_counts
+            .as_ref()
+            .map(|_| count_files_per_dir(hashed_files.iter().map(|file| file.path.as_str()))),
+        mtime: old.mtime,
+        size: old.size,
+        mode: old.mode.clone(),
+        encoding: old.encoding,
+        metadata: old.metadata.clone(),
+        separator: old.separator,
+        stamps: old.stamps.as_ref().map(|_| {
+            subtree_stamps(
+                hashed_files
+                    .iter()
+                    .map(|file| (file.path.as_str(), file.size, file.modified)),
+            )
+        }),
+        aggregate: old
+            .aggregate
+            .map(|_| aggregate_with_options(hashed_files, options).0),
+        prefix: old.prefix.clone(),
+        salt: old.salt,
+        dir_name: old.dir_name.clone(),
+        ..Default::default()
+    };
+    write_manifest(
+        header,
+        hashed_files,
+        NonZeroUsize::new(old.shards.len()),
+        options,
+    )
+}
+
+/// Writes the hashfile with `header` for `hashed_files`, split
+/// across `shards` separate shard files (if any).
+fn write_manifest(
+    mut header: Header,
+    hashed_files: &[HashedFile],
+    shards: Option<NonZeroUsize>,
+    options: &HashOptions,
+) -> IOResult<()> {
+    let hashfile_path = Utf8Path::new(".").join(HASH_RESULTS_FILENAME);
+    let records = match shards {
+        None => hashed_files,
+        Some(shards) => {
+            // Sorted order is kept across shards, so each one
+            // covers a contiguous range of file paths.
+            let shard_len = hashed_files.len().div_ceil(shards.get()).max(1);
+            let chunks: Vec<_> = hashed_files.chunks(shard_len).collect();
+            for (i, shard) in chunks.iter().enumerate() {
+                let shard_name = format!("{}.{}", HASH_RESULTS_FILENAME, i);
+                // Ranges are open at either end, so files added before the
+                // first or after the last one still belong to some shard.
+                let shard_header = Header {
+                    from: (i > 0).then(|| shard[0].path.clone()),
+                    until: chunks.get(i + 1).map(|next| next[0].path.clone()),
+                    ..header.for_shard()
+                };
+                write_hashfile(
+                    &Utf8Path::new(".").join(&shard_name),
+                    &shard_header,
+                    shard,
+                    options,
+                )?;
+                header.shards.push(shard_name);
+            }
+            // The manifest itself doesn't contain any records.
+            &[]
+        }
+    };
+    write_hashfile(&hashfile_path, &header, records, options)
+}
+
+/// `HashOptions::root_prefix`, `/`-separated and without any surrounding
+/// separators, or `None` if there's nothing left of it.
+fn root_prefix(options: &HashOptions) -> IOResult<Option<String>> {
+    let Some(prefix) = &options.root_prefix else {
+        return Ok(None);
+    };
+    if prefix.contains(['\n', '\r']) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Invalid root prefix '{}'.", prefix),
+        ));
+    }
+    let prefix = oi_vei(prefix);
+    let prefix = prefix.trim_matches('/');
+    Ok((!prefix.is_empty()).then(|| prefix.to_string()))
+}
+
+/// Reads back the metadata recorded in the header of the hashfile at
+/// `hashfile_path` (see `HashOptions::metadata`), without reading any of
+/// its records or shards.
+#[inline(never)]
+pub fn read_hashfile_metadata(hashfile_path: &str) -> IOResult<HashMap<String, String>> {
+    let data = std::fs::read_to_string(hashfile_path)?;
+    let (header, _) = Header::parse(&data)?;
+    Ok(header.metadata.into_iter().collect())
+}
+
+/// Re-hashes only the files beneath `subtree` (relative to `dir_path`) and
+/// splices them into the existing hashfile in place of that subtree's old
+/// records, leaving every other record exactly as it was. Far cheaper than
+/// `create_hashfile` when only one subtree changed.
+///
+/// Recorded per-directory file counts are updated to match. Fails with
+/// `ErrorKind::Unsupported` for sharded hashfiles, and ones recording
+/// subtree stamps or an aggregate, since those would need every file
+/// re-hashed (or stat'ed) anyway. The subtree is hashed with the default
+/// options, and at least has to exist.
+#[inline(never)]
+pub fn update_hashfile_subtree(dir_path: &str, subtree: &str) -> IOResult<()> {
     let hashfile_path = Utf8Path::new(".").join(HASH_RESULTS_FILENAME);
-    let hashed_files = hash_files(dir_path)?;
-    let data = serialize_hashed_files(hashed_files);
-    std::fs::write(hashfile_path, data)?;
-    Ok(())
+    let mut blocks = read_hashfile(&hashfile_path, false)?;
+    let (mut header, block) = blocks.swap_remove(0);
+    if !blocks.is_empty() || header.stamps.is_some() || header.aggregate.is_some() {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            "Can't update part of a sharded hashfile, or one with subtree stamps or an aggregate.",
+        ));
+    }
+    // Already recorded mtimes are kept, so the new records need theirs too.
+    let options = HashOptions {
+        record_mtime: header.mtime,
+        ..Default::default()
+    };
+    header.check_mode(&options.mode)?;
+
+    let subtree = oi_vei(subtree);
+    let subtree = subtree.trim_matches('/');
+    let prefix = match subtree.is_empty() {
+        true => String::new(),
+        false => format!("{}/", subtree),
+    };
+    let subtree_path = Utf8Path::new(dir_path).join(subtree);
+    let (fresh, _) = hash_files_salted(subtree_path.as_str(), &options, header.salt.as_ref())?;
+    let mut hashed_files = Vec::new();
+    for line in block.lines() {
+        let file = recorded_file(&header, line)?;
+        if !file.path.starts_with(&prefix) {
+            hashed_files.push(file);
+        }
+    }
+    hashed_files.extend(fresh.into_iter().map(|mut file| {
+        file.path.insert_str(0, &prefix);
+        file
+    }));
+    hashed_files.sort_unstable();
+    if header.dir_counts.is_some() {
+        header.dir_counts = Some(count_files_per_dir(
+            hashed_files.iter().map(|file| file.path.as_str()),
+        ));
+    }
+    write_hashfile(&hashfile_path, &header, &hashed_files, &options)
+}
+
+/// The `HashedFile` a record `line` under `header` was written from, as
+/// far as the hashfile tells: sizes are zero without a size column.
+fn recorded_file(header: &Header, line: &str) -> IOResult<HashedFile> {
+    let record = header.parse_record(line).ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("Failed to parse hashfile record '{}'.", line),
+        )
+    })?;
+    let (hash, size, size_only) = match parse_expected(header, record.hash)? {
+        Expected::Size(size) => (size_only_hash(size), size, true),
+        Expected::Hash(hash) => (hash, record.size.unwrap_or(0), false),
+    };
+    Ok(HashedFile {
+        hash,
+        path: record.path.into_owned(),
+        size,
+        modified: record
+            .mtime
+            .map(|secs| UNIX_EPOCH + Duration::from_secs(secs)),
+        size_only,
+    })
+}
+
+/// Re-hashes `dir_path` and compares it against the existing hashfile,
+/// returning the paths of every file that changed, was added or was
+/// removed since, then rewrites the hashfile to match. So calling this
+/// again straight away always returns nothing. The rewritten hashfile keeps
+/// whatever the old one recorded (columns, shards, salt and so on).
+///
+/// Paths are joined onto `dir_path`, just like when validating.
+#[inline(never)]
+pub fn changed_since(dir_path: &str) -> IOResult<Vec<String>> {
+    changed_since_with_options(dir_path, &HashOptions::default(), true)
+}
+
+/// Alias for `changed_since`, but with the behavior controlled by `options`,
+/// and only rewriting the hashfile when `update_hashfile` is set.
+#[inline(never)]
+pub fn changed_since_with_options(
+    dir_path: &str,
+    options: &HashOptions,
+    update_hashfile: bool,
+) -> IOResult<Vec<String>> {
+    if update_hashfile {
+        check_metadata(&options.metadata)?;
+    }
+    let blocks = read_hashfile(
+        &Utf8Path::new(".").join(HASH_RESULTS_FILENAME),
+        options.trusted_hashfile,
+    )?;
+    let mut recorded = recorded_files(&blocks, options)?;
+    // Every shard repeats the salt, and the rewritten hashfile keeps it.
+    let salt = blocks.first().and_then(|(header, _)| header.salt);
+    let (mut hashed_files, _) = hash_files_salted(dir_path, options, salt.as_ref())?;
+    let mut changed = Vec::new();
+    for file in &hashed_files {
+        match recorded.remove(file.path.as_str()) {
+            Some(expected) if expected.matches(file) => {}
+            // Either modified or added.
+            _ => changed.push(file.path.as_str()),
+        }
+    }
+    // Whatever's left was never seen in the live directory.
+    changed.extend(recorded.keys().map(|path| &**path));
+    changed.sort_unstable();
+
+    let root = oi_vei(dir_path);
+    let changed = changed
+        .into_iter()
+        .map(|path| Utf8Path::new(&root).join(path).into_string())
+        .collect();
+    if update_hashfile {
+        rewrite_hashfiles(dir_path, &mut hashed_files, &blocks[0].0, options)?;
+    }
+    Ok(changed)
+}
+
+/// Validates `dir_path` against its hashfile, then rewrites the hashfile to
+/// match the directory as it is now, accepting every change as the new
+/// baseline. The directory is only hashed once for both, so this is about
+/// as fast as validating alone. The rewritten hashfile keeps whatever the
+/// old one recorded (columns, shards, salt and so on).
+///
+/// The report is of what changed since the old hashfile: `failed_files` are
+/// the modified and removed files (plus the added ones with
+/// `HashOptions::strict`), and `added` the added ones. Only those are
+/// checked, none of the other `HashOptions` validation checks are done.
+#[inline(never)]
+pub fn validate_and_update(dir_path: &str) -> IOResult<ValidationReport> {
+    validate_and_update_with_options(dir_path, &HashOptions::default())
+}
+
+/// Alias for `validate_and_update`, but with the behavior controlled by `options`.
+#[inline(never)]
+pub fn validate_and_update_with_options(
+    dir_path: &str,
+    options: &HashOptions,
+) -> IOResult<ValidationReport> {
+    check_metadata(&options.metadata)?;
+    let blocks = read_hashfile(
+        &Utf8Path::new(".").join(HASH_RESULTS_FILENAME),
+        options.trusted_hashfile,
+    )?;
+    let mut recorded = recorded_files(&blocks, options)?;
+    let salt = blocks.first().and_then(|(header, _)| header.salt);
+    let (mut hashed_files, _) = hash_files_salted(dir_path, options, salt.as_ref())?;
+    let root = oi_vei(dir_path);
+    let full_path = |path: &str| Utf8Path::new(&root).join(path).into_string();
+    let mut report = ValidationReport::default();
+    for file in &hashed_files {
+        match recorded.remove(file.path.as_str()) {
+            Some(expected) if expected.matches(file) => {}
+            Some(_) => report.failed_files.push(full_path(&file.path)),
+            None => report.added.push(full_path(&file.path)),
+        }
+    }
+    report.missing = recorded.keys().map(|path| full_path(path)).collect();
+    report.failed_files.extend(report.missing.iter().cloned());
+    if options.strict {
+        report.failed_files.extend(report.added.iter().cloned());
+    }
+    report.sort();
+    rewrite_hashfiles(dir_path, &mut hashed_files, &blocks[0].0, options)?;
+    Ok(report)
+}
+
+/// What every file record in `blocks` expects of its file, keyed by path.
+fn recorded_files<'a>(
+    blocks: &'a [(Header, String)],
+    options: &HashOptions,
+) -> IOResult<BTreeMap<Cow<'a, str>, Expected>> {
+    let mut recorded = BTreeMap::new();
+    for (header, block) in blocks {
+        header.check_mode(&options.mode)?;
+        for line in block.lines() {
+            let record = header.parse_record(line).ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Failed to parse hashfile record '{}'.", line),
+                )
+            })?;
+            recorded.insert(record.path, parse_expected(header, record.hash)?);
+        }
+    }
+    Ok(recorded)
+}
+
+/// Exact size in bytes of the file records `create_hashfile` writes
+/// for `hashed_files`: for each file, the 64 character hex hash (or size
+/// marker, for files too large to content hash), a space, the path,
+/// and a newline. Header lines (only written when enabled in
+/// `HashOptions`) aren't included.
+pub fn estimated_hashfile_size(hashed_files: &[HashedFile]) -> usize {
+    let header = Header::default();
+    hashed_files
+        .iter()
+        .map(|file| header.record_len(file))
+        .sum()
+}
+
+/// The file records `create_hashfile` writes for `hashed_files`,
+/// without any header lines.
+pub fn serialize_hashed_files(hashed_files: &[HashedFile]) -> Vec<u8> {
+    util::serialize_hashed_files(hashed_files, &Header::default())
+}
+
+/// Identical to `serialize_hashed_files`, but writes the records straight
+/// into `writer` instead of collecting them into a `Vec`, so serializing
+/// millions of files doesn't need the whole output in memory at once.
+/// Each record is a separate write, so unbuffered sinks like `File`
+/// should be wrapped in a `BufWriter`.
+pub fn serialize_hashed_files_to<W: Write>(
+    hashed_files: &[HashedFile],
+    mut writer: W,
+) -> IOResult<()> {
+    util::serialize_hashed_files_to(hashed_files, &Header::default(), &mut writer)
+}
+
+/// Serializes `hashed_dir` in the format `b3sum` prints, so the results
+/// can be checked with `b3sum --check` from inside the hashed directory.
+///
+/// With `total_line`, a final `<root hash>  <dir name>` line is appended
+/// holding the directory checksum. `b3sum --check` can't verify that line
+/// (it would try to hash the directory itself), so only enable it for
+/// consumers that know to compare it against `HashedDirectory::hash`.
+pub fn serialize_coreutils(hashed_dir: &HashedDirectory, total_line: bool) -> Vec<u8> {
+    let total = total_line.then_some((&hashed_dir.hash, hashed_dir.dir_name.as_str()));
+    util::serialize_coreutils(&hashed_dir.files, total)
 }
 
 /// TODO: docs
 #[inline(never)]
 pub fn validate_hashfile(dir_path: &str) -> IOResult<Option<Vec<String>>> {
-    let hashfile_path = Utf8Path::new(".").join(HASH_RESULTS_FILENAME);
-    let data = std::fs::read(hashfile_path)?;
-    let failed_files = validate_data(dir_path, data)?;
+    let report = validate_hashfile_with_options(dir_path, &HashOptions::default())?;
+    let failed_files = report.failed_files;
     // The length of failed_files is the amount
     // of files that failed validation.
     Ok(match failed_files.len() {
@@ -76,6 +820,447 @@ pub fn validate_hashfile(dir_path: &str) -> IOResult<Option<Vec<String>>> {
     })
 }
 
+/// Alias for `validate_hashfile`, but with the behavior controlled by `options`,
+/// returning a full `ValidationReport` instead of just the failed files.
+#[inline(never)]
+pub fn validate_hashfile_with_options(
+    dir_path: &str,
+    options: &HashOptions,
+) -> IOResult<ValidationReport> {
+    let hashfile_path = Utf8Path::new(".").join(HASH_RESULTS_FILENAME);
+    validate_hashfile_at(hashfile_path.as_str(), dir_path, options)
+}
+
+/// Validates `dir_path` against the hashfile at `hashfile_path`, which may
+/// be a regular hashfile, a sharded hashfile's manifest, or a single shard.
+///
+/// Validating a manifest validates all of its shards. Since each shard is a
+/// complete hashfile for its range of (sorted) file paths, shards can also be
+/// validated independently (e.g. on different machines) and their reports
+/// combined: a shard only reports added files within its own range, and
+/// `HashOptions::count_precheck` only counts those. Directory counts are only
+/// recorded in the manifest though, so `HashOptions::dir_counts` needs it.
+#[inline(never)]
+pub fn validate_hashfile_at(
+    hashfile_path: &str,
+    dir_path: &str,
+    options: &HashOptions,
+) -> IOResult<ValidationReport> {
+    let mut blocks = read_hashfile(Utf8Path::new(hashfile_path), options.trusted_hashfile)?;
+    for (header, _) in &blocks {
+        header.check_mode(&options.mode)?;
+    }
+    let mut report = ValidationReport {
+        dir_name_mismatch: dir_name_mismatch(dir_path, &blocks[0].0)?,
+        ..Default::default()
+    };
+    if options.count_precheck {
+        // Every line of a block is exactly one file record.
+        let expected = blocks.iter().map(|(_, block)| block.lines().count()).sum();
+        report.structure_changed = count_precheck(dir_path, &blocks[0].0, expected, options)?;
+        if report.structure_changed.is_some() {
+            return Ok(report);
+        }
+    }
+    let unchanged_dirs = match options.subtree_stamps {
+        true => find_unchanged_dirs(dir_path, &blocks[0].0, options, &mut report)?,
+        false => HashSet::new(),
+    };
+    let context = ValidationContext::new(dir_path, options, unchanged_dirs)?;
+    for (header, block) in &blocks {
+        let failures = validate_data(dir_path, block, header, options, &context)?;
+        report
+            .silent_corruption
+            .extend(same_size_failures(dir_path, block, header, &failures));
+        report.push_failures(failures);
+    }
+    report.added = find_added_files(dir_path, &blocks, options)?;
+    if options.strict {
+        report.failed_files.extend(report.added.iter().cloned());
+    }
+    if options.dir_counts {
+        // Only the hashfile itself records directory counts, never its shards.
+        let expected = blocks.swap_remove(0).0.dir_counts.ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidData,
+                "Hashfile doesn't record directory file counts.",
+            )
+        })?;
+        report.count_mismatches = validate_dir_counts(dir_path, &expected, options)?;
+    }
+    report.sort();
+    Ok(report)
+}
+
+/// Identical to `validate_hashfile_with_options`, but reading and validating
+/// the hashfile one top-level subtree at a time, with at most `max_subtrees`
+/// of them (each validated in parallel) in flight at once. So only the
+/// records of those subtrees are ever held in memory, rather than the whole
+/// hashfile, which matters for huge trees on small machines. Files directly
+/// inside `dir_path` are validated as a subtree of their own.
+///
+/// Reports are identical to the all-at-once validation. A single huge
+/// subtree is still held all at once, so this helps most when the tree is
+/// spread over many top-level directories. Threads are whatever the rayon
+/// pool this runs on has, like everything else.
+#[inline(never)]
+pub fn validate_hashfile_by_subtree(
+    dir_path: &str,
+    options: &HashOptions,
+    max_subtrees: NonZeroUsize,
+) -> IOResult<ValidationReport> {
+    let hashfile_path = Utf8Path::new(".").join(HASH_RESULTS_FILENAME);
+    let (header, mut records) = open_records(&hashfile_path)?;
+    header.check_mode(&options.mode)?;
+    let mut report = ValidationReport {
+        dir_name_mismatch: dir_name_mismatch(dir_path, &header)?,
+        ..Default::default()
+    };
+    if options.count_precheck {
+        // Costs an extra read of the hashfile, but no more memory.
+        let (_, mut counted) = open_records(&hashfile_path)?;
+        let mut expected = 0;
+        while counted.next_line()?.is_some() {
+            expected += 1;
+        }
+        report.structure_changed = count_precheck(dir_path, &header, expected, options)?;
+        if report.structure_changed.is_some() {
+            return Ok(report);
+        }
+    }
+    let unchanged_dirs = match options.subtree_stamps {
+        true => find_unchanged_dirs(dir_path, &header, options, &mut report)?,
+        false => HashSet::new(),
+    };
+
+    let root = oi_vei(dir_path);
+    let (live_files, live_folders) =
+        get_top_level(Utf8Path::new(&root), EntryFilter::new(options))?;
+    let live_folders: HashSet<String> = live_folders
+        .into_iter()
+        .map(|folder| folder.file_name().unwrap_or_default().to_string())
+        .collect();
+    // Records are sorted by path, so every top-level subtree's records are
+    // contiguous. Only files directly inside dir_path can be spread
+    // between them, and those end up in multiple (root) blocks.
+    let mut root_files = HashSet::new();
+    let mut seen_subtrees = HashSet::new();
+    let mut batch: Vec<(String, String)> = Vec::with_capacity(max_subtrees.get());
+    let context = ValidationContext::new(&root, options, unchanged_dirs)?;
+    let validate_batch = |batch: &mut Vec<(String, String)>, report: &mut ValidationReport| {
+        let results = batch
+            .par_iter()
+            .map(|(subtree, block)| {
+                let find_added = live_folders.contains(subtree);
+                validate_subtree(
+                    &root, subtree, block, &header, options, &context, find_added,
+                )
+            })
+            .collect::<IOResult<Vec<_>>>()?;
+        for ((_, block), (failures, added)) in batch.iter().zip(results) {
+            report
+                .silent_corruption
+                .extend(same_size_failures(&root, block, &header, &failures));
+            report.push_failures(failures);
+            report.added.extend(added);
+        }
+        batch.clear();
+        IOResult::Ok(())
+    };
+    while let Some(line) = records.next_line()? {
+        let record = header.parse_record(line).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Failed to parse hashfile record '{}'.", line),
+            )
+        })?;
+        let subtree = match record.path.split_once('/') {
+            Some((subtree, _)) => subtree,
+            None => {
+                root_files.insert(record.path.to_string());
+                ""
+            }
+        };
+        match batch.last_mut() {
+            Some((last, block)) if last == subtree => {
+                block.push_str(line);
+                block.push('\n');
+            }
+            _ => {
+                if batch.len() == max_subtrees.get() {
+                    validate_batch(&mut batch, &mut report)?;
+                }
+                seen_subtrees.insert(subtree.to_string());
+                batch.push((subtree.to_string(), format!("{}\n", line)));
+            }
+        }
+    }
+    validate_batch(&mut batch, &mut report)?;
+
+    // Whatever wasn't covered by any subtree's records: files directly
+    // inside dir_path, and every file of subtrees without any records.
+    for file in live_files {
+        if !root_files.contains(file.file_name().unwrap_or_default()) {
+            report.added.push(oi_vei(file.as_str()));
+        }
+    }
+    for folder in live_folders.difference(&seen_subtrees) {
+        let folder = Utf8Path::new(&root).join(folder);
+        for path in relative_file_paths(folder.as_str(), options)? {
+            report.added.push(folder.join(path).into_string());
+        }
+    }
+    report.added.sort_unstable();
+    if options.strict {
+        report.failed_files.extend(report.added.iter().cloned());
+    }
+    if options.dir_counts {
+        let expected = header.dir_counts.as_ref().ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidData,
+                "Hashfile doesn't record directory file counts.",
+            )
+        })?;
+        report.count_mismatches = validate_dir_counts(dir_path, expected, options)?;
+    }
+    report.sort();
+    Ok(report)
+}
+
+/// Validates a single top-level `subtree` of `dir_path` (or the files
+/// directly inside it, when empty) against its hashfile records in `block`,
+/// returning the failures and, when `find_added` is set, which of its live
+/// files weren't recorded.
+fn validate_subtree(
+    dir_path: &str,
+    subtree: &str,
+    block: &str,
+    header: &Header,
+    options: &HashOptions,
+    context: &ValidationContext,
+    find_added: bool,
+) -> IOResult<(Vec<Failure>, Vec<String>)> {
+    let failures = validate_data(dir_path, block, header, options, context)?;
+    if !find_added {
+        return Ok((failures, Vec::new()));
+    }
+    let subtree_path = Utf8Path::new(dir_path).join(subtree);
+    let recorded: HashSet<Cow<str>> = block
+        .lines()
+        .filter_map(|line| header.parse_record(line))
+        .map(|record| record.path)
+        .collect();
+    let added = relative_file_paths(subtree_path.as_str(), options)?
+        .into_iter()
+        .map(|path| format!("{}/{}", subtree, path))
+        .filter(|path| !recorded.contains(path.as_str()))
+        .map(|path| Utf8Path::new(dir_path).join(path).into_string())
+        .collect();
+    Ok((failures, added))
+}
+
+/// `HashOptions::count_precheck`: compares the `expected` number of files
+/// against the live count within the range `header` covers, returning the
+/// mismatch if they differ.
+fn count_precheck(
+    dir_path: &str,
+    header: &Header,
+    expected: usize,
+    options: &HashOptions,
+) -> IOResult<Option<CountMismatch>> {
+    let found = relative_file_paths(dir_path, options)?
+        .iter()
+        .filter(|path| header.in_range(path))
+        .count();
+    Ok((expected != found).then(|| CountMismatch {
+        dir: String::new(),
+        expected,
+        found,
+    }))
+}
+
+/// `HashOptions::subtree_stamps`: finds every directory whose live subtree
+/// stamp matches the one `header` records, adding the topmost of them to
+/// `report.unchanged_dirs`.
+fn find_unchanged_dirs(
+    dir_path: &str,
+    header: &Header,
+    options: &HashOptions,
+    report: &mut ValidationReport,
+) -> IOResult<HashSet<String>> {
+    let recorded = header.stamps.as_ref().ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidData,
+            "Hashfile doesn't record subtree stamps.",
+        )
+    })?;
+    let live = live_subtree_stamps(dir_path, options)?;
+    let mut unchanged_dirs = HashSet::new();
+    // BTreeMap iterates parents before their children, so a directory
+    // is only reported when its parent wasn't already.
+    for (dir, stamp) in recorded {
+        if live.get(dir).is_some_and(|live| hash_eq(live, stamp)) {
+            if dir.is_empty() || !unchanged_dirs.contains(parent_dir(dir)) {
+                report.unchanged_dirs.push(dir.clone());
+            }
+            unchanged_dirs.insert(dir.clone());
+        }
+    }
+    Ok(unchanged_dirs)
+}
+
+/// Validates `dir_path` against `expected` hashes keyed by relative path
+/// (see `normalize_path`), e.g. from the caller's own database, without any
+/// hashfile involved. The live directory is hashed with the default options.
+///
+/// Files whose hash differs or that are missing from `dir_path` fail, and
+/// live files without an expected hash are `added`, just like validating
+/// against a hashfile. Paths in the report are joined onto `dir_path`.
+#[inline(never)]
+pub fn validate_directory_against(
+    dir_path: &str,
+    expected: &HashMap<String, Hash>,
+) -> IOResult<ValidationReport> {
+    let (live_files, _) = hash_files(dir_path, &HashOptions::default())?;
+    let root = oi_vei(dir_path);
+    let join = |path: &str| Utf8Path::new(&root).join(path).into_string();
+    let mut report = ValidationReport::default();
+    let mut seen = HashSet::with_capacity(live_files.len());
+    for file in &live_files {
+        match expected.get(&file.path) {
+            Some(hash) => {
+                seen.insert(file.path.as_str());
+                if !hash_eq(hash, &file.hash) {
+                    report.failed_files.push(join(&file.path));
+                }
+            }
+            None => report.added.push(join(&file.path)),
+        }
+    }
+    report.missing = expected
+        .keys()
+        .filter(|path| !seen.contains(path.as_str()))
+        .map(|path| join(path))
+        .collect();
+    report.failed_files.extend(report.missing.iter().cloned());
+    report.sort();
+    Ok(report)
+}
+
+/// Validates `target_dir` against the hashfile at `source_hashfile_path`,
+/// which was created for some other directory (e.g. the original that
+/// `target_dir` was copied from). This is the "did my copy succeed" check.
+///
+/// Recorded paths are always relative to the hashed directory, so they're
+/// simply re-rooted under `target_dir`. To control the behavior with
+/// `HashOptions`, use `validate_hashfile_at` directly.
+#[inline(never)]
+pub fn validate_copy(source_hashfile_path: &str, target_dir: &str) -> IOResult<ValidationReport> {
+    validate_hashfile_at(source_hashfile_path, target_dir, &HashOptions::default())
+}
+
+/// Re-hashes `dir_path` and compares just its aggregate hash against the
+/// one recorded in the hashfile, returning whether they match.
+///
+/// Every file still has to be hashed, so this is no faster than full
+/// validation, but gives a single yes or no rather than a report. The
+/// hashfile must have been created with `HashOptions::record_aggregate`.
+#[inline(never)]
+pub fn verify_aggregate(dir_path: &str) -> IOResult<bool> {
+    verify_aggregate_with_options(dir_path, &HashOptions::default())
+}
+
+/// Alias for `verify_aggregate`, but with the behavior controlled by `options`.
+#[inline(never)]
+pub fn verify_aggregate_with_options(dir_path: &str, options: &HashOptions) -> IOResult<bool> {
+    let hashfile_path = Utf8Path::new(".").join(HASH_RESULTS_FILENAME);
+    let data = std::fs::read_to_string(&hashfile_path)?;
+    let (header, _) = Header::parse(&data)?;
+    header.check_mode(&options.mode)?;
+    let aggregate = header.aggregate.ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            "Hashfile doesn't record an aggregate hash (see HashOptions::record_aggregate).",
+        )
+    })?;
+    let (hashed_files, _) = hash_files_salted(dir_path, options, header.salt.as_ref())?;
+    Ok(hash_eq(
+        &aggregate_with_options(&hashed_files, options).0,
+        &aggregate,
+    ))
+}
+
+/// Hashes the live directories `dir_a` and `dir_b` and compares them,
+/// without any hashfile involved. This is the ad-hoc "are these two
+/// folders identical" check.
+#[inline(never)]
+pub fn compare_directories(dir_a: &str, dir_b: &str) -> IOResult<DirDiff> {
+    let options = HashOptions::default();
+    let (files_a, _) = hash_files(dir_a, &options)?;
+    let (files_b, _) = hash_files(dir_b, &options)?;
+    Ok(dir_diff(&files_a, &files_b))
+}
+
+/// Validates only the files at the given `paths` (relative to
+/// `dir_path`) against the hashfile, without touching any other file.
+///
+/// Much faster than full validation when only a few files matter.
+/// Fails with `ErrorKind::NotFound` if any of `paths` isn't recorded
+/// in the hashfile. Only `ValidationReport::failed_files` (and
+/// `ValidationReport::read_errors`) are populated.
+#[inline(never)]
+pub fn validate_paths(dir_path: &str, paths: &[&str]) -> IOResult<ValidationReport> {
+    validate_paths_with_options(dir_path, paths, &HashOptions::default())
+}
+
+/// Alias for `validate_paths`, but with the behavior controlled by `options`.
+#[inline(never)]
+pub fn validate_paths_with_options(
+    dir_path: &str,
+    paths: &[&str],
+    options: &HashOptions,
+) -> IOResult<ValidationReport> {
+    let blocks = read_hashfile(
+        &Utf8Path::new(".").join(HASH_RESULTS_FILENAME),
+        options.trusted_hashfile,
+    )?;
+    for (header, _) in &blocks {
+        header.check_mode(&options.mode)?;
+    }
+    let mut report = ValidationReport::default();
+    report.push_failures(validate_selected(dir_path, &blocks, paths, options)?);
+    report.sort();
+    Ok(report)
+}
+
+/// Relative paths of every directory beneath `dir_path` that doesn't
+/// contain a single visible file, even in its subdirectories, in path
+/// order. `dir_path` itself is never included.
+#[inline(never)]
+pub fn empty_directories(dir_path: &str) -> IOResult<Vec<String>> {
+    empty_folders(dir_path)
+}
+
+/// Number of hidden (dot-prefixed) files and folders beneath `dir_path`
+/// that hashing skips, e.g. for warning that they aren't covered. A hidden
+/// folder counts once, without anything inside of it. The hashfile
+/// itself is hidden too, so it's counted whenever it exists.
+#[inline(never)]
+pub fn count_hidden(dir_path: &str) -> IOResult<usize> {
+    hidden_entries(dir_path)
+}
+
+/// Normalizes `path` exactly the way hashed file paths are normalized
+/// internally, so caller-built paths can be compared against (or used to
+/// look up) the paths stored in a `HashedDirectory` or hashfile.
+///
+/// On Windows every `\` is converted to `/`. Everywhere else
+/// the path is returned unchanged.
+#[inline]
+pub fn normalize_path(path: &str) -> String {
+    oi_vei(path)
+}
+
 /// Alias for `hash_directory`, but with `num_threads` number
 /// of threads to be used in the rayon threadpool.
 pub fn hash_directory_with_threads(
@@ -85,6 +1270,24 @@ pub fn hash_directory_with_threads(
     with_threads(num_threads, || hash_directory(dir_path))
 }
 
+/// Alias for `hash_directory`, but only using half the available cores
+/// (see `power_save_threads`), for laptops and single board computers where
+/// maxing out every core throttles them (or drains their battery) more
+/// than it speeds up hashing.
+pub fn hash_directory_power_save(dir_path: &str) -> IOResult<HashedDirectory> {
+    hash_directory_with_threads(dir_path, power_save_threads(0.5))
+}
+
+/// Number of threads that uses `fraction` of the available cores, rounded
+/// down, for passing to e.g. `hash_directory_with_threads`. Always at least
+/// one, and never more than there are cores, whatever `fraction` is.
+pub fn power_save_threads(fraction: f64) -> usize {
+    let cores = std::thread::available_parallelism().map_or(1, NonZeroUsize::get);
+    // NaN makes it through the clamp, but casts to zero.
+    let threads = (cores as f64 * fraction.clamp(0.0, 1.0)) as usize;
+    threads.max(1)
+}
+
 /// Alias for `create_hashfile`, but with `num_threads` number
 /// of threads to be used in the rayon threadpool.
 pub fn create_hashfile_with_threads(dir_path: &str, num_threads: usize) -> IOResult<()> {
@@ -114,3 +1317,1508 @@ where
         .expect("BUG: Initializing unique threadpools should never fail.")
         .install(func)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::Scratch;
+
+    #[cfg(unix)]
+    #[test]
+    #[ignore = "needs a non-root user, since root can read the locked folder anyway"]
+    fn validating_skips_the_same_unreadable_folders_as_hashing() {
+        use std::fs::Permissions;
+        use std::os::unix::fs::PermissionsExt;
+
+        let scratch = Scratch::new();
+        scratch.write("tree/kept.txt", "kept");
+        scratch.write("tree/locked/secret.txt", "secret");
+        let locked = scratch.path().join("tree/locked");
+        std::fs::set_permissions(&locked, Permissions::from_mode(0o000)).unwrap();
+        let readable = locked.read_dir().is_ok();
+        let options = HashOptions {
+            skip_unreadable_dirs: true,
+            dir_counts: true,
+            ..Default::default()
+        };
+        let report = create_hashfile_with_options("tree", &options)
+            .and_then(|_| validate_hashfile_at(HASH_RESULTS_FILENAME, "tree", &options));
+        std::fs::set_permissions(&locked, Permissions::from_mode(0o755)).unwrap();
+        assert!(
+            !readable,
+            "the folder was still readable, so nothing was skipped"
+        );
+        let report = report.unwrap();
+        assert!(report.failed_files.is_empty());
+        assert!(report.added.is_empty());
+        assert!(report.count_mismatches.is_empty());
+        assert!(!scratch.hashfile().contains("secret.txt"));
+    }
+
+    /// Every hashfile in the scratch directory, by name.
+    fn hashfiles(scratch: &Scratch) -> BTreeMap<String, String> {
+        std::fs::read_dir(scratch.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.is_file())
+            .map(|path| {
+                let name = path.file_name().unwrap().to_string_lossy().into_owned();
+                (name, std::fs::read_to_string(path).unwrap())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn changed_since_rewrites_with_the_recorded_header() {
+        let scratch = Scratch::new();
+        scratch.write("tree/a.txt", "alpha");
+        scratch.write("tree/sub/b.txt", "beta");
+        scratch.write("tree/sub/c.txt", "gamma");
+        let options = HashOptions {
+            salted: true,
+            record_mtime: true,
+            record_size: true,
+            dir_counts: true,
+            record_aggregate: true,
+            shards: NonZeroUsize::new(2),
+            hash_encoding: HashEncoding::Base32,
+            path_separator: PathSeparator::Backslash,
+            root_prefix: Some("backup".to_string()),
+            metadata: HashMap::from([("host".to_string(), "nas".to_string())]),
+            ..Default::default()
+        };
+        create_hashfile_with_options("tree", &options).unwrap();
+        let created = hashfiles(&scratch);
+        assert_eq!(created.len(), 3);
+
+        // Nothing changed, so the rewrite (with default options) is identical.
+        assert!(changed_since("tree").unwrap().is_empty());
+        assert_eq!(hashfiles(&scratch), created);
+
+        scratch.write("tree/sub/b.txt", "BETA");
+        assert_eq!(changed_since("tree").unwrap(), ["tree/sub/b.txt"]);
+        assert!(changed_since("tree").unwrap().is_empty());
+        let rewritten = hashfiles(&scratch);
+        let header = |files: &BTreeMap<String, String>| {
+            files[HASH_RESULTS_FILENAME]
+                .lines()
+                .filter(|line| !line.starts_with("#aggregate"))
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(header(&rewritten), header(&created));
+        assert_ne!(rewritten, created);
+    }
+
+    #[test]
+    fn validate_and_update_keeps_a_salted_hashfile_salted() {
+        let scratch = Scratch::new();
+        scratch.write("tree/kept.txt", "kept");
+        scratch.write("tree/edited.txt", "before");
+        scratch.write("tree/removed.txt", "removed");
+        let options = HashOptions {
+            salted: true,
+            record_size: true,
+            ..Default::default()
+        };
+        create_hashfile_with_options("tree", &options).unwrap();
+        let salt_line = |hashfile: &str| {
+            hashfile
+                .lines()
+                .find(|line| line.starts_with("#salt "))
+                .map(str::to_string)
+        };
+        let salt = salt_line(&scratch.hashfile()).expect("salt should be recorded");
+
+        scratch.write("tree/edited.txt", "after");
+        scratch.write("tree/new.txt", "new");
+        std::fs::remove_file("tree/removed.txt").unwrap();
+        let report = validate_and_update("tree").unwrap();
+        assert_eq!(report.failed_files, ["tree/edited.txt", "tree/removed.txt"]);
+        assert_eq!(report.added, ["tree/new.txt"]);
+
+        let hashfile = scratch.hashfile();
+        assert_eq!(salt_line(&hashfile), Some(salt));
+        assert!(hashfile.contains("#column size"));
+        // The new baseline validates cleanly, salt and all.
+        let report = validate_and_update("tree").unwrap();
+        assert!(report.failed_files.is_empty() && report.added.is_empty());
+        assert_eq!(validate_hashfile("tree").unwrap(), None);
+    }
+
+    #[test]
+    fn aggregate_sizes_wins_over_aggregate_via_buffer() {
+        let scratch = Scratch::new();
+        scratch.write("tree/a.bin", [0u8; 3]);
+        scratch.write("tree/b.bin", [1u8; 5]);
+        let aggregate = |aggregate_via_buffer, aggregate_sizes| {
+            let options = HashOptions {
+                aggregate_via_buffer,
+                aggregate_sizes,
+                ..Default::default()
+            };
+            let hashed_dir = hash_directory_with_options("tree", &options).unwrap();
+            let mut shuffled = hashed_dir.files.clone();
+            shuffled.reverse();
+            assert_eq!(
+                aggregate_hash_with_options(&shuffled, &options),
+                hashed_dir.hash
+            );
+            hashed_dir.hash
+        };
+        assert_eq!(aggregate(true, false), aggregate(false, false));
+        assert_eq!(aggregate(true, true), aggregate(false, true));
+        assert_ne!(aggregate(false, true), aggregate(false, false));
+    }
+
+    #[test]
+    fn estimated_hashfile_size_is_exact() {
+        let scratch = Scratch::new();
+        scratch.write("tree/plain.txt", "plain");
+        scratch.write("tree/with space.txt", "spaced");
+        scratch.write("tree/deep/er/ünïcode ✓.txt", "multibyte");
+        scratch.write("tree/empty", "");
+        let hashed_dir = hash_directory("tree").unwrap();
+        create_hashfile("tree").unwrap();
+        let written = scratch.hashfile().len();
+        assert_eq!(estimated_hashfile_size(&hashed_dir.files), written);
+        assert_eq!(serialize_hashed_files(&hashed_dir.files).len(), written);
+    }
+
+    #[test]
+    fn strict_validation_fails_unexpected_files() {
+        let scratch = Scratch::new();
+        scratch.write("tree/expected.txt", "expected");
+        create_hashfile("tree").unwrap();
+        scratch.write("tree/dropped/payload.sh", "unexpected");
+
+        let lenient = validate_hashfile_with_options("tree", &HashOptions::default()).unwrap();
+        assert!(lenient.is_valid());
+        assert_eq!(lenient.added, ["tree/dropped/payload.sh"]);
+
+        let strict = HashOptions {
+            strict: true,
+            ..Default::default()
+        };
+        let report = validate_hashfile_with_options("tree", &strict).unwrap();
+        assert!(!report.is_valid());
+        assert_eq!(report.failed_files, ["tree/dropped/payload.sh"]);
+        assert_eq!(report.added, report.failed_files);
+    }
+
+    #[test]
+    fn validating_by_subtree_matches_validating_all_at_once() {
+        let scratch = Scratch::new();
+        for path in [
+            "a.txt",
+            "m.txt",
+            "z.txt",
+            "docs/x.md",
+            "docs/y.md",
+            "src/lib.rs",
+            "src/deep/mod.rs",
+            "zz/keep",
+        ] {
+            scratch.write(&format!("tree/{}", path), path);
+        }
+        let options = HashOptions {
+            dir_counts: true,
+            ..Default::default()
+        };
+        create_hashfile_with_options("tree", &options).unwrap();
+        scratch.write("tree/m.txt", "edited root file");
+        scratch.write("tree/src/deep/mod.rs", "edited");
+        scratch.write("tree/docs/new.md", "added");
+        scratch.write("tree/fresh/top.txt", "new top-level folder");
+        std::fs::remove_file("tree/zz/keep").unwrap();
+
+        let whole = validate_hashfile_with_options("tree", &options).unwrap();
+        assert_eq!(
+            whole.failed_files,
+            ["tree/m.txt", "tree/src/deep/mod.rs", "tree/zz/keep"]
+        );
+        assert_eq!(whole.added, ["tree/docs/new.md", "tree/fresh/top.txt"]);
+        for max_subtrees in [1, 2, 8] {
+            let max_subtrees = NonZeroUsize::new(max_subtrees).unwrap();
+            let by_subtree = validate_hashfile_by_subtree("tree", &options, max_subtrees).unwrap();
+            assert_eq!(by_subtree.failed_files, whole.failed_files);
+            assert_eq!(by_subtree.added, whole.added);
+            let counts = |report: &ValidationReport| {
+                report
+                    .count_mismatches
+                    .iter()
+                    .map(|m| (m.dir.clone(), m.expected, m.found))
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(counts(&by_subtree), counts(&whole));
+            assert!(!counts(&whole).is_empty());
+        }
+    }
+
+    #[test]
+    fn validate_directory_against_a_callers_own_hashes() {
+        let scratch = Scratch::new();
+        scratch.write("tree/ok.txt", "ok");
+        scratch.write("tree/sub/edited.txt", "original");
+        scratch.write("tree/sub/gone.txt", "gone");
+        let expected: HashMap<String, Hash> = hash_directory("tree")
+            .unwrap()
+            .files
+            .into_iter()
+            .map(Into::into)
+            .collect();
+        assert!(validate_directory_against("tree", &expected)
+            .unwrap()
+            .is_valid());
+
+        scratch.write("tree/sub/edited.txt", "edited");
+        scratch.write("tree/new.txt", "new");
+        std::fs::remove_file("tree/sub/gone.txt").unwrap();
+        let report = validate_directory_against("tree", &expected).unwrap();
+        assert_eq!(
+            report.failed_files,
+            ["tree/sub/edited.txt", "tree/sub/gone.txt"]
+        );
+        assert_eq!(report.added, ["tree/new.txt"]);
+        // No hashfile was ever involved.
+        assert!(!scratch.path().join(HASH_RESULTS_FILENAME).exists());
+    }
+
+    #[test]
+    fn update_hashfile_subtree_only_touches_that_subtree() {
+        let scratch = Scratch::new();
+        scratch.write("tree/root.txt", "root");
+        scratch.write("tree/sub/a.txt", "a");
+        scratch.write("tree/sub/b.txt", "b");
+        scratch.write("tree/other/c.txt", "c");
+        let options = HashOptions {
+            dir_counts: true,
+            record_size: true,
+            ..Default::default()
+        };
+        create_hashfile_with_options("tree", &options).unwrap();
+        scratch.write("tree/sub/a.txt", "a, edited");
+        scratch.write("tree/sub/deeper/new.txt", "new");
+        std::fs::remove_file("tree/sub/b.txt").unwrap();
+        scratch.write("tree/other/c.txt", "c, edited but not re-hashed");
+
+        update_hashfile_subtree("tree", "sub").unwrap();
+        let report = validate_hashfile_with_options("tree", &options).unwrap();
+        assert_eq!(report.failed_files, ["tree/other/c.txt"]);
+        assert!(report.added.is_empty());
+        assert!(report.count_mismatches.is_empty());
+        assert!(scratch.hashfile().contains("sub/deeper/new.txt"));
+
+        let sharded = HashOptions {
+            shards: NonZeroUsize::new(2),
+            ..Default::default()
+        };
+        create_hashfile_with_options("tree", &sharded).unwrap();
+        let err = update_hashfile_subtree("tree", "sub").err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn dir_counts_catch_a_file_added_to_one_folder() {
+        let scratch = Scratch::new();
+        scratch.write("tree/a.txt", "a");
+        scratch.write("tree/sub/b.txt", "b");
+        scratch.write("tree/other/c.txt", "c");
+        let options = HashOptions {
+            dir_counts: true,
+            ..Default::default()
+        };
+        create_hashfile_with_options("tree", &options).unwrap();
+        scratch.write("tree/sub/new.txt", "new");
+
+        let report = validate_hashfile_with_options("tree", &options).unwrap();
+        assert!(report.failed_files.is_empty());
+        let mismatches: Vec<_> = report
+            .count_mismatches
+            .iter()
+            .map(|m| (&*m.dir, m.expected, m.found))
+            .collect();
+        assert_eq!(mismatches, [("sub", 1, 2)]);
+    }
+
+    #[test]
+    fn normalize_path_matches_the_hashed_paths() {
+        let scratch = Scratch::new();
+        scratch.write("tree/sub/b.txt", "b");
+        let hashed_dir = hash_directory("tree").unwrap();
+        let native = format!("sub{}b.txt", std::path::MAIN_SEPARATOR);
+        let normalized = normalize_path(&native);
+        assert_eq!(normalized, "sub/b.txt");
+        assert!(hashed_dir.files.iter().any(|f| f.path == normalized));
+        #[cfg(not(windows))]
+        assert_eq!(normalize_path(r"odd\name"), r"odd\name");
+    }
+
+    #[test]
+    fn shards_validated_separately_cover_every_file_once() {
+        let scratch = Scratch::new();
+        for path in ["a.txt", "b.txt", "c.txt", "sub/x.txt", "sub/y.txt", "z.txt"] {
+            scratch.write(&format!("tree/{}", path), path);
+        }
+        let options = HashOptions {
+            shards: NonZeroUsize::new(3),
+            // Bounds are written with the separator too, and read back.
+            path_separator: PathSeparator::Backslash,
+            ..Default::default()
+        };
+        create_hashfile_with_options("tree", &options).unwrap();
+        // One added file within (or past either end of) each shard's range.
+        for path in ["0.txt", "sub/xx.txt", "zz.txt"] {
+            scratch.write(&format!("tree/{}", path), "added");
+        }
+        scratch.write("tree/sub/x.txt", "edited");
+
+        let whole = validate_hashfile_with_options("tree", &options).unwrap();
+        let precheck = HashOptions {
+            count_precheck: true,
+            ..Default::default()
+        };
+        let (mut failed, mut added) = (Vec::new(), Vec::new());
+        for i in 0..3 {
+            let shard = format!("{}.{}", HASH_RESULTS_FILENAME, i);
+            let report = validate_hashfile_at(&shard, "tree", &options).unwrap();
+            assert_eq!(report.added.len(), 1, "{}", shard);
+            failed.extend(report.failed_files);
+            added.extend(report.added);
+            let mismatch = validate_hashfile_at(&shard, "tree", &precheck)
+                .unwrap()
+                .structure_changed
+                .unwrap();
+            assert_eq!((mismatch.expected, mismatch.found), (2, 3));
+        }
+        added.sort();
+        assert_eq!(failed, whole.failed_files);
+        assert_eq!(failed, ["tree/sub/x.txt"]);
+        assert_eq!(added, whole.added);
+        assert_eq!(added, ["tree/0.txt", "tree/sub/xx.txt", "tree/zz.txt"]);
+    }
+
+    #[test]
+    fn count_precheck_skips_validating_a_changed_structure() {
+        let scratch = Scratch::new();
+        scratch.write("tree/a.txt", "a");
+        scratch.write("tree/b.txt", "b");
+        create_hashfile("tree").unwrap();
+        let options = HashOptions {
+            count_precheck: true,
+            ..Default::default()
+        };
+        scratch.write("tree/a.txt", "edited");
+        // Same number of files, so validation goes ahead as usual.
+        let report = validate_hashfile_with_options("tree", &options).unwrap();
+        assert!(report.structure_changed.is_none());
+        assert_eq!(report.failed_files, ["tree/a.txt"]);
+
+        scratch.write("tree/c.txt", "added");
+        let report = validate_hashfile_with_options("tree", &options).unwrap();
+        let mismatch = report.structure_changed.as_ref().unwrap();
+        assert_eq!(
+            (&*mismatch.dir, mismatch.expected, mismatch.found),
+            ("", 2, 3)
+        );
+        // Nothing was hashed, so the edit (and the addition) go unreported.
+        assert!(report.failed_files.is_empty());
+        assert!(report.added.is_empty());
+    }
+
+    #[test]
+    fn hash_directories_from_reader_gives_each_line_its_own_result() {
+        let scratch = Scratch::new();
+        scratch.write("one/a.txt", "a");
+        scratch.write("two/b.txt", "b");
+        let mut input = b"one\n\n  two  \nmissing\n".to_vec();
+        // Not utf8, so reading the line itself fails and ends the batch.
+        input.extend_from_slice(b"\xff\xfe\nnever read\n");
+        let results = hash_directories_from_reader(std::io::Cursor::new(input));
+        assert_eq!(results.len(), 4);
+        let hash = |i: usize| results[i].as_ref().unwrap().hash;
+        assert_eq!(hash(0), hash_directory("one").unwrap().hash);
+        assert_eq!(hash(1), hash_directory("two").unwrap().hash);
+        let kind = |i: usize| results[i].as_ref().err().unwrap().kind();
+        assert_eq!(kind(2), ErrorKind::NotFound);
+        assert_eq!(kind(3), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn every_record_starts_with_a_hash_of_hash_hex_len() {
+        let scratch = Scratch::new();
+        scratch.write("tree/a.txt", "a");
+        scratch.write("tree/with space.txt", "spaced");
+        assert_eq!(HASH_HEX_LEN, blake3::hash(b"").to_hex().len());
+        create_hashfile("tree").unwrap();
+        let hashed_dir = hash_directory("tree").unwrap();
+        let hashfile = scratch.hashfile();
+        for (line, file) in hashfile.lines().zip(&hashed_dir.files) {
+            let (hash, path) = line.split_at(HASH_HEX_LEN);
+            assert_eq!(hash, file.hash.to_hex().as_str());
+            assert_eq!(path, format!(" {}", file.path));
+        }
+    }
+
+    #[test]
+    fn threads_used_is_the_pool_hashing_ran_on() {
+        let scratch = Scratch::new();
+        scratch.write("tree/a.txt", "a");
+        let hashed_dir = hash_directory_with_threads("tree", 2).unwrap();
+        assert_eq!(hashed_dir.threads_used, 2);
+        let hashed_dir = hash_directory_with_threads("tree", 3).unwrap();
+        assert_eq!(hashed_dir.threads_used, 3);
+        assert_eq!(
+            hash_directory("tree").unwrap().threads_used,
+            rayon::current_num_threads()
+        );
+    }
+
+    #[test]
+    fn validate_paths_only_touches_the_given_files() {
+        use crate::util::CONTENTS_HASHED;
+        use std::sync::atomic::Ordering;
+
+        let scratch = Scratch::new();
+        for path in ["a.txt", "b.txt", "sub/c.txt", "sub/d.txt"] {
+            scratch.write(&format!("tree/{}", path), path);
+        }
+        create_hashfile("tree").unwrap();
+        scratch.write("tree/a.txt", "edited");
+        scratch.write("tree/sub/c.txt", "edited");
+
+        let before = CONTENTS_HASHED.load(Ordering::Relaxed);
+        let report = validate_paths("tree", &["sub/c.txt", "b.txt"]).unwrap();
+        assert_eq!(CONTENTS_HASHED.load(Ordering::Relaxed) - before, 2);
+        // a.txt changed too, but wasn't asked about.
+        assert_eq!(report.failed_files, ["tree/sub/c.txt"]);
+        assert!(report.added.is_empty());
+
+        let err = validate_paths("tree", &["b.txt", "unrecorded.txt"])
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn continue_on_error_turns_unreadable_files_into_failures() {
+        let scratch = Scratch::new();
+        scratch.write("tree/a.txt", "a");
+        scratch.write("tree/b.txt", "b");
+        scratch.write("tree/c.txt", "c");
+        create_hashfile("tree").unwrap();
+        // Still there, but can't be read as a file (even by root, unlike chmod).
+        std::fs::remove_file("tree/b.txt").unwrap();
+        std::fs::create_dir("tree/b.txt").unwrap();
+        scratch.write("tree/c.txt", "edited");
+
+        let err = validate_hashfile_with_options("tree", &HashOptions::default())
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("tree/b.txt"));
+
+        let options = HashOptions {
+            continue_on_error: true,
+            ..Default::default()
+        };
+        let report = validate_hashfile_with_options("tree", &options).unwrap();
+        // Validation carried on past b.txt and still caught c.txt.
+        assert_eq!(report.failed_files, ["tree/b.txt", "tree/c.txt"]);
+        let read_errors: Vec<_> = report.read_errors.iter().map(|e| &*e.path).collect();
+        assert_eq!(read_errors, ["tree/b.txt"]);
+    }
+
+    #[test]
+    fn directory_macs_need_the_key_and_the_same_contents() {
+        let scratch = Scratch::new();
+        scratch.write("tree/a.txt", "a");
+        scratch.write("tree/sub/b.txt", "b");
+        let key = [7; blake3::KEY_LEN];
+        let mac = hash_directory_mac("tree", &key).unwrap();
+        assert!(verify_directory_mac("tree", &key, &mac).unwrap());
+        // Not just the plain aggregate, and different for every key.
+        assert_ne!(mac, hash_directory("tree").unwrap().hash);
+        let wrong_key = [8; blake3::KEY_LEN];
+        assert!(!verify_directory_mac("tree", &wrong_key, &mac).unwrap());
+
+        scratch.write("tree/sub/b.txt", "B");
+        assert_ne!(hash_directory_mac("tree", &key).unwrap(), mac);
+        assert!(!verify_directory_mac("tree", &key, &mac).unwrap());
+    }
+
+    #[test]
+    fn empty_directories_lists_folders_without_visible_files() {
+        let scratch = Scratch::new();
+        scratch.write("tree/a.txt", "a");
+        scratch.write("tree/full/deep/b.txt", "b");
+        scratch.write("tree/hidden_only/.keep", "");
+        std::fs::create_dir_all("tree/empty/nested/deeper").unwrap();
+        std::fs::create_dir_all("tree/full/also_empty").unwrap();
+        assert_eq!(
+            empty_directories("tree").unwrap(),
+            [
+                "empty",
+                "empty/nested",
+                "empty/nested/deeper",
+                "full/also_empty",
+                "hidden_only",
+            ]
+        );
+        std::fs::create_dir("bare").unwrap();
+        assert!(empty_directories("bare").unwrap().is_empty());
+    }
+
+    #[test]
+    fn root_only_hash_matches_hash_directory() {
+        let scratch = Scratch::new();
+        for path in ["a", "b/c", "b/d/e", "f", "g/h", "i"] {
+            scratch.write(&format!("tree/{}", path), path);
+        }
+        let expected = hash_directory("tree").unwrap().hash;
+        assert_eq!(hash_directory_root_only("tree").unwrap(), expected);
+        // However the files are split into chunks, including uneven ones.
+        for chunk_len in [1, 2, 4, 6, 100] {
+            assert_eq!(hash_root_in_chunks("tree", chunk_len).unwrap(), expected);
+        }
+        std::fs::create_dir("empty").unwrap();
+        assert_eq!(
+            hash_directory_root_only("empty").unwrap(),
+            hash_directory("empty").unwrap().hash
+        );
+    }
+
+    #[test]
+    fn metadata_round_trips_through_the_header() {
+        let scratch = Scratch::new();
+        scratch.write("tree/a.txt", "a");
+        let metadata = HashMap::from([
+            ("host".to_string(), "nas".to_string()),
+            (
+                "comment".to_string(),
+                "nightly run, with spaces".to_string(),
+            ),
+            ("empty".to_string(), String::new()),
+        ]);
+        let options = HashOptions {
+            metadata: metadata.clone(),
+            shards: NonZeroUsize::new(2),
+            ..Default::default()
+        };
+        create_hashfile_with_options("tree", &options).unwrap();
+        assert_eq!(
+            read_hashfile_metadata(HASH_RESULTS_FILENAME).unwrap(),
+            metadata
+        );
+        // It's never validated, so any (or no) metadata validates the same.
+        assert!(
+            validate_hashfile_with_options("tree", &HashOptions::default())
+                .unwrap()
+                .is_valid()
+        );
+
+        for (key, value) in [("two words", "v"), ("", "v"), ("k", "line\nbreak")] {
+            let options = HashOptions {
+                metadata: HashMap::from([(key.to_string(), value.to_string())]),
+                ..Default::default()
+            };
+            let err = create_hashfile_with_options("tree", &options)
+                .err()
+                .unwrap();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn validate_copy_re_roots_the_records_under_the_copy() {
+        let scratch = Scratch::new();
+        for path in ["a.txt", "sub/b.txt", "sub/deep/c.txt"] {
+            scratch.write(&format!("original/{}", path), path);
+            scratch.write(&format!("copy/{}", path), path);
+        }
+        create_hashfile("original").unwrap();
+        // The hashfile can be anywhere, e.g. shipped alongside the copy.
+        std::fs::create_dir("shipped").unwrap();
+        std::fs::rename(HASH_RESULTS_FILENAME, "shipped/original.b3").unwrap();
+        assert!(validate_copy("shipped/original.b3", "copy")
+            .unwrap()
+            .is_valid());
+
+        scratch.write("copy/sub/deep/c.txt", "truncated");
+        std::fs::remove_file("copy/a.txt").unwrap();
+        scratch.write("copy/stray.tmp", "stray");
+        let report = validate_copy("shipped/original.b3", "copy").unwrap();
+        assert_eq!(report.failed_files, ["copy/a.txt", "copy/sub/deep/c.txt"]);
+        assert_eq!(report.added, ["copy/stray.tmp"]);
+        // The original is untouched, and still matches.
+        assert!(validate_copy("shipped/original.b3", "original")
+            .unwrap()
+            .is_valid());
+    }
+
+    #[test]
+    fn serialize_hashed_files_to_streams_the_same_records() {
+        /// Accepts `left` more bytes, then fails every write.
+        struct FailingWriter {
+            left: usize,
+        }
+        impl Write for FailingWriter {
+            fn write(&mut self, buf: &[u8]) -> IOResult<usize> {
+                if self.left == 0 {
+                    return Err(Error::new(ErrorKind::StorageFull, "full"));
+                }
+                let len = buf.len().min(self.left);
+                self.left -= len;
+                Ok(len)
+            }
+            fn flush(&mut self) -> IOResult<()> {
+                Ok(())
+            }
+        }
+
+        let scratch = Scratch::new();
+        scratch.write("tree/a.txt", "a");
+        scratch.write("tree/with space/b.txt", "b");
+        let files = hash_directory("tree").unwrap().files;
+        let mut streamed = Vec::new();
+        serialize_hashed_files_to(&files, &mut streamed).unwrap();
+        assert_eq!(streamed, serialize_hashed_files(&files));
+        create_hashfile("tree").unwrap();
+        assert_eq!(streamed, scratch.hashfile().into_bytes());
+
+        let err = serialize_hashed_files_to(&files, FailingWriter { left: 70 })
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::StorageFull);
+        let mut empty = Vec::new();
+        serialize_hashed_files_to(&[], &mut empty).unwrap();
+        assert!(empty.is_empty());
+    }
+
+    /// Checks `listing` the way `b3sum --check` does, run from inside
+    /// `dir_path`, returning every path it checked.
+    fn b3sum_check(dir_path: &str, listing: &str) -> Vec<String> {
+        let mut checked = Vec::new();
+        for line in listing.lines() {
+            let (escaped, line) = match line.strip_prefix('\\') {
+                Some(line) => (true, line),
+                None => (false, line),
+            };
+            let (hex, escaped_path) = line.split_once("  ").unwrap();
+            let mut path = String::new();
+            let mut chars = escaped_path.chars();
+            while let Some(c) = chars.next() {
+                path.push(match c {
+                    '\\' if escaped => match chars.next() {
+                        Some('n') => '\n',
+                        Some('\\') => '\\',
+                        other => panic!("invalid escape {:?}", other),
+                    },
+                    c => c,
+                });
+            }
+            let contents = std::fs::read(Utf8Path::new(dir_path).join(&path)).unwrap();
+            assert_eq!(Hash::from_hex(hex).unwrap(), blake3::hash(&contents));
+            checked.push(path);
+        }
+        checked
+    }
+
+    #[test]
+    fn serialize_coreutils_passes_a_b3sum_check() {
+        let scratch = Scratch::new();
+        scratch.write("tree/a.txt", "a");
+        scratch.write("tree/with space/b.txt", "b");
+        scratch.write("tree/back\\slash.txt", "c");
+        scratch.write("tree/new\nline.txt", "d");
+        scratch.write("tree/big.bin", "larger than the limit");
+        let hashed = hash_directory("tree").unwrap();
+        let listing = String::from_utf8(serialize_coreutils(&hashed, false)).unwrap();
+        let checked = b3sum_check("tree", &listing);
+        let expected: Vec<_> = hashed.iter().map(|file| file.path.clone()).collect();
+        assert_eq!(checked, expected);
+        assert!(listing.lines().any(|line| line.starts_with('\\')));
+
+        let with_total = String::from_utf8(serialize_coreutils(&hashed, true)).unwrap();
+        let (files, total) = with_total.trim_end().rsplit_once('\n').unwrap();
+        assert_eq!(format!("{}\n", files), listing);
+        assert_eq!(total, format!("{}  tree", hashed.hash.to_hex()));
+
+        let options = HashOptions {
+            max_content_size: Some(4),
+            ..Default::default()
+        };
+        let hashed = hash_directory_with_options("tree", &options).unwrap();
+        let listing = String::from_utf8(serialize_coreutils(&hashed, false)).unwrap();
+        let checked = b3sum_check("tree", &listing);
+        assert_eq!(checked.len(), hashed.len() - 1);
+        assert!(!checked.contains(&"big.bin".to_owned()));
+    }
+
+    #[test]
+    fn compare_directories_sorts_files_into_each_side() {
+        let scratch = Scratch::new();
+        for dir in ["a", "b"] {
+            scratch.write(&format!("{}/same.txt", dir), "same");
+            scratch.write(&format!("{}/sub/nested.txt", dir), "nested");
+        }
+        scratch.write("a/sub/edited.txt", "before");
+        scratch.write("b/sub/edited.txt", "after");
+        scratch.write("a/gone.txt", "gone");
+        scratch.write("b/moved/gone.txt", "gone");
+        scratch.write("b/new.txt", "new");
+
+        let diff = compare_directories("a", "b").unwrap();
+        assert!(!diff.is_identical());
+        assert_eq!(diff.only_in_a, ["gone.txt"]);
+        assert_eq!(diff.only_in_b, ["moved/gone.txt", "new.txt"]);
+        assert_eq!(diff.differing, ["sub/edited.txt"]);
+
+        let reversed = compare_directories("b", "a").unwrap();
+        assert_eq!(reversed.only_in_a, diff.only_in_b);
+        assert_eq!(reversed.only_in_b, diff.only_in_a);
+        assert_eq!(reversed.differing, diff.differing);
+
+        assert!(compare_directories("a", "a").unwrap().is_identical());
+        assert_eq!(
+            compare_directories("a", "missing").err().unwrap().kind(),
+            ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn verify_aggregate_notices_any_change() {
+        let scratch = Scratch::new();
+        scratch.write("tree/a.txt", "a");
+        scratch.write("tree/sub/b.txt", "b");
+        let options = HashOptions {
+            record_aggregate: true,
+            ..Default::default()
+        };
+        create_hashfile("tree").unwrap();
+        let err = verify_aggregate("tree").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+        create_hashfile_with_options("tree", &options).unwrap();
+        assert!(verify_aggregate("tree").unwrap());
+        assert!(verify_aggregate_with_options("tree", &options).unwrap());
+
+        scratch.write("tree/sub/b.txt", "B");
+        assert!(!verify_aggregate("tree").unwrap());
+        scratch.write("tree/sub/b.txt", "b");
+        assert!(verify_aggregate("tree").unwrap());
+        scratch.write("tree/sub/new.txt", "new");
+        assert!(!verify_aggregate("tree").unwrap());
+        std::fs::remove_file("tree/sub/new.txt").unwrap();
+        std::fs::rename("tree/a.txt", "tree/sub/a.txt").unwrap();
+        assert!(!verify_aggregate("tree").unwrap());
+    }
+
+    #[test]
+    fn hash_file_list_matches_hash_directory() {
+        let scratch = Scratch::new();
+        scratch.write("tree/a.txt", "a");
+        scratch.write("tree/sub/b.txt", "b");
+        scratch.write("tree/sub/deeper/c.txt", "c");
+        scratch.write("tree/.hidden", "hidden");
+        let walked = hash_directory("tree").unwrap();
+
+        // Out of order, with a duplicate, like a hand-written manifest.
+        let listed =
+            hash_file_list("tree", &["sub/deeper/c.txt", "a.txt", "sub/b.txt", "a.txt"]).unwrap();
+        assert!(listed.files == walked.files);
+        assert_eq!(listed.hash, walked.hash);
+        assert_eq!(
+            (listed.size, listed.dir_name),
+            (walked.size, walked.dir_name)
+        );
+
+        let subset = hash_file_list("tree", &["sub/b.txt", ".hidden"]).unwrap();
+        let paths: Vec<_> = subset.iter().map(|file| file.path.as_str()).collect();
+        assert_eq!(paths, [".hidden", "sub/b.txt"]);
+        assert_eq!(subset.hash, aggregate_hash(&subset.files));
+
+        let err = hash_file_list("tree", &["a.txt", "missing.txt"])
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn root_prefix_round_trips_through_validation() {
+        let scratch = Scratch::new();
+        scratch.write("tree/a.txt", "a");
+        scratch.write("tree/sub/b.txt", "b");
+        let prefixed = HashOptions {
+            root_prefix: Some("/backup/2024/".to_string()),
+            record_aggregate: true,
+            ..Default::default()
+        };
+        create_hashfile_with_options("tree", &prefixed).unwrap();
+        let hashfile = scratch.hashfile();
+        assert!(hashfile.contains(" backup/2024/a.txt\n"));
+        assert!(hashfile.contains(" backup/2024/sub/b.txt\n"));
+        assert!(verify_aggregate("tree").unwrap());
+        assert!(validate_hashfile("tree").unwrap().is_none());
+
+        scratch.write("tree/sub/b.txt", "B");
+        scratch.write("tree/new.txt", "new");
+        let report = validate_hashfile_with_options("tree", &HashOptions::default()).unwrap();
+        assert_eq!(report.failed_files, ["tree/sub/b.txt"]);
+        assert_eq!(report.added, ["tree/new.txt"]);
+        let report = validate_paths("tree", &["sub/b.txt"]).unwrap();
+        assert_eq!(report.failed_files, ["tree/sub/b.txt"]);
+
+        // The prefix is never hashed, so the aggregate is the unprefixed one.
+        std::fs::remove_file("tree/new.txt").unwrap();
+        let aggregate = |hashfile: String| {
+            let line = hashfile.lines().find(|line| line.starts_with("#aggregate"));
+            line.unwrap().to_string()
+        };
+        scratch.write("tree/sub/b.txt", "b");
+        let recorded = aggregate(scratch.hashfile());
+        create_hashfile_with_options(
+            "tree",
+            &HashOptions {
+                root_prefix: None,
+                ..prefixed
+            },
+        )
+        .unwrap();
+        assert_eq!(aggregate(scratch.hashfile()), recorded);
+
+        let err = create_hashfile_with_options(
+            "tree",
+            &HashOptions {
+                root_prefix: Some("two\nlines".to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn hash_files_unsorted_sorts_into_hash_directory() {
+        let scratch = Scratch::new();
+        for i in 0..20 {
+            scratch.write(&format!("tree/{}/{}.txt", i % 3, i), i.to_string());
+        }
+        scratch.write("tree/.hidden", "hidden");
+        let walked = hash_directory("tree").unwrap();
+        let mut unsorted = hash_files_unsorted("tree").unwrap();
+        assert_eq!(unsorted.len(), 20);
+        unsorted.sort_unstable();
+        assert!(unsorted == walked.files);
+        assert_eq!(aggregate_hash(&unsorted), walked.hash);
+    }
+
+    #[test]
+    fn excluded_names_never_show_up_anywhere() {
+        let scratch = Scratch::new();
+        scratch.write("tree/kept.txt", "kept");
+        scratch.write("tree/target.txt", "only the exact name is excluded");
+        scratch.write("tree/target/build.log", "log");
+        scratch.write("tree/sub/target/deep/build.log", "log");
+        scratch.write("tree/sub/file.sig", "file named like the excluded one");
+        scratch.write("tree/sub/deep/target", "a file with the excluded name");
+        let options = HashOptions {
+            exclude: HashSet::from(["target".to_string(), "file.sig".to_string()]),
+            ..Default::default()
+        };
+        let is_excluded = |path: &str| path.split('/').any(|name| options.exclude.contains(name));
+        for parallel_traversal in [false, true] {
+            let options = HashOptions {
+                parallel_traversal,
+                exclude: options.exclude.clone(),
+                ..Default::default()
+            };
+            let hashed = hash_directory_with_options("tree", &options).unwrap();
+            let paths: Vec<_> = hashed.iter().map(|file| file.path.as_str()).collect();
+            assert_eq!(paths, ["kept.txt", "target.txt"]);
+        }
+        create_hashfile_with_options("tree", &options).unwrap();
+        let hashfile = scratch.hashfile();
+        assert!(hashfile
+            .lines()
+            .all(|line| !is_excluded(line.rsplit(' ').next().unwrap())));
+
+        scratch.write("tree/new/target/more.log", "log");
+        let report = validate_hashfile_with_options("tree", &options).unwrap();
+        assert!(report.failed_files.is_empty());
+        assert!(report.added.is_empty());
+
+        // Forcing a name in beats excluding it.
+        let forced = HashOptions {
+            force_include: vec!["targ?t".to_string()],
+            exclude: options.exclude.clone(),
+            ..Default::default()
+        };
+        let hashed = hash_directory_with_options("tree", &forced).unwrap();
+        assert!(hashed
+            .iter()
+            .any(|file| file.path == "sub/target/deep/build.log"));
+        assert!(hashed.iter().all(|file| !file.path.ends_with("file.sig")));
+    }
+
+    #[test]
+    fn deterministic_hashfiles_ignore_mtimes_and_metadata() {
+        let scratch = Scratch::new();
+        scratch.write("tree/a.txt", "a");
+        scratch.write("tree/sub/b.txt", "b");
+        scratch.write("tree/sub/deeper/c.txt", "c");
+        let options = |deterministic_hashfile, host: &str| HashOptions {
+            deterministic_hashfile,
+            record_mtime: true,
+            record_size: true,
+            subtree_stamps: true,
+            dir_counts: true,
+            record_aggregate: true,
+            metadata: HashMap::from([("host".to_string(), host.to_string())]),
+            ..Default::default()
+        };
+        let touch_everything = || {
+            for path in ["tree/a.txt", "tree/sub/b.txt", "tree/sub/deeper/c.txt"] {
+                let file = std::fs::File::options().write(true).open(path).unwrap();
+                let modified = file.metadata().unwrap().modified().unwrap();
+                file.set_modified(modified + Duration::from_secs(100))
+                    .unwrap();
+            }
+        };
+
+        create_hashfile_with_options("tree", &options(true, "nas")).unwrap();
+        let created = scratch.hashfile();
+        assert!(!created.contains("nas"));
+        touch_everything();
+        create_hashfile_with_options("tree", &options(true, "laptop")).unwrap();
+        assert_eq!(scratch.hashfile(), created);
+        assert!(validate_hashfile("tree").unwrap().is_none());
+
+        create_hashfile_with_options("tree", &options(false, "nas")).unwrap();
+        let recorded = scratch.hashfile();
+        assert!(recorded.contains("nas"));
+        touch_everything();
+        create_hashfile_with_options("tree", &options(false, "nas")).unwrap();
+        assert_ne!(scratch.hashfile(), recorded);
+
+        // A fresh salt every time defeats it.
+        let salted = HashOptions {
+            salted: true,
+            ..options(true, "nas")
+        };
+        create_hashfile_with_options("tree", &salted).unwrap();
+        let first = scratch.hashfile();
+        create_hashfile_with_options("tree", &salted).unwrap();
+        assert_ne!(scratch.hashfile(), first);
+    }
+
+    #[test]
+    fn salted_hashfiles_validate_with_their_own_salt() {
+        let scratch = Scratch::new();
+        scratch.write("tree/a.txt", "a");
+        scratch.write("tree/sub/b.txt", "b");
+        let salted = HashOptions {
+            salted: true,
+            ..Default::default()
+        };
+        let salt_and_hash = |hashfile: String| {
+            let salt = hashfile
+                .lines()
+                .find_map(|line| line.strip_prefix("#salt "));
+            let record = hashfile.lines().find(|line| line.ends_with(" a.txt"));
+            (salt.unwrap().to_string(), record.unwrap().to_string())
+        };
+        create_hashfile_with_options("tree", &salted).unwrap();
+        let (first_salt, first_record) = salt_and_hash(scratch.hashfile());
+        assert!(validate_hashfile("tree").unwrap().is_none());
+        create_hashfile_with_options("tree", &salted).unwrap();
+        let (second_salt, second_record) = salt_and_hash(scratch.hashfile());
+        assert!(validate_hashfile("tree").unwrap().is_none());
+        assert_ne!(first_salt, second_salt);
+        assert_ne!(first_record, second_record);
+        assert!(!first_record.starts_with(blake3::hash(b"a").to_hex().as_str()));
+
+        scratch.write("tree/a.txt", "A");
+        assert_eq!(validate_hashfile("tree").unwrap().unwrap(), ["tree/a.txt"]);
+
+        let salts: HashSet<_> = (0..64).map(|_| random_salt().unwrap()).collect();
+        assert_eq!(salts.len(), 64);
+    }
+
+    #[test]
+    fn one_throttle_caps_every_subtree_validated_in_parallel() {
+        let scratch = Scratch::new();
+        let contents = "x".repeat(50_000);
+        for subtree in ["a", "b", "c", "d"] {
+            scratch.write(&format!("tree/{}/file.txt", subtree), &contents);
+        }
+        create_hashfile("tree").unwrap();
+        let options = HashOptions {
+            max_bytes_per_sec: std::num::NonZeroU64::new(500_000),
+            ..Default::default()
+        };
+        let max_subtrees = NonZeroUsize::new(4).unwrap();
+        let start = std::time::Instant::now();
+        let report = with_threads(4, || {
+            validate_hashfile_by_subtree("tree", &options, max_subtrees)
+        })
+        .unwrap();
+        assert!(report.failed_files.is_empty());
+        // 200 KB at 500 KB/s, where a throttle per subtree would let
+        // all four go at full speed at once and take only a quarter of that.
+        assert!(start.elapsed() >= Duration::from_millis(350));
+    }
+
+    #[cfg(feature = "unicode")]
+    #[test]
+    fn normalized_names_validate_by_subtree() {
+        let scratch = Scratch::new();
+        scratch.write("tree/cafe\u{301}/menu.txt", "espresso");
+        scratch.write("tree/other/cre\u{300}me.txt", "brulee");
+        scratch.write("tree/plain.txt", "plain");
+        let options = HashOptions {
+            normalize_unicode: true,
+            ..Default::default()
+        };
+        create_hashfile_with_options("tree", &options).unwrap();
+        let max_subtrees = NonZeroUsize::new(1).unwrap();
+        let report = validate_hashfile_by_subtree("tree", &options, max_subtrees).unwrap();
+        assert!(report.failed_files.is_empty());
+        assert!(report.read_errors.is_empty());
+
+        scratch.write("tree/other/cre\u{300}me.txt", "caramel");
+        let report = validate_hashfile_by_subtree("tree", &options, max_subtrees).unwrap();
+        assert_eq!(report.failed_files, ["tree/other/cre\u{300}me.txt"]);
+    }
+
+    #[test]
+    fn timestamps_bracket_hashing_and_never_change_the_hash() {
+        let scratch = Scratch::new();
+        scratch.write("tree/a.txt", "a");
+        scratch.write("tree/sub/b.txt", "b");
+        let untimed = hash_directory("tree").unwrap();
+        assert!(untimed.started_at.is_none() && untimed.finished_at.is_none());
+
+        let options = HashOptions {
+            record_timestamps: true,
+            ..Default::default()
+        };
+        let before = SystemTime::now();
+        let timed = hash_directory_with_options("tree", &options).unwrap();
+        let after = SystemTime::now();
+        let (started_at, finished_at) = (timed.started_at.unwrap(), timed.finished_at.unwrap());
+        assert!(before <= started_at);
+        assert!(started_at <= finished_at);
+        assert!(finished_at <= after);
+        assert_eq!(timed.hash, untimed.hash);
+
+        create_hashfile_with_options("tree", &options).unwrap();
+        let recorded = scratch.hashfile();
+        create_hashfile("tree").unwrap();
+        assert_eq!(scratch.hashfile(), recorded);
+    }
+
+    #[test]
+    fn cross_machine_hash_ignores_where_the_tree_lives() {
+        let scratch = Scratch::new();
+        for root in ["here", "somewhere/else/entirely"] {
+            scratch.write(&format!("{}/a.txt", root), "a");
+            scratch.write(&format!("{}/sub/b.txt", root), "b");
+            scratch.write(&format!("{}/.hidden", root), root);
+        }
+        std::fs::create_dir("here/empty").unwrap();
+        let here = cross_machine_hash("here").unwrap();
+        assert_eq!(cross_machine_hash("somewhere/else/entirely").unwrap(), here);
+        assert_eq!(hash_directory("here").unwrap().hash, here);
+
+        scratch.write("here/sub/b.txt", "B");
+        assert_ne!(cross_machine_hash("here").unwrap(), here);
+    }
+
+    #[cfg(feature = "unicode")]
+    #[test]
+    fn cross_machine_hash_ignores_how_names_are_normalized() {
+        let scratch = Scratch::new();
+        scratch.write("composed/caf\u{e9}/menu.txt", "espresso");
+        scratch.write("decomposed/cafe\u{301}/menu.txt", "espresso");
+        assert_eq!(
+            cross_machine_hash("composed").unwrap(),
+            cross_machine_hash("decomposed").unwrap()
+        );
+    }
+
+    #[test]
+    fn content_only_hash_survives_renames_and_moves() {
+        let scratch = Scratch::new();
+        scratch.write("tree/a.txt", "a");
+        scratch.write("tree/sub/b.txt", "b");
+        scratch.write("tree/sub/c.txt", "c");
+        let before = content_only_hash("tree").unwrap();
+        let path_hash = hash_directory("tree").unwrap().hash;
+
+        std::fs::rename("tree/a.txt", "tree/sub/renamed.txt").unwrap();
+        std::fs::create_dir_all("tree/x/y").unwrap();
+        std::fs::rename("tree/sub/b.txt", "tree/x/y/b.txt").unwrap();
+        assert_eq!(content_only_hash("tree").unwrap(), before);
+        assert_ne!(hash_directory("tree").unwrap().hash, path_hash);
+
+        // Copies count, and so do edits.
+        scratch.write("tree/copy.txt", "c");
+        assert_ne!(content_only_hash("tree").unwrap(), before);
+        std::fs::remove_file("tree/copy.txt").unwrap();
+        scratch.write("tree/sub/c.txt", "C");
+        assert_ne!(content_only_hash("tree").unwrap(), before);
+    }
+
+    #[test]
+    fn aggregate_hash_matches_hash_directory() {
+        let scratch = Scratch::new();
+        std::fs::create_dir("empty").unwrap();
+        let empty = hash_directory("empty").unwrap();
+        assert_eq!(aggregate_hash(&empty.files), empty.hash);
+
+        for i in 0..30 {
+            scratch.write(&format!("tree/{}/{}.txt", i % 4, i), i.to_string());
+        }
+        scratch.write("tree/a b/c-d.txt", "odd names");
+        let hashed = hash_directory("tree").unwrap();
+        assert_eq!(aggregate_hash(&hashed.files), hashed.hash);
+        let mut shuffled = hashed.files.clone();
+        shuffled.reverse();
+        shuffled.swap(3, 17);
+        assert_eq!(aggregate_hash(&shuffled), hashed.hash);
+
+        for (aggregate_sizes, aggregate_via_buffer) in [(true, false), (false, true), (true, true)]
+        {
+            let options = HashOptions {
+                aggregate_sizes,
+                aggregate_via_buffer,
+                mode: HashMode::DeriveKey("aggregate test".to_string()),
+                ..Default::default()
+            };
+            let hashed = hash_directory_with_options("tree", &options).unwrap();
+            assert_eq!(
+                aggregate_hash_with_options(&hashed.files, &options),
+                hashed.hash
+            );
+            let mut reversed = hashed.files.clone();
+            reversed.reverse();
+            assert_eq!(
+                aggregate_hash_with_options(&reversed, &options),
+                hashed.hash
+            );
+        }
+    }
+
+    #[test]
+    fn hash_file_tee_feeds_every_sink_the_whole_file() {
+        /// Keeps every byte written to it, and counts the flushes.
+        #[derive(Default)]
+        struct Sink {
+            written: Vec<u8>,
+            flushes: usize,
+        }
+        impl Write for Sink {
+            fn write(&mut self, buf: &[u8]) -> IOResult<usize> {
+                // Takes a little at a time, so writes have to be looped.
+                let len = buf.len().min(1000);
+                self.written.extend_from_slice(&buf[..len]);
+                Ok(len)
+            }
+            fn flush(&mut self) -> IOResult<()> {
+                self.flushes += 1;
+                Ok(())
+            }
+        }
+
+        let scratch = Scratch::new();
+        let contents: Vec<u8> = (0..100_000u32).map(|i| (i % 253) as u8).collect();
+        scratch.write("big.bin", &contents);
+        let (mut first, mut second) = (Sink::default(), Sink::default());
+        let mut other_hasher = blake3::Hasher::new_derive_key("another digest");
+        let options = HashOptions {
+            read_buffer_size: NonZeroUsize::new(4096),
+            ..Default::default()
+        };
+        let hash = {
+            let mut sinks: [&mut dyn Write; 3] = [&mut first, &mut second, &mut other_hasher];
+            hash_file_tee("big.bin", &mut sinks, &options).unwrap()
+        };
+        assert_eq!(hash, blake3::hash(&contents));
+        assert_eq!(first.written, contents);
+        assert_eq!(second.written, contents);
+        assert_eq!((first.flushes, second.flushes), (1, 1));
+        let mut expected = blake3::Hasher::new_derive_key("another digest");
+        expected.update(&contents);
+        assert_eq!(other_hasher.finalize(), expected.finalize());
+
+        // The blake3 hash is the one hash_directory records.
+        scratch.write("tree/big.bin", &contents);
+        let hashed = hash_directory("tree").unwrap();
+        assert_eq!(
+            hash_file_tee("tree/big.bin", &mut [], &options).unwrap(),
+            hashed[0].hash
+        );
+        assert_eq!(
+            hash_file_tee("missing.bin", &mut [], &options)
+                .unwrap_err()
+                .kind(),
+            ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn breadth_first_hashes_shallow_files_first() {
+        use std::sync::{Arc, Mutex};
+
+        let scratch = Scratch::new();
+        for path in [
+            "a/b/c/deep.txt",
+            "a/b/mid.txt",
+            "a/top.txt",
+            "root.txt",
+            "z/b.txt",
+        ] {
+            // Each file holds its own path, so the transform can tell them apart.
+            scratch.write(&format!("tree/{}", path), path);
+        }
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&order);
+        let options = HashOptions {
+            breadth_first: true,
+            content_transform: Some(Box::new(move |contents: &[u8]| {
+                let path = String::from_utf8(contents.to_vec()).unwrap();
+                seen.lock().unwrap().push(path);
+                contents.to_vec()
+            })),
+            ..Default::default()
+        };
+        let hashed = with_threads(1, || hash_directory_with_options("tree", &options)).unwrap();
+        assert_eq!(
+            *order.lock().unwrap(),
+            [
+                "root.txt",
+                "a/top.txt",
+                "z/b.txt",
+                "a/b/mid.txt",
+                "a/b/c/deep.txt"
+            ]
+        );
+        let depth_first = hash_directory("tree").unwrap();
+        assert!(hashed.files == depth_first.files);
+        assert_eq!(hashed.hash, depth_first.hash);
+    }
+
+    #[test]
+    fn sync_plan_tells_missing_files_apart_from_changed_ones_anywhere() {
+        let scratch = Scratch::new();
+        scratch.write("tree/changed.txt", "before");
+        scratch.write("tree/gone.txt", "gone");
+        create_hashfile("tree").unwrap();
+        scratch.write("tree/changed.txt", "after!");
+        std::fs::remove_file(scratch.path().join("tree/gone.txt")).unwrap();
+        let report = validate_hashfile_with_options("tree", &HashOptions::default()).unwrap();
+        assert_eq!(report.missing, ["tree/gone.txt"]);
+        // The report is all sync_plan goes on, so it doesn't matter where it's made from.
+        std::env::set_current_dir(scratch.path().join("tree")).unwrap();
+        let plan = report.sync_plan();
+        assert_eq!(plan.to_copy, ["tree/changed.txt"]);
+        assert_eq!(plan.to_add, ["tree/gone.txt"]);
+        assert!(plan.to_delete.is_empty());
+    }
+
+    #[test]
+    fn count_hidden_counts_hidden_folders_once_and_the_hashfile() {
+        let scratch = Scratch::new();
+        scratch.write("tree/visible.txt", "seen");
+        scratch.write("tree/.hidden", "unseen");
+        scratch.write("tree/.git/a", "unseen");
+        scratch.write("tree/.git/b/c", "unseen");
+        scratch.write("tree/sub/.also_hidden", "unseen");
+        scratch.write("tree/sub/visible.txt", "seen");
+        assert_eq!(count_hidden("tree").unwrap(), 3);
+        assert_eq!(count_hidden(".").unwrap(), 3);
+        create_hashfile(".").unwrap();
+        assert_eq!(count_hidden(".").unwrap(), 4);
+        assert_eq!(hash_directory("tree").unwrap().files.len(), 2);
+    }
+
+    #[test]
+    fn chain_hash_links_every_snapshot_into_the_latest() {
+        let roots = [b"first", b"secnd", b"third"].map(|root| blake3::hash(root));
+        let chain = |roots: &[Hash]| {
+            roots
+                .iter()
+                .fold(Hash::from([0; 32]), |link, root| chain_hash(&link, root))
+        };
+        let latest = chain(&roots);
+        let mut tampered = roots;
+        tampered[0] = blake3::hash(b"fir5t");
+        assert_ne!(chain(&tampered), latest);
+        assert_ne!(
+            chain_hash(&roots[0], &roots[1]),
+            chain_hash(&roots[1], &roots[0])
+        );
+        // Domain separated, so never just the hash of both halves.
+        let mut halves = [0; 64];
+        halves[..32].copy_from_slice(roots[0].as_bytes());
+        halves[32..].copy_from_slice(roots[1].as_bytes());
+        assert_ne!(chain_hash(&roots[0], &roots[1]), blake3::hash(&halves));
+        assert_eq!(chain(&roots), latest);
+    }
+
+    #[test]
+    fn inventory_directory_lists_what_would_be_hashed_without_reading_it() {
+        use crate::util::CONTENTS_HASHED;
+        use std::sync::atomic::Ordering;
+
+        let scratch = Scratch::new();
+        scratch.write("tree/b.txt", "bb");
+        scratch.write("tree/a/c.txt", "ccc");
+        scratch.write("tree/empty.txt", "");
+        scratch.write("tree/.hidden", "never listed");
+        let before = CONTENTS_HASHED.load(Ordering::Relaxed);
+        let inventory = inventory_directory("tree").unwrap();
+        assert_eq!(CONTENTS_HASHED.load(Ordering::Relaxed) - before, 0);
+        let hashed: Vec<(String, u64)> = hash_directory("tree")
+            .unwrap()
+            .files
+            .into_iter()
+            .map(|file| (file.path, file.size))
+            .collect();
+        assert_eq!(inventory, hashed);
+        assert_eq!(inventory[0], ("a/c.txt".to_string(), 3));
+    }
+
+    #[test]
+    fn dir_name_mismatch_only_flags_renamed_directories() {
+        let scratch = Scratch::new();
+        scratch.write("tree/a.txt", "a");
+        let options = HashOptions {
+            record_dir_name: true,
+            ..Default::default()
+        };
+        create_hashfile_with_options("tree", &options).unwrap();
+        let report = validate_hashfile_with_options("tree", &options).unwrap();
+        assert_eq!(report.dir_name_mismatch, None);
+        // Resolved first, so `.` still has the directory's own name.
+        let hashfile = scratch.path().join(HASH_RESULTS_FILENAME);
+        std::fs::copy(
+            &hashfile,
+            scratch.path().join("tree").join(HASH_RESULTS_FILENAME),
+        )
+        .unwrap();
+        std::env::set_current_dir(scratch.path().join("tree")).unwrap();
+        let report = validate_hashfile_with_options(".", &options).unwrap();
+        assert_eq!(report.dir_name_mismatch, None);
+        std::env::set_current_dir(scratch.path()).unwrap();
+
+        std::fs::rename(scratch.path().join("tree"), scratch.path().join("copy")).unwrap();
+        let report = validate_hashfile_with_options("copy", &options).unwrap();
+        assert_eq!(report.dir_name_mismatch.as_deref(), Some("tree"));
+        assert!(report.is_valid());
+        // Nothing to compare against without the option.
+        create_hashfile("copy").unwrap();
+        std::fs::rename(scratch.path().join("copy"), scratch.path().join("tree")).unwrap();
+        assert_eq!(
+            validate_hashfile_with_options("tree", &HashOptions::default())
+                .unwrap()
+                .dir_name_mismatch,
+            None
+        );
+    }
+
+    #[test]
+    fn power_save_threads_stays_between_one_and_every_core() {
+        let cores = std::thread::available_parallelism().unwrap().get();
+        assert_eq!(power_save_threads(1.0), cores);
+        assert_eq!(power_save_threads(0.5), (cores / 2).max(1));
+        for fraction in [0.0, -1.0, 2.0, f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            let threads = power_save_threads(fraction);
+            assert!(
+                (1..=cores).contains(&threads),
+                "{} gave {}",
+                fraction,
+                threads
+            );
+        }
+        assert_eq!(power_save_threads(2.0), cores);
+        assert_eq!(power_save_threads(0.0), 1);
+    }
+
+    #[test]
+    fn same_size_changes_are_flagged_as_silent_corruption() {
+        let scratch = Scratch::new();
+        scratch.write("tree/rotted.txt", "abcd");
+        scratch.write("tree/edited.txt", "abcd");
+        scratch.write("tree/gone.txt", "abcd");
+        scratch.write("tree/kept.txt", "abcd");
+        let options = HashOptions {
+            record_size: true,
+            ..Default::default()
+        };
+        let damage = || {
+            scratch.write("tree/rotted.txt", "abce");
+            scratch.write("tree/edited.txt", "abcde");
+            std::fs::remove_file("tree/gone.txt").unwrap();
+        };
+        let restore = || {
+            for path in ["rotted.txt", "edited.txt", "gone.txt"] {
+                scratch.write(&format!("tree/{}", path), "abcd");
+            }
+        };
+
+        create_hashfile_with_options("tree", &options).unwrap();
+        damage();
+        let report = validate_hashfile_with_options("tree", &options).unwrap();
+        assert_eq!(
+            report.failed_files,
+            ["tree/edited.txt", "tree/gone.txt", "tree/rotted.txt"]
+        );
+        assert_eq!(report.silent_corruption, ["tree/rotted.txt"]);
+
+        // Without recorded sizes there's nothing to tell them apart by.
+        restore();
+        create_hashfile("tree").unwrap();
+        damage();
+        let report = validate_hashfile_with_options("tree", &options).unwrap();
+        assert_eq!(report.failed_files.len(), 3);
+        assert!(report.silent_corruption.is_empty());
+    }
+
+    #[test]
+    fn create_hashfile_with_report_writes_both_files() {
+        let scratch = Scratch::new();
+        scratch.write("tree/a.txt", "a");
+        scratch.write("tree/sub/b.txt", "b");
+        create_hashfile_with_report("tree").unwrap();
+        let hashed_dir = hash_directory("tree").unwrap();
+        let report = std::fs::read_to_string(REPORT_FILENAME).unwrap();
+        assert!(report.contains(&hashed_dir.hash.to_hex().to_string()));
+        assert!(report.contains("File count: 2"));
+        assert_eq!(report, hashed_dir.to_string());
+        // The hashfile is just the same as without the report, and still validates.
+        let hashfile = scratch.hashfile();
+        create_hashfile("tree").unwrap();
+        assert_eq!(hashfile, scratch.hashfile());
+        assert!(validate_hashfile("tree").unwrap().is_none());
+    }
+
+    #[test]
+    fn hash_files_channel_yields_every_file_exactly_once() {
+        let scratch = Scratch::new();
+        for i in 0..200 {
+            scratch.write(&format!("tree/{}/{}.txt", i % 7, i), i.to_string());
+        }
+        let mut received: Vec<HashedFile> = hash_files_channel("tree")
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        received.sort_unstable();
+        let expected = hash_directory("tree").unwrap().files;
+        assert_eq!(received.len(), expected.len());
+        assert!(received
+            .iter()
+            .zip(&expected)
+            .all(|(a, b)| a.path == b.path && a.hash == b.hash));
+    }
+}
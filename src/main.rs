@@ -1,43 +1,252 @@
+use clap::{Parser, Subcommand, ValueEnum};
+use std::process::ExitCode;
+
 const MEBIBYTE: f64 = (1 << 20) as f64;
 
-#[allow(unreachable_code)]
-fn main() -> std::io::Result<()> {
-    let path = std::env::args()
-        .nth(1)
-        .expect("please enter directory path");
-
-    let (res, t) = time(|| b3hash::create_hashfile(&path));
-    let _ = res?;
-    println!("Execution time: {:.2}", t);
-    return Ok(());
-
-    let (res, t) = time(|| b3hash::validate_hashfile(&path));
-    let res = res?;
-    if res.is_none() {
-        println!("all files validated");
-        println!("time: {:.2}", t);
-    } else {
-        println!("validation failed:");
-        println!("files failes: {}", res.unwrap().len());
-    }
-    println!();
-    return Ok(());
-
-    let (res, t) = time(|| b3hash::hash_directory(&path));
-    let res = res?;
-    println!("Execution time: {:.2} seconds", t);
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+    /// Only print the directory checksum (or, when validating,
+    /// the files that failed).
+    #[arg(long, short, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Also print every file's hash.
+    #[arg(long, short, global = true)]
+    verbose: bool,
+}
+
+impl Cli {
+    #[inline]
+    fn verbosity(&self) -> Verbosity {
+        match (self.quiet, self.verbose) {
+            (true, _) => Verbosity::Quiet,
+            (_, true) => Verbosity::Verbose,
+            _ => Verbosity::Normal,
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Hash a directory and print the results.
+    Hash {
+        /// Use `-` to hash every directory listed on stdin, one per line.
+        dir_path: String,
+        #[arg(long, value_enum, default_value_t = Format::Plain)]
+        format: Format,
+        /// Leave the per-file list out of the output: JSON keeps only the
+        /// directory name, checksum, file count and size, and plain output
+        /// skips the file list `--verbose` would print.
+        #[arg(long)]
+        summary: bool,
+        /// Like `--summary`, but only for directories with more
+        /// than this many files.
+        #[arg(long, value_name = "FILES", conflicts_with = "summary")]
+        summary_above: Option<usize>,
+    },
+    /// Create a hashfile for a directory.
+    Create { dir_path: String },
+    /// Validate a directory against its hashfile.
+    Validate {
+        dir_path: String,
+        #[arg(long, value_enum, default_value_t = Format::Plain)]
+        format: Format,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+    /// Human-readable lines.
+    Plain,
+    /// A single JSON document, for scripts and pipelines.
+    Json,
+}
+
+/// How much `Format::Plain` output prints.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
+}
+
+fn main() -> std::io::Result<ExitCode> {
+    let cli = Cli::parse();
+    let verbosity = cli.verbosity();
+    match cli.command {
+        Command::Hash {
+            dir_path,
+            format,
+            summary,
+            summary_above,
+        } if dir_path == "-" => {
+            let summary_above = summary.then_some(0).or(summary_above);
+            let results = b3hash::hash_directories_from_reader(std::io::stdin().lock());
+            let mut hashed_dirs = Vec::with_capacity(results.len());
+            let mut any_failed = false;
+            for res in results {
+                match res {
+                    Ok(res) => hashed_dirs.push(res),
+                    Err(e) => {
+                        eprintln!("Error: {}", e);
+                        any_failed = true;
+                    }
+                }
+            }
+            match format {
+                Format::Json => {
+                    let exports = hashed_dirs
+                        .iter()
+                        .map(|res| to_export(res, summary_above))
+                        .collect::<std::io::Result<Vec<_>>>()?;
+                    println!("{}", to_json(&exports)?);
+                }
+                Format::Plain => {
+                    for res in &hashed_dirs {
+                        let verbosity = plain_verbosity(res, summary_above, verbosity);
+                        print_hashed_directory(res, None, verbosity);
+                        if verbosity > Verbosity::Quiet {
+                            println!();
+                        }
+                    }
+                }
+            }
+            if any_failed {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        Command::Hash {
+            dir_path,
+            format,
+            summary,
+            summary_above,
+        } => {
+            let summary_above = summary.then_some(0).or(summary_above);
+            let (res, t) = time(|| b3hash::hash_directory(&dir_path));
+            let res = res?;
+            match format {
+                Format::Json => println!("{}", to_json(&to_export(&res, summary_above)?)?),
+                Format::Plain => {
+                    let verbosity = plain_verbosity(&res, summary_above, verbosity);
+                    print_hashed_directory(&res, Some(t), verbosity)
+                }
+            }
+        }
+        Command::Create { dir_path } => {
+            let (res, t) = time(|| b3hash::create_hashfile(&dir_path));
+            res?;
+            if verbosity > Verbosity::Quiet {
+                println!("Execution time: {:.2} seconds", t);
+            }
+        }
+        Command::Validate { dir_path, format } => {
+            let (res, t) = time(|| {
+                b3hash::validate_hashfile_with_options(&dir_path, &b3hash::HashOptions::default())
+            });
+            let report = res?;
+            if let Some(recorded) = &report.dir_name_mismatch {
+                eprintln!(
+                    "Warning: hashfile was created for a directory named '{}'",
+                    recorded
+                );
+            }
+            let failed_files = &report.failed_files;
+            match format {
+                Format::Json => println!("{}", to_json(&report)?),
+                // Quiet output is just the failed files, one per line,
+                // so nothing at all gets printed when everything matched.
+                Format::Plain if verbosity == Verbosity::Quiet => {
+                    for file in failed_files {
+                        println!("{}", file);
+                    }
+                }
+                Format::Plain if failed_files.is_empty() => {
+                    println!("All files validated");
+                    println!("Execution time: {:.2} seconds", t);
+                }
+                Format::Plain => {
+                    println!("Validation failed:");
+                    for file in failed_files {
+                        println!("{}", file);
+                    }
+                    println!("Files failed: {}", failed_files.len());
+                }
+            }
+            if !failed_files.is_empty() {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Prints the human-readable summary of `res`,
+/// including execution stats when `t` is known.
+///
+/// Quiet output is only the hex checksum on its own line, and the
+/// `Directory checksum: ` line is always printed the same way, so
+/// scripts can rely on either format.
+fn print_hashed_directory(res: &b3hash::HashedDirectory, t: Option<f64>, verbosity: Verbosity) {
+    if verbosity == Verbosity::Quiet {
+        println!("{}", res.hash.to_hex());
+        return;
+    }
+    if let Some(t) = t {
+        println!("Execution time: {:.2} seconds", t);
+    }
     println!("Directory name: {}", res.dir_name);
     println!("Directory checksum: {}", res.hash.to_hex());
     println!("File count: {}", res.len());
+    if !res.skipped.is_empty() {
+        println!("Special files skipped: {}", res.skipped.len());
+    }
     println!("Final size in bytes: {}", res.size);
     println!("Final size in megabytes: {:.2}", res.size as f64 / 1e6);
     println!("Final size in gigabytes: {:.2}", res.size as f64 / 1e9);
-    println!(
-        "Execution speed: {:.2} MiB/s",
-        res.size as f64 / t / MEBIBYTE
-    );
-    println!();
-    Ok(())
+    if let Some(t) = t {
+        println!(
+            "Execution speed: {:.2} MiB/s",
+            res.size as f64 / t / MEBIBYTE
+        );
+    }
+    if verbosity == Verbosity::Verbose {
+        println!("Files:");
+        for file in res.iter() {
+            println!("{} {}", file.hash.to_hex(), file.path);
+        }
+    }
+}
+
+/// `verbosity` for printing `res` in plain format, which never
+/// lists its files when it has more than `summary_above` of them.
+fn plain_verbosity(
+    res: &b3hash::HashedDirectory,
+    summary_above: Option<usize>,
+    verbosity: Verbosity,
+) -> Verbosity {
+    match summary_above {
+        Some(max_files) if res.len() > max_files => verbosity.min(Verbosity::Normal),
+        _ => verbosity,
+    }
+}
+
+/// JSON export of `res`, which is just its summary when it has
+/// more than `summary_above` files.
+fn to_export(
+    res: &b3hash::HashedDirectory,
+    summary_above: Option<usize>,
+) -> std::io::Result<serde_json::Value> {
+    let value = match summary_above {
+        Some(max_files) if res.len() > max_files => serde_json::to_value(res.summary()),
+        _ => serde_json::to_value(res),
+    };
+    value.map_err(std::io::Error::other)
+}
+
+fn to_json<T: serde::Serialize>(value: &T) -> std::io::Result<String> {
+    serde_json::to_string_pretty(value).map_err(std::io::Error::other)
 }
 
 #[inline(always)]
@@ -52,3 +261,33 @@ where
         .as_secs_f64();
     (res, delta)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary_drops_the_plain_file_list_above_the_threshold() {
+        let dir = tempfile::TempDir::new().unwrap();
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            std::fs::write(dir.path().join(name), name).unwrap();
+        }
+        let res = b3hash::hash_directory(dir.path().to_str().unwrap()).unwrap();
+        assert_eq!(
+            plain_verbosity(&res, Some(2), Verbosity::Verbose),
+            Verbosity::Normal
+        );
+        assert_eq!(
+            plain_verbosity(&res, Some(3), Verbosity::Verbose),
+            Verbosity::Verbose
+        );
+        assert_eq!(
+            plain_verbosity(&res, Some(0), Verbosity::Quiet),
+            Verbosity::Quiet
+        );
+        assert_eq!(
+            plain_verbosity(&res, None, Verbosity::Verbose),
+            Verbosity::Verbose
+        );
+    }
+}
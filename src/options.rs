@@ -0,0 +1,506 @@
+use crate::control::HashControl;
+use crate::progress::ProgressEvent;
+use blake3::Hasher;
+use std::collections::{HashMap, HashSet};
+use std::num::{NonZeroU64, NonZeroUsize};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Optional behavior for hashing directories and creating/validating
+/// hashfiles.
+///
+/// `HashOptions::default()` always matches the behavior of the plain
+/// functions (`hash_directory`, `create_hashfile`, etc.), so callers only
+/// need to set the fields they care about and fill in the rest with
+/// `..Default::default()`.
+#[derive(Default)]
+pub struct HashOptions {
+    /// Record the number of files directly inside each directory in the
+    /// hashfile, and have validation report any directory whose live
+    /// file count differs from the recorded one.
+    ///
+    /// This catches structural changes (e.g. a file being deleted and a
+    /// differently-named one added) at the directory level, on top of
+    /// the regular per-file validation.
+    pub dir_counts: bool,
+
+    /// Cap how many bytes per second get hashed (across all threads),
+    /// for hashing in the background without saturating disk IO.
+    ///
+    /// Files are still hashed whole, and the throttle only kicks in between
+    /// files, so the rate is only accurate over many files (or time). When
+    /// validating, the cap covers the whole validation, including every
+    /// shard and every subtree `validate_hashfile_by_subtree` runs at once.
+    pub max_bytes_per_sec: Option<NonZeroU64>,
+
+    /// Compute the directory aggregate by copying every file's hash and path
+    /// into one large buffer and hashing it all at once, instead of feeding
+    /// the hasher one file at a time.
+    ///
+    /// The aggregate is identical either way. The buffered approach lets
+    /// blake3 vectorize better, folding about three times as fast (see
+    /// `benches/aggregate.rs`), but that's still only milliseconds per
+    /// hundred thousand files, so it only matters for huge file lists.
+    /// Ignored when `aggregate_sizes` is set, which always feeds the hasher
+    /// one file at a time.
+    pub aggregate_via_buffer: bool,
+
+    /// Also fold each file's size (as 8 little-endian bytes, after its path)
+    /// into the directory aggregate, binding the sizes to it as well, as a
+    /// defense in depth against hash collisions. This changes the aggregate
+    /// (including the one `record_aggregate` records in the hashfile), so
+    /// `verify_aggregate` has to be given the same option. Takes precedence
+    /// over `aggregate_via_buffer`, which is then ignored (that would give
+    /// the same aggregate anyway).
+    pub aggregate_sizes: bool,
+
+    /// Split the hashfile into this many shards, each a complete hashfile
+    /// for a contiguous range of (sorted) file paths, plus a manifest
+    /// in the usual hashfile location that references them.
+    ///
+    /// Each shard can then be validated independently with
+    /// `validate_hashfile_at`, e.g. on different machines.
+    pub shards: Option<NonZeroUsize>,
+
+    /// Before validating, compare the number of files the hashfile records
+    /// against the number of live files, and bail out without hashing
+    /// anything if they differ. Only when the counts match does the
+    /// (much more expensive) content validation happen.
+    ///
+    /// A single shard only covers some of the files, so this always
+    /// fails when validating one directly (rather than via its manifest).
+    pub count_precheck: bool,
+
+    /// Record each file's modification time in the hashfile, as an extra
+    /// column for humans inspecting it ("when did this change?").
+    ///
+    /// Timestamps don't affect any hashes, and validation ignores them
+    /// unless `trust_mtime` is set.
+    pub record_mtime: bool,
+
+    /// Record each file's size in the hashfile, as an extra column. Sizes
+    /// don't affect any hashes, but validation then lists files whose
+    /// contents changed while their size stayed exactly the same in
+    /// `ValidationReport::silent_corruption`, since that's more likely bit
+    /// rot than a deliberate edit.
+    pub record_size: bool,
+
+    /// Have files picked up for hashing in sorted order, a fixed-size chunk
+    /// at a time, instead of in whatever order rayon's work-stealing ends up
+    /// with. Useful for making profiling traces reproducible.
+    ///
+    /// Output is always in sorted order regardless, so this never changes
+    /// any results; it only stabilizes scheduling.
+    pub deterministic_scheduling: bool,
+
+    /// Files larger than this many bytes aren't content hashed at all, and
+    /// are recorded by path and size only. This gives a cheap fingerprint
+    /// of e.g. a media library that still changes whenever files are
+    /// added, removed or resized, without reading multi-gigabyte files.
+    ///
+    /// Such files are marked in the hashfile, and validation only checks
+    /// their size, so content changes to them go undetected by design.
+    pub max_content_size: Option<u64>,
+
+    /// Treat any file that isn't recorded in the hashfile as a validation
+    /// failure, rather than just reporting it as added. For lockdown-style
+    /// verification, where nothing unexpected should ever appear.
+    ///
+    /// Validating a single shard directly (rather than via its manifest)
+    /// only treats files within that shard's range of paths this way.
+    pub strict: bool,
+
+    /// Flush the hashfile to disk (`fsync`) before returning, so a power
+    /// loss right after `create_hashfile` returns can't lose it. Trades
+    /// speed for durability.
+    pub fsync: bool,
+    /// Also flush the directory containing the hashfile, so that the
+    /// hashfile's directory entry (i.e. its name) is durable too.
+    /// Only does anything on Unix.
+    pub fsync_dir: bool,
+
+    /// Traverse the directory tree using the rayon threadpool, reading
+    /// multiple folders at once, rather than on a single thread.
+    ///
+    /// Only worth it for huge trees of tiny files, where the traversal
+    /// itself is the bottleneck. Results are identical either way.
+    pub parallel_traversal: bool,
+
+    /// Which blake3 mode individual files are hashed with. Hashfiles record
+    /// the mode they were created with, and validating in a different mode
+    /// is an error (rather than every single file failing validation).
+    pub mode: HashMode,
+
+    /// What to do with special files (FIFOs, sockets, device files and the
+    /// like) found while hashing. See `SpecialFiles` for the details.
+    pub special_files: SpecialFiles,
+
+    /// Record a stamp of every directory's subtree in the hashfile, and
+    /// have validation skip re-hashing the files of any subtree whose
+    /// stamp still matches (see `ValidationReport::unchanged_dirs`).
+    ///
+    /// Stamps are derived from the path, size and modification time of
+    /// every file in the subtree, since there's no way to recompute the
+    /// contents side of things without reading every file. So like
+    /// `record_mtime`, this trusts that files whose metadata hasn't changed
+    /// haven't either, which silent corruption (or a tool deliberately
+    /// restoring the mtime) breaks. In exchange, revalidating mostly static
+    /// trees only costs a stat per file, plus hashing whatever changed.
+    ///
+    /// Stamps are deliberately not Merkle hashes of the contents: checking
+    /// whether a content-derived directory hash still matches means reading
+    /// every file beneath it, which is exactly the work skipping is meant to
+    /// save, so it could never skip anything.
+    ///
+    /// Like `dir_counts`, validating a lone shard with this set fails.
+    pub subtree_stamps: bool,
+
+    /// Separator used between components of the paths written to
+    /// hashfiles. Paths are always `/`-separated internally (see
+    /// `normalize_path`), so this only affects the hashfile itself, and
+    /// never any hash. Validation reads paths back using whatever
+    /// separator the hashfile says it was written with.
+    pub path_separator: PathSeparator,
+
+    /// Validate in two passes: first parse every record of the hashfile,
+    /// then hash every file, rather than parsing each record right before
+    /// hashing its file. Results are identical either way.
+    ///
+    /// It costs holding every parsed record in memory, and on a single core
+    /// over 50,000 tiny files (see `benches/validation.rs`) it came out a
+    /// few percent slower. The idea is keeping the IO-bound pass free of any
+    /// CPU-bound work on many cores, so measure before turning it on.
+    pub two_phase_validation: bool,
+
+    /// Transforms the contents of every file before it's hashed, so trees
+    /// can be compared in some normalized form (e.g. ignoring trailing
+    /// whitespace). `HashedFile::size` is still the size of the file itself.
+    ///
+    /// It gets called concurrently, and hashing is only deterministic
+    /// if this is a pure function of its input. Transformed files are read
+    /// into memory rather than memory mapped, so this is slower, and uses
+    /// memory proportional to the largest files times the thread count.
+    /// Special files (see `special_files`) and size-only files are never
+    /// transformed. Validation needs the exact same transform to succeed.
+    pub content_transform: Option<ContentTransform>,
+
+    /// Cap on the combined size of the files being hashed concurrently.
+    /// Workers wait to start on a file until enough of the budget is free,
+    /// so boxes with little RAM don't end up thrashing the page cache by
+    /// mapping many large files at once.
+    ///
+    /// Small files (which are never memory mapped) don't count towards the
+    /// budget, and a file larger than the whole budget is hashed on its own.
+    /// Every other file costs an extra stat.
+    pub max_in_flight_bytes: Option<NonZeroU64>,
+
+    /// Treat files that can't be read during validation as having failed,
+    /// recording the error in `ValidationReport::read_errors`, instead of
+    /// aborting validation with that error. For best-effort validation
+    /// of flaky disks. Errors reading the hashfile itself still abort.
+    pub continue_on_error: bool,
+
+    /// How many files to allocate room for up front while traversing.
+    ///
+    /// By default there's enough room for huge trees (a few MiB worth of
+    /// paths), which is wasted on tiny directories. Anything works, since
+    /// the list just grows as needed, so this only tunes allocation.
+    pub file_capacity_hint: Option<usize>,
+
+    /// Encoding of the hashes written to hashfiles. The hashfile records
+    /// which one it uses, so validation always decodes them correctly.
+    pub hash_encoding: HashEncoding,
+
+    /// Fail when two files' paths differ only by case, since they collide
+    /// on case-insensitive filesystems (and in anything keyed on
+    /// case-insensitive paths), making the results ambiguous there.
+    /// The error names every set of colliding paths.
+    ///
+    /// Case is compared by simple Unicode lowercasing, which, like
+    /// most filesystems, doesn't account for every locale's rules.
+    pub detect_case_collisions: bool,
+
+    /// Arbitrary key-value metadata to record in the hashfile header, e.g.
+    /// the tool version, hostname or a comment, for provenance. It never
+    /// affects validation, and can be read back with `read_hashfile_metadata`.
+    ///
+    /// Keys can't contain whitespace and values can't contain line breaks.
+    pub metadata: HashMap<String, String>,
+
+    /// Skip checking that the hashfile is valid utf8 when reading it.
+    ///
+    /// Hashfiles are always written as utf8, but one that was corrupted
+    /// (bad disk, partial write) or hand-edited might not be, which by
+    /// default is reported as an `InvalidData` error. Only set this when
+    /// the hashfile itself is known to be intact, e.g. because it was
+    /// just written or its own checksum was verified, since reading an
+    /// invalid one this way is undefined behavior.
+    pub trusted_hashfile: bool,
+
+    /// When validating against a hashfile with an mtime column (see
+    /// `record_mtime`), assume files whose mtime hasn't changed haven't
+    /// either, and skip hashing them. Only files with a different (or
+    /// unknown) mtime get their contents hashed.
+    ///
+    /// This is rsync's "quick check": when the hashfile also has a size
+    /// column (see `record_size`), a file's size has to be unchanged too.
+    /// It turns revalidating static trees into a stat per file, but silent
+    /// corruption, a tool restoring the mtime after a same-size write, or
+    /// a same-size change within the recorded second all go unnoticed.
+    /// Validating specific paths always hashes them.
+    pub trust_mtime: bool,
+
+    /// Record the aggregate hash of the whole directory (the same one
+    /// `hash_directory` returns) in the hashfile header, so that
+    /// `verify_aggregate` can check the directory with a single comparison.
+    pub record_aggregate: bool,
+
+    /// Record the name of the hashed directory in the hashfile header, so
+    /// validation can point out when a (moved) hashfile is being validated
+    /// against a directory with a different name, which is usually the
+    /// wrong one (see `ValidationReport::dir_name_mismatch`). Purely
+    /// advisory, since validation passes or fails just the same either way.
+    pub record_dir_name: bool,
+
+    /// Directory to prefix every path recorded in the hashfile with,
+    /// typically the hashed directory's own name, like `tar` does. Keeps
+    /// the paths of hashfiles for several directories unambiguous when
+    /// their records are merged.
+    ///
+    /// The prefix only exists in the hashfile: it's stripped again when
+    /// validating, and it's never part of any hash, so neither the file
+    /// hashes nor the aggregate change with it. Can't contain line breaks.
+    pub root_prefix: Option<String>,
+
+    /// Called after every file is hashed with how far along hashing is,
+    /// including an estimate of the time remaining. Calls come from
+    /// whichever worker hashed the file, so they can overlap.
+    ///
+    /// Every file is stat'ed up front to get the total bytes to hash,
+    /// which costs a syscall per file before any hashing starts.
+    /// Validation doesn't report progress.
+    pub progress: Option<ProgressCallback>,
+
+    /// Follow symlinks to files, recording each file under its real
+    /// (canonical) path rather than the path of any link to it, so a file
+    /// reached through several links (or directly) is only hashed once.
+    ///
+    /// Files inside the hashed directory are recorded relative to it
+    /// as usual, even if hidden. Files outside of it are recorded under
+    /// their absolute path. Symlinks to directories are still never
+    /// followed, and dangling ones are ignored.
+    pub resolve_symlinks: bool,
+
+    /// Handle for pausing, resuming or cancelling hashing (and validation)
+    /// from another thread while it runs. Keep a clone of the `Arc` to
+    /// control it with.
+    pub control: Option<Arc<HashControl>>,
+
+    /// File (or folder) names that are always left out, anywhere in the
+    /// tree, exactly like hidden entries are, e.g. the names of sidecar
+    /// files (signatures, metadata) written next to a hashfile that lives
+    /// inside the hashed directory. Names are matched exactly, and the
+    /// same set has to be given when validating.
+    pub exclude: HashSet<String>,
+
+    /// Patterns for file (or folder) names that are always hashed, anywhere
+    /// in the tree, even when they're also in `exclude` or hidden (like
+    /// `.gitignore`, or `.env*`). The precedence is force-include, then
+    /// `exclude`, then the default of skipping only hidden entries.
+    ///
+    /// Patterns are matched against whole names (never paths): `*` matches
+    /// any run of characters and `?` any single one, while everything else
+    /// only matches itself. A forced folder has everything beneath it
+    /// traversed as usual. The same patterns have to be given when validating.
+    pub force_include: Vec<String>,
+
+    /// Leave everything out of the hashfile that can change without the
+    /// directory's contents changing: `metadata`, the `record_mtime` column
+    /// and `subtree_stamps` (which are derived from mtimes). Hashing the
+    /// same contents then always writes a byte-identical hashfile, which
+    /// keeps diffs minimal when it's checked into version control.
+    pub deterministic_hashfile: bool,
+
+    /// Feed a random salt, generated fresh for every hashfile created, into
+    /// the hasher ahead of each file's contents, recording it in the header.
+    /// Identical files then hash differently in every hashfile, so recorded
+    /// hashes can't be matched up against known files (or other hashfiles).
+    ///
+    /// Validation always uses whatever salt the hashfile records, so this
+    /// only matters when creating one. Since the salt changes every time,
+    /// it defeats `deterministic_hashfile`.
+    pub salted: bool,
+
+    /// Fail hashing with `ErrorKind::Unsupported` as soon as anything other
+    /// than a regular file or folder is found (symlinks included), naming
+    /// the first such path, so nothing is ever silently left out. Takes
+    /// precedence over both `special_files` and `resolve_symlinks`.
+    pub regular_files_only: bool,
+
+    /// Record when hashing started and finished in the returned
+    /// `HashedDirectory` (see `HashedDirectory::started_at`).
+    pub record_timestamps: bool,
+
+    /// Size of the buffer files are read into (and fed into the hasher
+    /// from) when they're streamed rather than memory mapped: special files,
+    /// files below `mmap_min_size` and, with `decompress`, compressed ones.
+    /// Defaults to 128 KiB when
+    /// `None`. Only affects speed, never the resulting hashes.
+    pub read_buffer_size: Option<NonZeroUsize>,
+
+    /// Files smaller than this many bytes are read into a buffer (see
+    /// `read_buffer_size`) rather than memory mapped, skipping the cost of
+    /// setting up a mapping for tiny files. Zero maps everything, which
+    /// leaves it to blake3 (it already reads files under 16 KiB).
+    pub mmap_min_size: u64,
+
+    /// Files larger than this many bytes are memory mapped and hashed with
+    /// blake3's own multithreading (on the rayon pool hashing runs on), so a
+    /// few huge files don't leave the rest of the pool idle. `None` hashes
+    /// every file on a single thread.
+    ///
+    /// Files between `mmap_min_size` and this are memory mapped as usual.
+    /// Neither ever affects the resulting hashes, only the extra stat per
+    /// file it takes to choose (whenever either is set).
+    pub mmap_max_size: Option<u64>,
+
+    /// Fold the owning user and group ids of every regular file into its
+    /// hash (as fixed width bytes, after the contents are hashed), so a
+    /// `chown` changes the hash just like an edit does. The same option has
+    /// to be given when validating. Special files never include theirs.
+    ///
+    /// Only Unix has numeric ids to hash: everywhere else hashing (and
+    /// validating) fails with `ErrorKind::Unsupported`, rather than
+    /// silently leaving ownership out.
+    pub include_ownership: bool,
+
+    /// Leave out folders that can't be read (e.g. permission denied) along
+    /// with everything beneath them, rather than failing hashing entirely.
+    /// They're listed in `HashedDirectory::unreadable_dirs`, and aggregates
+    /// only cover the files that could be reached. The hashed directory
+    /// itself always has to be readable.
+    pub skip_unreadable_dirs: bool,
+
+    /// Hash files breadth-first: everything directly inside the directory,
+    /// then everything one folder down, and so on, picking them up in that
+    /// order like `deterministic_scheduling` does. Progress (see `progress`)
+    /// then shows shallow files finishing before deep ones.
+    ///
+    /// Output is still sorted by path, so no results change. Traversal order
+    /// never mattered, since files are always sorted before being hashed.
+    pub breadth_first: bool,
+
+    /// Hash the decompressed contents of gzip and zstd compressed files
+    /// (detected by their magic bytes, not their extension) instead of the
+    /// compressed bytes, so `file.gz` hashes the same as `file`, and just
+    /// recompressing a file doesn't change its hash. Recorded sizes are
+    /// still those of the compressed files.
+    ///
+    /// Compressed files are streamed rather than memory mapped, ignore
+    /// `content_transform`, and fail to hash if they don't decompress.
+    #[cfg(feature = "decompress")]
+    pub decompress: bool,
+
+    /// Record every path NFC normalized, so the same file name hashes the
+    /// same whether the filesystem stored it composed (like most Linux
+    /// filesystems) or decomposed (like macOS did). This changes the aggregate
+    /// of (and the paths recorded for) any tree with decomposed file names.
+    ///
+    /// Validation looks files up by their normalized paths too, so a file is
+    /// found whichever form its name is stored in, at the cost of one extra
+    /// traversal. Two files whose names only differ in normalization fail
+    /// hashing with `ErrorKind::InvalidData`.
+    #[cfg(feature = "unicode")]
+    pub normalize_unicode: bool,
+}
+
+/// Transform applied to file contents before hashing,
+/// see `HashOptions::content_transform`.
+pub type ContentTransform = Box<dyn Fn(&[u8]) -> Vec<u8> + Send + Sync>;
+
+/// Callback for reporting progress, see `HashOptions::progress`.
+pub type ProgressCallback = Box<dyn Fn(&ProgressEvent) + Send + Sync>;
+
+/// The blake3 modes files can be hashed with.
+///
+/// Directory aggregates are always computed in plain mode, but since
+/// they're derived from the per-file hashes they still depend on the mode.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub enum HashMode {
+    /// Regular, unkeyed blake3.
+    #[default]
+    Plain,
+    /// Keyed blake3 (a MAC), so hashes can't be reproduced without the key.
+    Keyed([u8; blake3::KEY_LEN]),
+    /// blake3's key derivation mode, with the given context string.
+    DeriveKey(String),
+}
+
+impl HashMode {
+    /// A fresh `Hasher` for this mode.
+    #[inline]
+    pub fn hasher(&self) -> Hasher {
+        match self {
+            Self::Plain => Hasher::new(),
+            Self::Keyed(key) => Hasher::new_keyed(key),
+            Self::DeriveKey(context) => Hasher::new_derive_key(context),
+        }
+    }
+}
+
+/// How special files (anything that's neither a regular file, a folder,
+/// nor a symlink) are handled while hashing a directory.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub enum SpecialFiles {
+    /// Leave them out of the results entirely, recording their paths in
+    /// `HashedDirectory::skipped` so the omission is at least visible.
+    #[default]
+    Skip,
+    /// Fail with an error naming the first special file found.
+    Error,
+    /// Hash whatever can be read from them, just like regular files.
+    /// Reading a FIFO blocks until something writes to it (and reading
+    /// some devices never ends), so each read that takes longer than
+    /// `timeout` fails with `ErrorKind::TimedOut`. Validation reads them
+    /// again the same way, so this only makes sense for devices and
+    /// FIFOs that produce the same contents every time.
+    Read { timeout: Duration },
+}
+
+/// Separators that paths in hashfiles can be written with.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub enum PathSeparator {
+    /// Always `/`, the same on every platform.
+    #[default]
+    Slash,
+    /// Always `\`, for native Windows tools. Files whose names contain
+    /// a literal `\` (allowed on Unix) can't be told apart from
+    /// separators, so don't use this for hashfiles of such trees.
+    Backslash,
+    /// Whatever the current platform natively uses.
+    Native,
+}
+
+impl PathSeparator {
+    /// The actual separator character.
+    #[inline]
+    pub fn as_char(self) -> char {
+        match self {
+            Self::Slash => '/',
+            Self::Backslash => '\\',
+            Self::Native => std::path::MAIN_SEPARATOR,
+        }
+    }
+}
+
+/// Text encodings hashes can be written to hashfiles with.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub enum HashEncoding {
+    /// Lowercase hex, 64 characters.
+    #[default]
+    Hex,
+    /// URL-safe Base64 without padding, 43 characters.
+    Base64Url,
+    /// Base32 (RFC 4648) without padding, 52 characters.
+    Base32,
+}
@@ -0,0 +1,145 @@
+use crate::options::ProgressCallback;
+use crate::util::stat;
+use camino::Utf8PathBuf;
+use rayon::prelude::*;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// A snapshot of how far along hashing a directory is, handed to
+/// `HashOptions::progress` after every file.
+#[derive(Clone, Copy, Debug)]
+pub struct ProgressEvent {
+    /// Files hashed so far, including this one.
+    pub files_done: u64,
+    /// Files that will be hashed in total.
+    pub files_total: u64,
+    /// Bytes hashed so far, including this file.
+    pub bytes_done: u64,
+    /// Bytes that will be hashed in total, as of the pre-scan.
+    pub bytes_total: u64,
+    /// Time since hashing started.
+    pub elapsed: Duration,
+    /// Estimated time remaining, assuming the remaining bytes go at the same
+    /// rate as those so far. `None` until there's a rate to go off of, and
+    /// always zero once every file is done.
+    pub eta: Option<Duration>,
+}
+
+/// Tracks progress across every rayon worker hashing the same directory,
+/// reporting it to the callback.
+///
+/// Totals come from stat'ing every file up front, which is an extra
+/// syscall per file, so this only exists when a callback was given.
+/// That metadata isn't kept for hashing to reuse: holding onto every
+/// file's `Metadata` until it's hashed would cost memory proportional to
+/// the whole tree, so files that need it are stat'ed again when hashed.
+pub struct Progress<'a> {
+    callback: &'a ProgressCallback,
+    start: Instant,
+    files_total: u64,
+    bytes_total: u64,
+    files_done: AtomicU64,
+    bytes_done: AtomicU64,
+}
+
+impl<'a> Progress<'a> {
+    #[inline]
+    pub fn new(callback: &'a ProgressCallback, file_list: &[Utf8PathBuf]) -> Self {
+        // Anything that can't be stat'ed fails once it's hashed anyway.
+        let bytes_total = file_list
+            .par_iter()
+            .map(|path| stat(path).map_or(0, |metadata| metadata.len()))
+            .reduce(|| 0, u64::saturating_add);
+        Self {
+            callback,
+            start: Instant::now(),
+            files_total: file_list.len() as u64,
+            bytes_total,
+            files_done: AtomicU64::new(0),
+            bytes_done: AtomicU64::new(0),
+        }
+    }
+
+    /// Records one more file of `bytes` as done and reports it.
+    #[inline]
+    pub fn file_done(&self, bytes: u64) {
+        let files_done = self.files_done.fetch_add(1, Ordering::Relaxed) + 1;
+        let bytes_done = self
+            .bytes_done
+            .fetch_add(bytes, Ordering::Relaxed)
+            .saturating_add(bytes);
+        let elapsed = self.start.elapsed();
+        // Files can grow between the pre-scan and being hashed,
+        // so the byte count alone can't tell when we're done.
+        let eta = match files_done >= self.files_total {
+            true => Some(Duration::ZERO),
+            false => eta(bytes_done, self.bytes_total, elapsed),
+        };
+        (self.callback)(&ProgressEvent {
+            files_done,
+            files_total: self.files_total,
+            bytes_done,
+            bytes_total: self.bytes_total,
+            elapsed,
+            eta,
+        });
+    }
+}
+
+/// Time left to get through `bytes_total` at the rate `bytes_done`
+/// took `elapsed`, or `None` if nothing's been done yet to measure.
+#[inline]
+fn eta(bytes_done: u64, bytes_total: u64, elapsed: Duration) -> Option<Duration> {
+    if bytes_done == 0 {
+        return None;
+    }
+    let remaining = bytes_total.saturating_sub(bytes_done);
+    Some(elapsed.mul_f64(remaining as f64 / bytes_done as f64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::Scratch;
+    use crate::{hash_directory_with_options, HashOptions};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn eta_scales_the_elapsed_time_by_the_bytes_left() {
+        let elapsed = Duration::from_secs(2);
+        assert_eq!(eta(0, 100, elapsed), None);
+        assert_eq!(eta(50, 100, elapsed), Some(Duration::from_secs(2)));
+        assert_eq!(eta(25, 100, elapsed), Some(Duration::from_secs(6)));
+        assert_eq!(eta(100, 100, elapsed), Some(Duration::ZERO));
+        // Files grew since the pre-scan.
+        assert_eq!(eta(150, 100, elapsed), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn every_file_is_reported_and_the_last_has_no_time_left() {
+        let scratch = Scratch::new();
+        scratch.write("tree/a.txt", "a".repeat(1000));
+        scratch.write("tree/sub/b.txt", "b".repeat(3000));
+        scratch.write("tree/sub/empty.txt", "");
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&events);
+        let options = HashOptions {
+            progress: Some(Box::new(move |event: &ProgressEvent| {
+                recorded.lock().unwrap().push(*event)
+            })),
+            ..Default::default()
+        };
+        hash_directory_with_options("tree", &options).unwrap();
+
+        let mut events = events.lock().unwrap().clone();
+        events.sort_by_key(|event| event.files_done);
+        let done: Vec<_> = events.iter().map(|event| event.files_done).collect();
+        assert_eq!(done, [1, 2, 3]);
+        for event in &events {
+            assert_eq!((event.files_total, event.bytes_total), (3, 4000));
+            assert_eq!(event.eta.is_none(), event.bytes_done == 0);
+        }
+        let last = events.last().unwrap();
+        assert_eq!((last.bytes_done, last.eta), (4000, Some(Duration::ZERO)));
+    }
+}
@@ -0,0 +1,205 @@
+use crate::util::{Failed, Failure};
+
+/// The outcome of validating a directory against a hashfile.
+#[derive(Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ValidationReport {
+    /// Paths of files whose hash no longer matches, or which no longer exist,
+    /// in path order (so they're reproducible, whatever order they failed in).
+    pub failed_files: Vec<String>,
+    /// Files in `failed_files` that were recorded but don't exist anymore,
+    /// in path order. Every other failed file is still there, but changed
+    /// or couldn't be read (or, with `HashOptions::strict`, is `added`).
+    pub missing: Vec<String>,
+    /// Paths of files present in the directory that weren't recorded.
+    /// These are purely informational, and only fail validation (by also
+    /// being included in `failed_files`) when `HashOptions::strict` is set.
+    pub added: Vec<String>,
+    /// Directories whose live file count differs from the count recorded
+    /// in the hashfile. Only populated when `HashOptions::dir_counts` is set.
+    pub count_mismatches: Vec<CountMismatch>,
+    /// Set when `HashOptions::count_precheck` found a different number of
+    /// live files than the hashfile records, in which case no file contents
+    /// were hashed at all. `dir` is always empty, and the counts cover
+    /// every file in the directory tree.
+    pub structure_changed: Option<CountMismatch>,
+    /// Directories whose subtree stamp still matched, so none of the files
+    /// beneath them were re-hashed. Only the topmost such directories are
+    /// listed (empty for the validated directory itself), in path order.
+    /// Only populated when `HashOptions::subtree_stamps` is set.
+    pub unchanged_dirs: Vec<String>,
+    /// Files that couldn't be read, which are also in `failed_files`.
+    /// Only populated when `HashOptions::continue_on_error` is set,
+    /// otherwise the first such error is returned instead.
+    pub read_errors: Vec<ReadError>,
+    /// Name of the directory the hashfile was created for, when it differs
+    /// from the name of the validated directory. Purely advisory, so it
+    /// doesn't affect `is_valid`. Only ever set for hashfiles created with
+    /// `HashOptions::record_dir_name`.
+    pub dir_name_mismatch: Option<String>,
+    /// Files in `failed_files` whose contents changed, but whose size is
+    /// still exactly what was recorded, in path order. Edits usually change
+    /// the size, so these are more likely silent corruption (bit rot).
+    /// Files that are missing or couldn't be read are never listed. Only
+    /// populated for hashfiles created with `HashOptions::record_size`.
+    pub silent_corruption: Vec<String>,
+}
+
+impl ValidationReport {
+    /// Sorts every list by path, so reports are reproducible regardless
+    /// of hashfile order (shards, strict mode additions, etc). `sync_plan`
+    /// relies on `added` and `missing` being sorted.
+    pub(crate) fn sort(&mut self) {
+        self.failed_files.sort_unstable();
+        self.missing.sort_unstable();
+        self.added.sort_unstable();
+        self.count_mismatches
+            .sort_unstable_by(|x, y| x.dir.cmp(&y.dir));
+        self.unchanged_dirs.sort_unstable();
+        self.silent_corruption.sort_unstable();
+        self.read_errors
+            .sort_unstable_by(|x, y| x.path.cmp(&y.path));
+    }
+
+    /// Adds `failures` to `failed_files`, along with the missing ones to
+    /// `missing` and any errors behind them to `read_errors`.
+    pub(crate) fn push_failures(&mut self, failures: Vec<Failure>) {
+        for (path, failed) in failures {
+            match failed {
+                Failed::Changed => {}
+                Failed::Missing => self.missing.push(path.clone()),
+                Failed::Unreadable(error) => self.read_errors.push(ReadError {
+                    path: path.clone(),
+                    error: error.to_string(),
+                }),
+            }
+            self.failed_files.push(path);
+        }
+    }
+
+    /// Whether the directory passed validation.
+    #[inline]
+    pub fn is_valid(&self) -> bool {
+        self.failed_files.is_empty()
+            && self.count_mismatches.is_empty()
+            && self.structure_changed.is_none()
+    }
+
+    /// What a sync tool would need to do to bring the validated directory
+    /// back in line with what the hashfile recorded (see `SyncPlan`).
+    ///
+    /// Entirely worked out from the report, with `missing` telling the
+    /// files that went missing apart from the ones that changed, so nothing
+    /// is checked on disk. Paths are the same as in the report.
+    pub fn sync_plan(&self) -> SyncPlan {
+        let mut plan = SyncPlan {
+            to_add: self.missing.clone(),
+            to_delete: self.added.clone(),
+            ..Default::default()
+        };
+        for path in &self.failed_files {
+            // Strict mode fails added files too, but those are already covered.
+            if self.added.binary_search(path).is_ok() || self.missing.binary_search(path).is_ok() {
+                continue;
+            }
+            plan.to_copy.push(path.clone());
+        }
+        plan
+    }
+}
+
+/// The actions that would undo every difference a `ValidationReport`
+/// found, treating the hashfile's source as the side to copy from.
+/// Every list is in path order.
+#[derive(Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SyncPlan {
+    /// Files that are still there but changed, so need copying over again.
+    pub to_copy: Vec<String>,
+    /// Files that were recorded but are gone, so need copying in fresh.
+    pub to_add: Vec<String>,
+    /// Files that were never recorded, so need deleting.
+    pub to_delete: Vec<String>,
+}
+
+/// How the contents of two live directories differ (see `compare_directories`).
+///
+/// Every path is relative to the directories being compared, and every
+/// list is in path order.
+#[derive(Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct DirDiff {
+    /// Files only present in the first directory.
+    pub only_in_a: Vec<String>,
+    /// Files only present in the second directory.
+    pub only_in_b: Vec<String>,
+    /// Files present in both, but with different contents.
+    pub differing: Vec<String>,
+}
+
+impl DirDiff {
+    /// Whether both directories contain exactly the same files.
+    #[inline]
+    pub fn is_identical(&self) -> bool {
+        self.only_in_a.is_empty() && self.only_in_b.is_empty() && self.differing.is_empty()
+    }
+}
+
+/// A directory containing a different number of files than recorded.
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct CountMismatch {
+    /// Path of the directory, relative to the validated directory
+    /// (empty for the validated directory itself).
+    pub dir: String,
+    /// Number of files the hashfile recorded.
+    pub expected: usize,
+    /// Number of files actually present.
+    pub found: usize,
+}
+
+/// A file that failed validation because it couldn't be read.
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ReadError {
+    /// Path of the file, joined onto the validated directory.
+    pub path: String,
+    /// Description of the error reading it.
+    pub error: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sync_plan_after_sorting_skips_strictly_failed_additions() {
+        let added = ["tree/z_added.txt", "tree/b_added.txt"].map(String::from);
+        let mut report = ValidationReport {
+            failed_files: ["tree/y_gone.txt", "tree/changed.txt", "tree/a_gone.txt"]
+                .map(String::from)
+                .into(),
+            missing: vec!["tree/y_gone.txt".into(), "tree/a_gone.txt".into()],
+            added: added.to_vec(),
+            count_mismatches: ["tree/sub", ""]
+                .map(|dir| CountMismatch {
+                    dir: dir.into(),
+                    expected: 1,
+                    found: 2,
+                })
+                .into(),
+            unchanged_dirs: vec!["tree/y".into(), "tree/x".into()],
+            ..Default::default()
+        };
+        // As strict mode would have it.
+        report.failed_files.extend(added);
+        report.sort();
+
+        assert_eq!(report.added, ["tree/b_added.txt", "tree/z_added.txt"]);
+        let dirs: Vec<_> = report.count_mismatches.iter().map(|m| &*m.dir).collect();
+        assert_eq!(dirs, ["", "tree/sub"]);
+        assert_eq!(report.unchanged_dirs, ["tree/x", "tree/y"]);
+        let plan = report.sync_plan();
+        assert_eq!(plan.to_copy, ["tree/changed.txt"]);
+        assert_eq!(plan.to_add, ["tree/a_gone.txt", "tree/y_gone.txt"]);
+        assert_eq!(plan.to_delete, report.added);
+    }
+}
@@ -0,0 +1,53 @@
+//! Scaffolding shared by the unit tests.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use tempfile::TempDir;
+
+/// Hashfiles are always read from and written to the current directory,
+/// so tests take turns being in their own scratch directory.
+static CWD_LOCK: Mutex<()> = Mutex::new(());
+
+/// A fresh temporary directory that's the current directory until dropped.
+pub struct Scratch {
+    previous: PathBuf,
+    dir: TempDir,
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl Scratch {
+    pub fn new() -> Self {
+        let lock = CWD_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+        let previous = std::env::current_dir().unwrap();
+        let dir = TempDir::new().unwrap();
+        std::env::set_current_dir(dir.path()).unwrap();
+        Self {
+            previous,
+            dir,
+            _lock: lock,
+        }
+    }
+
+    /// Writes `contents` to `path`, relative to the scratch
+    /// directory, creating any missing parent folders.
+    pub fn write(&self, path: &str, contents: impl AsRef<[u8]>) {
+        let path = self.dir.path().join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+
+    /// The contents of the hashfile in the scratch directory.
+    pub fn hashfile(&self) -> String {
+        std::fs::read_to_string(self.dir.path().join(crate::HASH_RESULTS_FILENAME)).unwrap()
+    }
+
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = std::env::set_current_dir(&self.previous);
+    }
+}
@@ -0,0 +1,69 @@
+use std::num::NonZeroU64;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Limits how many bytes per second get hashed, shared by every
+/// rayon worker hashing the same directory.
+///
+/// This is a token bucket without any burst capacity: tokens (bytes)
+/// accrue at `limit` per second from the moment the `Throttle` is created,
+/// and after hashing a file each worker sleeps until enough tokens have
+/// accrued to cover every byte hashed so far. Since blake3 is almost
+/// always faster than the disk, this effectively caps the read rate.
+pub struct Throttle {
+    limit: NonZeroU64,
+    start: Instant,
+    consumed: AtomicU64,
+}
+
+impl Throttle {
+    #[inline]
+    pub fn new(limit: NonZeroU64) -> Self {
+        Self {
+            limit,
+            start: Instant::now(),
+            consumed: AtomicU64::new(0),
+        }
+    }
+
+    /// Spends `bytes` tokens, blocking the current thread until
+    /// they're available.
+    #[inline]
+    pub fn consume(&self, bytes: u64) {
+        // fetch_add itself wraps, but by then we'd have been sleeping for
+        // centuries anyway, so it's only the local total that needs care.
+        let total = self
+            .consumed
+            .fetch_add(bytes, Ordering::Relaxed)
+            .saturating_add(bytes);
+        let allowed_at = Duration::from_secs_f64(total as f64 / self.limit.get() as f64);
+        if let Some(wait) = allowed_at.checked_sub(self.start.elapsed()) {
+            std::thread::sleep(wait);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::Scratch;
+    use crate::{hash_directory, hash_directory_with_options, HashOptions};
+    use std::num::NonZeroU64;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn throttled_hashing_takes_at_least_as_long_as_the_limit_allows() {
+        let scratch = Scratch::new();
+        for name in ["a", "b", "c", "d"] {
+            scratch.write(&format!("tree/{}.bin", name), vec![7; 25_000]);
+        }
+        let options = HashOptions {
+            max_bytes_per_sec: NonZeroU64::new(400_000),
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let throttled = hash_directory_with_options("tree", &options).unwrap();
+        // 100 KB at 400 KB/s, and the throttle never lets anything through early.
+        assert!(start.elapsed() >= Duration::from_millis(250));
+        assert_eq!(throttled.hash, hash_directory("tree").unwrap().hash);
+    }
+}
@@ -1,12 +1,236 @@
-use blake3::Hash;
+use blake3::{Hash, Hasher};
+use camino::Utf8Path;
+use core::cmp::Ordering;
 use core::ops::Deref;
+use std::collections::BTreeMap;
+use std::num::NonZeroU64;
+use std::time::SystemTime;
 
 /// TODO: docs
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct HashedDirectory {
     pub dir_name: String,
     pub files: Vec<HashedFile>,
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_hash"))]
     pub hash: Hash,
-    /// Cumulative size of all hashed files, in bytes.
+    /// Cumulative size of all hashed files, in bytes. Pinned at `u64::MAX`
+    /// if it would overflow, so that value means "at least this much"
+    /// (see `size_overflowed`).
+    pub size: u64,
+    /// Relative paths of the special files (FIFOs, sockets, device files
+    /// and the like) that were left out, in path order. Always empty
+    /// unless `HashOptions::special_files` is `SpecialFiles::Skip`.
+    pub skipped: Vec<String>,
+    /// Relative paths of the folders that couldn't be read, and so were
+    /// left out along with everything beneath them, in path order. Always
+    /// empty unless `HashOptions::skip_unreadable_dirs` is set.
+    pub unreadable_dirs: Vec<String>,
+    /// Number of threads in the rayon threadpool the files were hashed on,
+    /// purely to document the run. The results never depend on it. Zero
+    /// when the files weren't all hashed in one go (see `DirectoryHasher`).
+    pub threads_used: usize,
+    /// When hashing started and finished, purely to document the run (e.g.
+    /// for audit logs), and never part of any hash. Only recorded when
+    /// `HashOptions::record_timestamps` is set.
+    pub started_at: Option<SystemTime>,
+    pub finished_at: Option<SystemTime>,
+}
+
+impl HashedDirectory {
+    /// Fingerprint of just the set of file paths, ignoring their contents.
+    ///
+    /// Unlike `hash`, this only changes when files are added, removed or
+    /// renamed, which makes it a cheap way to check for structural changes.
+    pub fn structure_hash(&self) -> Hash {
+        let mut hasher = Hasher::new();
+        for file in &self.files {
+            // Length prefixed, otherwise e.g. "ab" + "c" and "a" + "bc"
+            // would produce the same fingerprint.
+            hasher.update(&(file.path.len() as u64).to_le_bytes());
+            hasher.update(file.path.as_bytes());
+        }
+        hasher.finalize()
+    }
+
+    /// Whether the sizes of the files add up to more than `u64::MAX`, so
+    /// `size` (and every other total pinned like it) is only a lower bound.
+    /// Only files adding up to exactly `u64::MAX` need a second look.
+    pub fn size_overflowed(&self) -> bool {
+        self.size == u64::MAX
+            && self
+                .files
+                .iter()
+                .try_fold(0u64, |total, file| total.checked_add(file.size))
+                .is_none()
+    }
+
+    /// Every file's hash keyed by its path, for ordered iteration and
+    /// range queries (e.g. every file under `dir/sub/`).
+    ///
+    /// The files are already sorted, which `BTreeMap` takes advantage of
+    /// when collecting, so this is cheap compared to an unsorted insert.
+    pub fn into_btree(self) -> BTreeMap<String, Hash> {
+        self.files.into_iter().map(<(String, Hash)>::from).collect()
+    }
+
+    /// Total size of the files beneath each directory (at any depth),
+    /// keyed by the directory's path relative to the hashed directory,
+    /// which itself is the empty string. Like `du`, but only counting
+    /// hashed files, so directories without any files aren't listed.
+    ///
+    /// Sizes are pinned at `u64::MAX` just like `size`.
+    pub fn sizes_by_directory(&self) -> BTreeMap<String, u64> {
+        let mut sizes: BTreeMap<String, u64> = BTreeMap::new();
+        for file in &self.files {
+            let ancestors = file.path.rmatch_indices('/').map(|(i, _)| &file.path[..i]);
+            for dir in ancestors.chain([""]) {
+                // Only allocates the first time each directory is seen.
+                match sizes.get_mut(dir) {
+                    Some(size) => *size = size.saturating_add(file.size),
+                    None => {
+                        sizes.insert(dir.to_string(), file.size);
+                    }
+                }
+            }
+        }
+        sizes
+    }
+
+    /// Total size of all hashed files with each one rounded up to a whole
+    /// number of `block_size` byte blocks, which approximates the space
+    /// they actually take up on disk (like `du` rather than `ls`). Empty
+    /// files take up no blocks at all.
+    ///
+    /// Pinned at `u64::MAX` just like `size`.
+    pub fn allocated_size(&self, block_size: NonZeroU64) -> u64 {
+        let block_size = block_size.get();
+        self.files.iter().fold(0, |total: u64, file| {
+            let blocks = file.size.div_ceil(block_size);
+            total.saturating_add(blocks.saturating_mul(block_size))
+        })
+    }
+
+    /// How many bytes bigger this directory is than `other` (e.g. an older
+    /// result), so negative when it shrank. Saturates at the ends of `i64`.
+    pub fn size_delta(&self, other: &HashedDirectory) -> i64 {
+        signed_delta(self.size, other.size)
+    }
+
+    /// How many more files this directory has than `other`,
+    /// so negative when files were removed. Saturates like `size_delta`.
+    pub fn file_count_delta(&self, other: &HashedDirectory) -> i64 {
+        signed_delta(self.files.len() as u64, other.files.len() as u64)
+    }
+
+    /// Every distinct file hash, once each and sorted by its bytes, e.g. for
+    /// the keys of a content-addressable store. Size-only files are left out,
+    /// since their `hash` is only a stand-in rather than of their contents.
+    pub fn unique_hashes(&self) -> Vec<Hash> {
+        let mut digests: Vec<Digest> = self
+            .files
+            .iter()
+            .filter(|file| !file.size_only)
+            .map(|file| file.hash.into())
+            .collect();
+        digests.sort_unstable();
+        digests.dedup();
+        digests.into_iter().map(Hash::from).collect()
+    }
+
+    /// Every file grouped by its lowercased extension, i.e. whatever follows
+    /// the last `.` of its name, so `a.tar.gz` goes under `gz`. Files without
+    /// one (including names like `.gitignore` and `notes.`) go under the
+    /// empty string. Each group is in path order.
+    pub fn by_extension(&self) -> BTreeMap<String, Vec<&HashedFile>> {
+        let mut groups: BTreeMap<String, Vec<&HashedFile>> = BTreeMap::new();
+        for file in &self.files {
+            let extension = Utf8Path::new(&file.path).extension().unwrap_or_default();
+            groups
+                .entry(extension.to_lowercase())
+                .or_default()
+                .push(file);
+        }
+        groups
+    }
+
+    /// Extremes of the recorded paths, for catching files that might not
+    /// be restorable on a filesystem with stricter limits (e.g. Windows'
+    /// `MAX_PATH`). Ties go to whichever path comes first.
+    pub fn path_stats(&self) -> PathStats<'_> {
+        let mut stats = PathStats::default();
+        for file in &self.files {
+            let path = file.path.as_str();
+            if path.len() > stats.longest_path.len() {
+                stats.longest_path = path;
+            }
+            stats.max_depth = stats.max_depth.max(path.matches('/').count());
+            for component in path.split('/') {
+                if component.len() > stats.longest_component.len() {
+                    stats.longest_component = component;
+                }
+            }
+        }
+        stats
+    }
+
+    /// Everything but the per-file list, for compact exports
+    /// of directories too large to list every file of.
+    #[inline]
+    pub fn summary(&self) -> DirectorySummary<'_> {
+        DirectorySummary {
+            dir_name: &self.dir_name,
+            hash: self.hash,
+            file_count: self.files.len(),
+            size: self.size,
+        }
+    }
+}
+
+/// A summary of the results, followed by a table of every file
+/// (see `HashedFile`'s `Display`), one per line in path order.
+impl core::fmt::Display for HashedDirectory {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        writeln!(f, "Directory name: {}", self.dir_name)?;
+        writeln!(f, "Directory checksum: {}", self.hash.to_hex())?;
+        writeln!(f, "File count: {}", self.files.len())?;
+        writeln!(f, "Size in bytes: {}", self.size)?;
+        writeln!(f)?;
+        for file in &self.files {
+            writeln!(f, "{}", file)?;
+        }
+        Ok(())
+    }
+}
+
+/// `x - y`, clamped to what fits in an `i64`.
+#[inline]
+fn signed_delta(x: u64, y: u64) -> i64 {
+    let delta = x as i128 - y as i128;
+    delta.clamp(i64::MIN as i128, i64::MAX as i128) as i64
+}
+
+/// See `HashedDirectory::path_stats`. Lengths are in bytes of utf8,
+/// and everything is empty (or zero) when there aren't any files.
+#[derive(Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct PathStats<'a> {
+    /// The longest relative file path.
+    pub longest_path: &'a str,
+    /// Most folders any file is nested in, so zero when
+    /// every file is directly inside the hashed directory.
+    pub max_depth: usize,
+    /// The longest single file or folder name.
+    pub longest_component: &'a str,
+}
+
+/// A `HashedDirectory` without its files (see `HashedDirectory::summary`).
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct DirectorySummary<'a> {
+    pub dir_name: &'a str,
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_hash"))]
+    pub hash: Hash,
+    pub file_count: usize,
+    /// Same as `HashedDirectory::size`.
     pub size: u64,
 }
 
@@ -20,11 +244,71 @@ impl Deref for HashedDirectory {
 }
 
 /// TODO: docs
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct HashedFile {
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_hash"))]
     pub hash: Hash,
     pub path: String,
     /// Size of the hashed file, in bytes.
     pub size: u64,
+    /// When the file was last modified. Only recorded when
+    /// `HashOptions::record_mtime` or `HashOptions::subtree_stamps`
+    /// is set, and never part of any hash.
+    pub modified: Option<SystemTime>,
+    /// Whether the file was larger than `HashOptions::max_content_size`,
+    /// so only its size was recorded. `hash` is then just a stand-in
+    /// derived from the size, rather than a hash of the file's contents.
+    pub size_only: bool,
+}
+
+/// The file's path and hash, dropping everything else.
+impl From<HashedFile> for (String, Hash) {
+    #[inline]
+    fn from(file: HashedFile) -> Self {
+        (file.path, file.hash)
+    }
+}
+
+/// `HashedFile`s are compared **by path only**, ignoring their hash and size.
+/// Paths are unique within a directory, so this gives the same ordering
+/// used for computing directory hashes, and lets `sort()` or a `BTreeSet`
+/// be used directly.
+impl PartialEq for HashedFile {
+    #[inline]
+    fn eq(&self, other: &Self) -> bool {
+        self.path == other.path
+    }
+}
+
+impl Eq for HashedFile {}
+
+impl PartialOrd for HashedFile {
+    #[inline]
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for HashedFile {
+    #[inline]
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.path.cmp(&other.path)
+    }
+}
+
+/// The file's hash, size and path, with sizes padded so that consecutive
+/// files line up into columns for any file smaller than 10 TB.
+impl core::fmt::Display for HashedFile {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(
+            f,
+            "{}  {:>13}  {}",
+            self.hash.to_hex(),
+            self.size,
+            self.path
+        )
+    }
 }
 
 impl Deref for HashedFile {
@@ -35,3 +319,390 @@ impl Deref for HashedFile {
         &self.path
     }
 }
+
+/// A blake3 digest that can be used as a key in any kind of collection.
+///
+/// `blake3::Hash` is already usable as a `HashMap` key, but it doesn't
+/// implement `Ord`, so it can't go in a `BTreeMap` or be sorted. `Digest`
+/// compares, orders and hashes by the raw 32 bytes, so it works everywhere
+/// (e.g. `HashMap<Digest, Vec<String>>` for grouping duplicate files).
+///
+/// Comparisons are **not** constant time, so use `blake3::Hash`'s own `eq`
+/// whenever timing could leak anything.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Digest([u8; blake3::OUT_LEN]);
+
+impl Digest {
+    /// The raw bytes of the digest.
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8; blake3::OUT_LEN] {
+        &self.0
+    }
+}
+
+impl From<Hash> for Digest {
+    #[inline]
+    fn from(hash: Hash) -> Self {
+        Self(*hash.as_bytes())
+    }
+}
+
+impl From<Digest> for Hash {
+    #[inline]
+    fn from(digest: Digest) -> Self {
+        Hash::from_bytes(digest.0)
+    }
+}
+
+impl core::fmt::Debug for Digest {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_tuple("Digest")
+            .field(&Hash::from_bytes(self.0).to_hex())
+            .finish()
+    }
+}
+
+/// Hashes are exported as the same hex string used in hashfiles,
+/// rather than the raw byte array blake3 would give us.
+#[cfg(feature = "serde")]
+fn serialize_hash<S: serde::Serializer>(hash: &Hash, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(hash.to_hex().as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    fn file(path: &str, contents: &str) -> HashedFile {
+        HashedFile {
+            hash: blake3::hash(contents.as_bytes()),
+            path: path.to_string(),
+            size: contents.len() as u64,
+            modified: None,
+            size_only: false,
+        }
+    }
+
+    #[test]
+    fn hashed_files_compare_by_path_only() {
+        assert!(file("a.txt", "old") == file("a.txt", "new contents"));
+        assert!(file("a.txt", "same") != file("b.txt", "same"));
+
+        let mut files = [file("sub/b", "1"), file("a", "2"), file("sub/a", "3")];
+        files.sort();
+        let paths: Vec<_> = files.iter().map(|f| &*f.path).collect();
+        assert_eq!(paths, ["a", "sub/a", "sub/b"]);
+
+        // A set keeps whichever file with a given path went in first.
+        let mut set = BTreeSet::new();
+        assert!(set.insert(file("a", "first")));
+        assert!(!set.insert(file("a", "second")));
+        assert_eq!(set.len(), 1);
+        assert_eq!(set.first().unwrap().hash, blake3::hash(b"first"));
+    }
+
+    #[test]
+    fn digests_order_and_hash_by_their_bytes() {
+        use std::collections::{BTreeMap, HashMap};
+
+        let hashes = [blake3::hash(b"x"), blake3::hash(b"y"), blake3::hash(b"x")];
+        let digests: Vec<Digest> = hashes.iter().copied().map(Digest::from).collect();
+        assert_eq!(digests[0], digests[2]);
+        assert_ne!(digests[0], digests[1]);
+        assert_eq!(digests[0].as_bytes(), hashes[0].as_bytes());
+        assert_eq!(Hash::from(digests[1]), hashes[1]);
+        assert_eq!(
+            digests[0] < digests[1],
+            hashes[0].as_bytes() < hashes[1].as_bytes()
+        );
+
+        let mut counts: BTreeMap<Digest, usize> = BTreeMap::new();
+        let mut paths: HashMap<Digest, Vec<&str>> = HashMap::new();
+        for (digest, path) in digests.iter().zip(["a", "b", "c"]) {
+            *counts.entry(*digest).or_default() += 1;
+            paths.entry(*digest).or_default().push(path);
+        }
+        assert_eq!(counts[&digests[0]], 2);
+        assert_eq!(paths[&digests[0]], ["a", "c"]);
+        assert_eq!(
+            format!("{:?}", digests[1]),
+            format!("Digest({:?})", hashes[1].to_hex())
+        );
+    }
+
+    #[test]
+    fn structure_hash_only_changes_with_the_set_of_paths() {
+        use crate::hash_directory;
+        use crate::test_util::Scratch;
+
+        let scratch = Scratch::new();
+        scratch.write("tree/a.txt", "a");
+        scratch.write("tree/sub/b.txt", "b");
+        let original = hash_directory("tree").unwrap();
+        scratch.write("tree/a.txt", "edited");
+        let edited = hash_directory("tree").unwrap();
+        assert_ne!(edited.hash, original.hash);
+        assert_eq!(edited.structure_hash(), original.structure_hash());
+
+        scratch.write("tree/c.txt", "c");
+        let added = hash_directory("tree").unwrap();
+        assert_ne!(added.structure_hash(), original.structure_hash());
+        std::fs::remove_file("tree/c.txt").unwrap();
+        std::fs::rename("tree/a.txt", "tree/z.txt").unwrap();
+        let renamed = hash_directory("tree").unwrap();
+        assert_ne!(renamed.structure_hash(), original.structure_hash());
+        std::fs::rename("tree/z.txt", "tree/a.txt").unwrap();
+        assert_eq!(
+            hash_directory("tree").unwrap().structure_hash(),
+            original.structure_hash()
+        );
+
+        // Paths can't run into each other.
+        let mut joined = hash_directory("tree").unwrap();
+        joined.files = vec![file("ab", ""), file("c", "")];
+        let mut split = hash_directory("tree").unwrap();
+        split.files = vec![file("a", ""), file("bc", "")];
+        assert_ne!(joined.structure_hash(), split.structure_hash());
+    }
+
+    #[test]
+    fn totals_past_u64_max_are_pinned_and_flagged() {
+        use crate::DirectoryHasher;
+
+        let sized = |path: &str, size| HashedFile {
+            size,
+            ..file(path, "")
+        };
+        let mut hasher = DirectoryHasher::new("huge");
+        hasher.add_file(sized("sub/a", u64::MAX / 2 + 1));
+        hasher.add_file(sized("sub/b", u64::MAX / 2 + 1));
+        let huge = hasher.finalize();
+        assert_eq!(huge.size, u64::MAX);
+        assert!(huge.size_overflowed());
+        assert_eq!(huge.sizes_by_directory()["sub"], u64::MAX);
+        assert_eq!(
+            huge.allocated_size(NonZeroU64::new(4096).unwrap()),
+            u64::MAX
+        );
+
+        // Exactly u64::MAX fits, so it isn't an overflow.
+        let mut hasher = DirectoryHasher::new("full");
+        hasher.add_file(sized("a", u64::MAX - 1));
+        hasher.add_file(sized("b", 1));
+        let full = hasher.finalize();
+        assert_eq!(full.size, u64::MAX);
+        assert!(!full.size_overflowed());
+    }
+
+    #[test]
+    fn into_btree_supports_range_queries() {
+        use crate::test_util::Scratch;
+
+        let scratch = Scratch::new();
+        for path in [
+            "a.txt",
+            "dir/sub/x",
+            "dir/sub/y",
+            "dir/subway",
+            "dir/z",
+            "zz",
+        ] {
+            scratch.write(&format!("tree/{}", path), path);
+        }
+        let hashed_dir = crate::hash_directory("tree").unwrap();
+        let expected: Vec<(String, Hash)> = hashed_dir
+            .files
+            .iter()
+            .map(|file| (file.path.clone(), file.hash))
+            .collect();
+        let btree = hashed_dir.into_btree();
+        assert!(btree.iter().map(|(p, h)| (p.clone(), *h)).eq(expected));
+        let under_sub: Vec<&str> = btree
+            .range("dir/sub/".to_string()..)
+            .take_while(|(path, _)| path.starts_with("dir/sub/"))
+            .map(|(path, _)| path.as_str())
+            .collect();
+        assert_eq!(under_sub, ["dir/sub/x", "dir/sub/y"]);
+        assert_eq!(btree["dir/z"], blake3::hash(b"dir/z"));
+    }
+
+    #[test]
+    fn sizes_by_directory_adds_up_every_ancestor() {
+        use crate::DirectoryHasher;
+
+        let mut hasher = DirectoryHasher::new("tree");
+        for (path, contents) in [
+            ("top.txt", "1"),
+            ("sub/a.txt", "22"),
+            ("sub/deep/er/b.txt", "333"),
+            ("sub/deep/c.txt", "4444"),
+            ("subway/d.txt", "55555"),
+        ] {
+            hasher.add_file(file(path, contents));
+        }
+        let sizes = hasher.finalize().sizes_by_directory();
+        let expected = BTreeMap::from([
+            (String::new(), 15),
+            ("sub".to_string(), 9),
+            ("sub/deep".to_string(), 7),
+            ("sub/deep/er".to_string(), 3),
+            ("subway".to_string(), 5),
+        ]);
+        assert_eq!(sizes, expected);
+
+        let empty = DirectoryHasher::new("empty").finalize();
+        assert!(empty.sizes_by_directory().is_empty());
+    }
+
+    #[test]
+    fn path_stats_finds_the_extremes() {
+        use crate::DirectoryHasher;
+
+        let mut hasher = DirectoryHasher::new("tree");
+        for path in [
+            "a/b/c/d.txt",
+            "aaaaaaaaaaa",
+            "top-level-name.txt",
+            // Nine bytes, but only five characters.
+            "x/\u{e9}\u{e9}\u{e9}\u{e9}y",
+            "y/z/1.txt",
+        ] {
+            hasher.add_file(file(path, ""));
+        }
+        let hashed = hasher.finalize();
+        let stats = hashed.path_stats();
+        assert_eq!(stats.longest_path, "top-level-name.txt");
+        assert_eq!(stats.longest_component, "top-level-name.txt");
+        assert_eq!(stats.max_depth, 3);
+
+        // Ties go to the first path, and lengths are in bytes.
+        let mut hasher = DirectoryHasher::new("ties");
+        for path in ["b/aaaaaaaa", "a/\u{e9}\u{e9}\u{e9}\u{e9}", "c/ccccccc"] {
+            hasher.add_file(file(path, ""));
+        }
+        let hashed = hasher.finalize();
+        let stats = hashed.path_stats();
+        assert_eq!(stats.longest_path, "a/\u{e9}\u{e9}\u{e9}\u{e9}");
+        assert_eq!(stats.longest_component, "\u{e9}\u{e9}\u{e9}\u{e9}");
+        assert_eq!(stats.max_depth, 1);
+
+        let empty = DirectoryHasher::new("empty").finalize();
+        let stats = empty.path_stats();
+        assert_eq!((stats.longest_path, stats.longest_component), ("", ""));
+        assert_eq!(stats.max_depth, 0);
+    }
+
+    #[test]
+    fn by_extension_groups_on_the_last_lowercased_extension() {
+        use crate::DirectoryHasher;
+
+        let mut hasher = DirectoryHasher::new("tree");
+        for path in [
+            "a.tar.gz",
+            "b.GZ",
+            "docs/readme.md",
+            "docs/v1.2/notes",
+            ".gitignore",
+            "notes.",
+            "Makefile",
+            "src/main.rs",
+            "src/lib.Rs",
+        ] {
+            hasher.add_file(file(path, ""));
+        }
+        let hashed = hasher.finalize();
+        let by_extension = hashed.by_extension();
+        let groups: BTreeMap<&str, Vec<&str>> = by_extension
+            .iter()
+            .map(|(extension, files)| {
+                let paths = files.iter().map(|file| file.path.as_str()).collect();
+                (extension.as_str(), paths)
+            })
+            .collect();
+        let expected = BTreeMap::from([
+            (
+                "",
+                vec![".gitignore", "Makefile", "docs/v1.2/notes", "notes."],
+            ),
+            ("gz", vec!["a.tar.gz", "b.GZ"]),
+            ("md", vec!["docs/readme.md"]),
+            ("rs", vec!["src/lib.Rs", "src/main.rs"]),
+        ]);
+        assert_eq!(groups, expected);
+        let total: usize = by_extension.values().map(Vec::len).sum();
+        assert_eq!(total, hashed.len());
+    }
+
+    #[test]
+    fn allocated_size_rounds_every_file_up_to_whole_blocks() {
+        use crate::DirectoryHasher;
+
+        let mut hasher = DirectoryHasher::new("tree");
+        for (path, size) in [("empty", 0), ("one", 1), ("exact", 4096), ("over", 4097)] {
+            hasher.add_file(HashedFile {
+                size,
+                ..file(path, "")
+            });
+        }
+        let hashed = hasher.finalize();
+        let blocks = |size| hashed.allocated_size(NonZeroU64::new(size).unwrap());
+        assert_eq!(blocks(4096), 4096 * 4);
+        assert_eq!(blocks(512), 512 * (1 + 8 + 9));
+        assert_eq!(blocks(1), hashed.size);
+        assert_eq!(blocks(u64::MAX), u64::MAX);
+    }
+
+    #[test]
+    fn deltas_are_signed_and_saturate() {
+        use crate::DirectoryHasher;
+
+        let dir = |sizes: &[u64]| {
+            let mut hasher = DirectoryHasher::new("tree");
+            for (i, &size) in sizes.iter().enumerate() {
+                hasher.add_file(HashedFile {
+                    size,
+                    ..file(&i.to_string(), "")
+                });
+            }
+            hasher.finalize()
+        };
+        let old = dir(&[10, 20]);
+        let new = dir(&[10, 20, 5]);
+        assert_eq!(new.size_delta(&old), 5);
+        assert_eq!(old.size_delta(&new), -5);
+        assert_eq!(new.file_count_delta(&old), 1);
+        assert_eq!(old.file_count_delta(&new), -1);
+        assert_eq!((old.size_delta(&old), old.file_count_delta(&old)), (0, 0));
+
+        let huge = dir(&[u64::MAX]);
+        let empty = dir(&[]);
+        assert_eq!(huge.size_delta(&empty), i64::MAX);
+        assert_eq!(empty.size_delta(&huge), i64::MIN);
+        assert_eq!(signed_delta(u64::MAX, u64::MAX - 1), 1);
+    }
+
+    #[test]
+    fn unique_hashes_lists_duplicate_contents_once() {
+        use crate::DirectoryHasher;
+
+        let mut hasher = DirectoryHasher::new("tree");
+        for (path, contents) in [
+            ("a.txt", "dup"),
+            ("b.txt", "one"),
+            ("c/a.txt", "dup"),
+            ("d.txt", "dup"),
+            ("e.txt", "two"),
+        ] {
+            hasher.add_file(file(path, contents));
+        }
+        hasher.add_file(HashedFile {
+            size_only: true,
+            ..file("huge.bin", "stand-in")
+        });
+        let unique = hasher.finalize().unique_hashes();
+        let mut expected = ["dup", "one", "two"].map(|contents| blake3::hash(contents.as_bytes()));
+        expected.sort_unstable_by_key(|hash| *hash.as_bytes());
+        assert_eq!(unique, expected);
+    }
+}
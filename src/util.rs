@@ -1,18 +1,33 @@
-use crate::fs::get_files;
+use crate::budget::MemoryBudget;
+use crate::fs::{get_entries, get_entries_parallel, get_files, Entries, EntryFilter};
+use crate::hashfile::{count_files_per_dir, subtree_stamps, Header, Record, SIZE_ONLY_PREFIX};
+use crate::options::{HashMode, HashOptions, SpecialFiles};
+use crate::progress::Progress;
+use crate::report::CountMismatch;
+use crate::throttle::Throttle;
 use crate::types::HashedFile;
 use crate::IOResult;
 use blake3::{Hash, Hasher};
-use camino::Utf8Path;
+use camino::{Utf8Path, Utf8PathBuf};
 use rayon::prelude::*;
-use std::io::{Error, ErrorKind};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::{File, Metadata};
+use std::io::{Error, ErrorKind, Read, Write};
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
 
 const DELIM: char = ' ';
-const NEWLINE: char = '\n';
 const REPLACEMENT: char = '/';
 const WINDOWS_MOMENT: char = '\\';
 
 /// Builds a `Vec` by hashing all visible files beneath `dir_path`.
 /// The returned `Vec` is always sorted by file path.
+/// Also returns the (sorted, relative) paths of whatever had to be left out
+/// (see `Skipped`).
 ///
 /// There are multiple to approach this. The most naive approach
 /// (the first thing I tried lol) is to iterate sequentially over the
@@ -45,45 +60,1017 @@ const WINDOWS_MOMENT: char = '\\';
 /// speed on directories with a mix of very large/small files. Even more
 /// so if I could avoid performance regressions with directories almost
 /// exclusively containing smaller files.
-pub fn hash_files(dir_path: &str) -> IOResult<Vec<HashedFile>> {
+pub fn hash_files(dir_path: &str, options: &HashOptions) -> IOResult<(Vec<HashedFile>, Skipped)> {
+    hash_files_salted(dir_path, options, None)
+}
+
+/// Everything `hash_files` left out, as sorted paths relative to the hashed
+/// directory (see `HashedDirectory::skipped` and `unreadable_dirs`).
+#[derive(Default)]
+pub struct Skipped {
+    /// Special files, because of `HashOptions::special_files`.
+    pub special: Vec<String>,
+    /// Folders that couldn't be read, with `HashOptions::skip_unreadable_dirs`.
+    pub unreadable_dirs: Vec<String>,
+}
+
+/// Identical to `hash_files`, but with `salt` (if any) fed into the
+/// hasher ahead of every file's contents (see `HashOptions::salted`).
+pub fn hash_files_salted(
+    dir_path: &str,
+    options: &HashOptions,
+    salt: Option<&[u8; 32]>,
+) -> IOResult<(Vec<HashedFile>, Skipped)> {
+    let mut hashed_files: Vec<HashedFile> = Vec::new();
+    let mut resorted = false;
+    let skipped = hash_files_chunked(dir_path, options, salt, usize::MAX, |chunk| {
+        match hashed_files.is_empty() {
+            true => hashed_files = chunk,
+            // Only symlinked files outside the directory ever come
+            // in a second chunk (see resolve_symlinks).
+            false => {
+                hashed_files.extend(chunk);
+                resorted = true;
+            }
+        }
+    })?;
+    if resorted {
+        hashed_files.sort_unstable();
+    }
+    Ok((hashed_files, skipped))
+}
+
+/// Identical to `hash_files`, but handing the sorted results to `on_chunk`
+/// at most `chunk_len` files at a time (in order), so they never all have
+/// to be held in memory at once. Each chunk is hashed in parallel, but
+/// chunks are hashed one after another.
+///
+/// With `HashOptions::resolve_symlinks`, files linked to from outside
+/// `dir_path` are handed over last, in a sorted chunk of their own.
+pub fn hash_files_chunked<F>(
+    dir_path: &str,
+    options: &HashOptions,
+    salt: Option<&[u8; 32]>,
+    chunk_len: usize,
+    mut on_chunk: F,
+) -> IOResult<Skipped>
+where
+    F: FnMut(Vec<HashedFile>),
+{
     // One more than the actual length because we don't want
     // stripped file paths to start with a slash.
     // Both slash types are just ascii (a single byte in utf8),
     // so this still lands on a valid utf8 boundary.
     let prefix_len = dir_path.len() + 1;
 
-    let mut file_list = get_files(dir_path.into())?;
+    let entries = match options.parallel_traversal {
+        true => get_entries_parallel(
+            dir_path.into(),
+            options.file_capacity_hint,
+            EntryFilter::new(options),
+        )?,
+        false => get_entries(
+            dir_path.into(),
+            options.file_capacity_hint,
+            EntryFilter::new(options),
+        )?,
+    };
+    if options.regular_files_only {
+        check_regular_only(&entries)?;
+    }
+    let mut file_list = entries.files;
+    let mut external = Vec::new();
+    if options.resolve_symlinks {
+        external = resolve_symlinks(dir_path, entries.symlinks, &mut file_list)?;
+    }
     file_list.sort_unstable();
+    file_list.dedup();
+    let mut special_list = entries.special;
+    special_list.sort_unstable();
+    #[inline(always)]
+    fn stripped(file_path: &Utf8Path, prefix_len: usize) -> &str {
+        // SAFETY: Since all files are descendants of dir_path,
+        // they all have dir_path as a prefix.
+        unsafe { file_path.as_str().get_unchecked(prefix_len..) }
+    }
+
+    let mut skipped = Skipped {
+        unreadable_dirs: entries
+            .unreadable
+            .iter()
+            .map(|path| oi_vei(stripped(path, prefix_len)))
+            .collect(),
+        ..Default::default()
+    };
+    skipped.unreadable_dirs.sort_unstable();
+    match options.special_files {
+        SpecialFiles::Skip => {
+            skipped.special = special_list
+                .iter()
+                .map(|path| oi_vei(stripped(path, prefix_len)))
+                .collect();
+            special_list.clear();
+        }
+        SpecialFiles::Error => {
+            if let Some(path) = special_list.first() {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    format!("Found special file '{}', which can't be hashed.", path),
+                ));
+            }
+        }
+        SpecialFiles::Read { .. } => {}
+    }
+    if options.detect_case_collisions {
+        check_case_collisions(
+            file_list
+                .iter()
+                .chain(&special_list)
+                .map(|path| stripped(path, prefix_len)),
+        )?;
+    }
+    if !special_list.is_empty() {
+        // Only ever non-empty when special files are being read,
+        // so the usual case doesn't pay for this.
+        file_list.extend(special_list.iter().cloned());
+        file_list.sort_unstable();
+    }
+    // Chunks have to cover contiguous ranges of paths, so breadth-first
+    // order only works when everything is hashed in a single chunk.
+    let breadth_first = options.breadth_first && chunk_len >= file_list.len();
+    if breadth_first {
+        // Stable, so files at the same depth stay in path order.
+        file_list.sort_by_cached_key(|path| path.components().count());
+    }
+    let throttle = options.max_bytes_per_sec.map(Throttle::new);
+    let budget = options.max_in_flight_bytes.map(MemoryBudget::new);
+    let progress = options
+        .progress
+        .as_ref()
+        .map(|callback| Progress::new(callback, &file_list));
+
+    let hash_one = |file_path: &Utf8PathBuf| {
+        if let Some(control) = &options.control {
+            control.checkpoint()?;
+        }
+        // Stat'ed at most once, however many things need the file's
+        // metadata, since stat isn't cheap on network filesystems.
+        let metadata = match budget.is_some() {
+            true => Some(stat(file_path)?),
+            false => None,
+        };
+        let reserved = match (&budget, &metadata) {
+            (Some(budget), Some(metadata)) => Some(budget.acquire(metadata.len())),
+            _ => None,
+        };
+        let file = match options.special_files {
+            SpecialFiles::Read { timeout } if special_list.binary_search(file_path).is_ok() => {
+                let stripped = stripped(file_path, prefix_len);
+                hash_special_file(file_path, stripped, options, salt, timeout)?
+            }
+            _ => {
+                let stripped = stripped(file_path, prefix_len);
+                hash_file_with_metadata(file_path, stripped, options, salt, metadata)?
+            }
+        };
+        // The file's no longer mapped, so there's no need
+        // to hold onto the budget while being throttled.
+        drop(reserved);
+        if let Some(throttle) = &throttle {
+            throttle.consume(file.size);
+        }
+        if let Some(progress) = &progress {
+            progress.file_done(file.size);
+        }
+        Ok(file)
+    };
+    for chunk in file_list.chunks(chunk_len) {
+        let mut hashed = match options.deterministic_scheduling || breadth_first {
+            true => hash_in_order(chunk, hash_one),
+            false => chunk.par_iter().map(&hash_one).collect(),
+        }?;
+        if breadth_first {
+            hashed.sort_unstable();
+        }
+        #[cfg(feature = "unicode")]
+        if options.normalize_unicode {
+            resort_normalized(&mut hashed)?;
+        }
+        on_chunk(hashed);
+    }
+    for chunk in external.chunks(chunk_len) {
+        #[allow(unused_mut)]
+        let mut hashed: Vec<_> = chunk
+            .par_iter()
+            .map(|file_path| hash_file(file_path, file_path.as_str(), options, salt))
+            .collect::<IOResult<_>>()?;
+        #[cfg(feature = "unicode")]
+        if options.normalize_unicode {
+            resort_normalized(&mut hashed)?;
+        }
+        on_chunk(hashed);
+    }
+    Ok(skipped)
+}
+
+/// The path a file at relative `stripped_file_path` is recorded under:
+/// always `/`-separated, and NFC normalized with `HashOptions::normalize_unicode`.
+#[inline]
+fn record_path(stripped_file_path: &str, options: &HashOptions) -> String {
+    #[cfg(feature = "unicode")]
+    if options.normalize_unicode {
+        use unicode_normalization::{is_nfc, UnicodeNormalization};
+        // Almost every path already is, so that's checked without allocating.
+        if !is_nfc(stripped_file_path) {
+            return oi_vei(&stripped_file_path.nfc().collect::<String>());
+        }
+    }
+    #[cfg(not(feature = "unicode"))]
+    let _ = options;
+    oi_vei(stripped_file_path)
+}
+
+/// On-disk paths (relative to `dir_path`) of the files beneath it that are
+/// recorded under a different, normalized path, keyed by that recorded path.
+/// Always empty unless `HashOptions::normalize_unicode` is set.
+fn denormalized_paths(dir_path: &str, options: &HashOptions) -> IOResult<HashMap<String, String>> {
+    #[cfg(feature = "unicode")]
+    if options.normalize_unicode {
+        // See hash_files() for why this is one longer than dir_path.
+        let prefix_len = dir_path.len() + 1;
+        return Ok(get_files(dir_path.into(), EntryFilter::new(options))?
+            .iter()
+            .filter_map(|file_path| {
+                let on_disk = oi_vei(&file_path.as_str()[prefix_len..]);
+                let recorded = record_path(&on_disk, options);
+                (recorded != on_disk).then_some((recorded, on_disk))
+            })
+            .collect());
+    }
+    #[cfg(not(feature = "unicode"))]
+    let _ = (dir_path, options);
+    Ok(HashMap::new())
+}
+
+/// Normalizing can change how paths sort, so `hashed_files` (sorted by
+/// their on-disk paths) are sorted again once their paths are normalized.
+/// Fails with `ErrorKind::InvalidData` if two of them normalized to the
+/// same path, since they couldn't be told apart anymore.
+#[cfg(feature = "unicode")]
+fn resort_normalized(hashed_files: &mut [HashedFile]) -> IOResult<()> {
+    hashed_files.sort_unstable();
+    match hashed_files.windows(2).find(|pair| pair[0] == pair[1]) {
+        Some(pair) => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "Found multiple files named '{}' once unicode normalized.",
+                pair[0].path
+            ),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Fails with `ErrorKind::Unsupported` if `entries` has any symlinks or
+/// special files, naming whichever comes first by path.
+fn check_regular_only(entries: &Entries) -> IOResult<()> {
+    let symlinks = entries.symlinks.iter().map(|path| (path, "symlink"));
+    let special = entries.special.iter().map(|path| (path, "special file"));
+    match symlinks.chain(special).min() {
+        Some((path, kind)) => Err(Error::new(
+            ErrorKind::Unsupported,
+            format!(
+                "Found {} '{}', but only regular files are allowed.",
+                kind, path
+            ),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Identical to `hash_files`, but without sorting anything, so the
+/// files come back in whatever order the OS listed them in. Special
+/// files are always skipped.
+pub fn hash_files_unsorted(dir_path: &str, options: &HashOptions) -> IOResult<Vec<HashedFile>> {
+    // Same as in hash_files_chunked, we don't want a leading slash.
+    let prefix_len = dir_path.len() + 1;
+    get_files(dir_path.into(), EntryFilter::new(options))?
+        .par_iter()
+        .map(|file_path| hash_file(file_path, &file_path.as_str()[prefix_len..], options, None))
+        .collect()
+}
+
+/// Identical to `hash_files_unsorted`, but sending each file through
+/// `sender` as soon as it's hashed, rather than collecting them. Failing to
+/// list the files is sent as the only result. Gives up on the remaining
+/// files once nobody's receiving anymore.
+pub fn send_hashed_files(
+    dir_path: &str,
+    options: &HashOptions,
+    sender: mpsc::Sender<IOResult<HashedFile>>,
+) {
+    let prefix_len = dir_path.len() + 1;
+    let file_list = match get_files(dir_path.into(), EntryFilter::new(options)) {
+        Ok(file_list) => file_list,
+        Err(e) => {
+            let _ = sender.send(Err(e));
+            return;
+        }
+    };
+    let _ = file_list
+        .par_iter()
+        .try_for_each_with(sender, |sender, file_path| {
+            let stripped = &file_path.as_str()[prefix_len..];
+            sender.send(hash_file(file_path, stripped, options, None))
+        });
+}
+
+/// Resolves every one of `symlinks` to the real file it points at,
+/// pushing the ones inside `dir_path` onto `file_list` (under `dir_path`,
+/// so they may now be listed twice) and returning the others, sorted and
+/// deduplicated by their absolute real path. Links to anything other than
+/// a file, or that don't resolve at all, are ignored.
+fn resolve_symlinks(
+    dir_path: &str,
+    symlinks: Vec<Utf8PathBuf>,
+    file_list: &mut Vec<Utf8PathBuf>,
+) -> IOResult<Vec<Utf8PathBuf>> {
+    let real_dir = Utf8Path::new(dir_path).canonicalize_utf8()?;
+    let mut external = Vec::new();
+    for link in symlinks {
+        let Ok(target) = link.canonicalize_utf8() else {
+            continue;
+        };
+        if !target.is_file() {
+            continue;
+        }
+        match target.strip_prefix(&real_dir) {
+            Ok(relative) => file_list.push(Utf8Path::new(dir_path).join(relative)),
+            Err(_) => external.push(target),
+        }
+    }
+    external.sort_unstable();
+    external.dedup();
+    Ok(external)
+}
 
+/// Identical to `hash_files`, but hashing exactly the files at `paths`
+/// (relative to `dir_path`) instead of walking the directory tree.
+/// Listed paths are hashed even if they're hidden, and listing the
+/// same path more than once only hashes it once.
+pub fn hash_listed_files(
+    dir_path: &str,
+    paths: &[&str],
+    options: &HashOptions,
+) -> IOResult<Vec<HashedFile>> {
+    let mut file_list: Vec<String> = paths.iter().map(|path| oi_vei(path)).collect();
+    file_list.sort_unstable();
+    file_list.dedup();
+    let dir_path = Utf8Path::new(dir_path);
     file_list
+        .par_iter()
+        .map(|path| hash_file(&dir_path.join(path), path, options, None))
+        .collect()
+}
+
+/// Fails with `ErrorKind::InvalidData` if any of `paths` differ only by case.
+fn check_case_collisions<'a, I>(paths: I) -> IOResult<()>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let mut by_lowercase: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for path in paths {
+        let path = oi_vei(path);
+        by_lowercase
+            .entry(path.to_lowercase())
+            .or_default()
+            .push(path);
+    }
+    let collisions: Vec<String> = by_lowercase
+        .into_values()
+        .filter(|paths| paths.len() > 1)
+        .map(|paths| paths.join("', '"))
+        .collect();
+    match collisions.is_empty() {
+        true => Ok(()),
+        false => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "Paths collide when case is ignored: ['{}'].",
+                collisions.join("'], ['")
+            ),
+        )),
+    }
+}
+
+/// Runs `hash_one` over every file in `file_list` in parallel, but
+/// with files always being picked up in order, a fixed-size chunk at a
+/// time, rather than whatever order rayon's work-stealing ends up with.
+/// The output is in the same order as `file_list`.
+///
+/// This only makes scheduling reproducible (for profiling traces and such),
+/// or has shallower files hashed first with `HashOptions::breadth_first`.
+fn hash_in_order<F>(file_list: &[Utf8PathBuf], hash_one: F) -> IOResult<Vec<HashedFile>>
+where
+    F: Fn(&Utf8PathBuf) -> IOResult<HashedFile> + Sync,
+{
+    const CHUNK_LEN: usize = 16;
+    let next_chunk = AtomicUsize::new(0);
+    let mut hashed_files: Vec<(usize, HashedFile)> = (0..rayon::current_num_threads())
         .into_par_iter()
-        .map(|file_path| {
-            // Using memory mapping is more-or-less mandatory here. If we
-            // were to instead use regular update() we'd need to explicitly
-            // load each file into memory and pass a reference to that buffer.
-            // Since we're running all these file hashes in parallel, any
-            // folder containing enough large files to exceed available RAM will
-            // quickly do so, making the system extremely unresponsive.
-            // Memory mapping uses cached/standby memory, which allows other
-            // running programs that have explicitly allocated memory
-            // to maintain priority.
-            let mut hasher = Hasher::new();
-            hasher.update_mmap(file_path.as_std_path())?;
-            // SAFETY: Since all files are descendants of dir_path,
-            // they all have dir_path as a prefix.
-            let stripped_file_path = unsafe { file_path.as_str().get_unchecked(prefix_len..) };
-            Ok(HashedFile {
-                hash: hasher.finalize(),
-                path: oi_vei(stripped_file_path),
-                size: hasher.count(),
-            })
+        .map(|_| {
+            let mut claimed = Vec::new();
+            loop {
+                let start = next_chunk.fetch_add(CHUNK_LEN, Ordering::Relaxed);
+                if start >= file_list.len() {
+                    return Ok(claimed);
+                }
+                let end = file_list.len().min(start + CHUNK_LEN);
+                for (i, file_path) in file_list[start..end].iter().enumerate() {
+                    claimed.push((start + i, hash_one(file_path)?));
+                }
+            }
         })
-        .collect()
+        .collect::<IOResult<Vec<Vec<_>>>>()?
+        .into_iter()
+        .flatten()
+        .collect();
+    hashed_files.sort_unstable_by_key(|(i, _)| *i);
+    Ok(hashed_files.into_iter().map(|(_, file)| file).collect())
+}
+
+/// Hashes the single file at `file_path`, recording it under `stripped_file_path`
+/// (its path relative to the hashed directory). `salt` (if any) is fed
+/// into the hasher ahead of the file's contents.
+#[inline]
+pub fn hash_file(
+    file_path: &Utf8Path,
+    stripped_file_path: &str,
+    options: &HashOptions,
+    salt: Option<&[u8; 32]>,
+) -> IOResult<HashedFile> {
+    hash_file_with_metadata(file_path, stripped_file_path, options, salt, None)
+}
+
+/// Identical to `hash_file`, but reusing `metadata` (if the caller already
+/// has it) instead of stat'ing the file again.
+#[inline]
+fn hash_file_with_metadata(
+    file_path: &Utf8Path,
+    stripped_file_path: &str,
+    options: &HashOptions,
+    salt: Option<&[u8; 32]>,
+    metadata: Option<Metadata>,
+) -> IOResult<HashedFile> {
+    // Using memory mapping is more-or-less mandatory here. If we
+    // were to instead use regular update() we'd need to explicitly
+    // load each file into memory and pass a reference to that buffer.
+    // Since we're running all these file hashes in parallel, any
+    // folder containing enough large files to exceed available RAM will
+    // quickly do so, making the system extremely unresponsive.
+    // Memory mapping uses cached/standby memory, which allows other
+    // running programs that have explicitly allocated memory
+    // to maintain priority.
+    let wants_mtime = options.record_mtime || options.subtree_stamps;
+    let metadata = match metadata {
+        Some(metadata) => Some(metadata),
+        None if wants_mtime
+            || options.max_content_size.is_some()
+            || contents_need_metadata(options) =>
+        {
+            Some(stat(file_path)?)
+        }
+        None => None,
+    };
+    let modified = match wants_mtime {
+        true => metadata.as_ref().map(|meta| meta.modified()).transpose()?,
+        false => None,
+    };
+    if let (Some(max), Some(meta)) = (options.max_content_size, &metadata) {
+        if meta.len() > max {
+            return Ok(HashedFile {
+                hash: size_only_hash(meta.len()),
+                path: record_path(stripped_file_path, options),
+                size: meta.len(),
+                modified,
+                size_only: true,
+            });
+        }
+    }
+    let (hash, size) = hash_contents(file_path, options, salt, metadata.as_ref())?;
+    Ok(HashedFile {
+        hash,
+        path: record_path(stripped_file_path, options),
+        size,
+        modified,
+        size_only: false,
+    })
+}
+
+/// Hashes the contents of the regular file at `file_path` in the mode
+/// `options` says (salted with `salt`), returning the hash and the size
+/// of the file. The hash covers the file's owner too with
+/// `HashOptions::include_ownership`.
+///
+/// Callers that already stat'ed the file pass its `metadata`, which is then
+/// used for everything (see `contents_need_metadata`) instead of another stat.
+#[inline]
+fn hash_contents(
+    file_path: &Utf8Path,
+    options: &HashOptions,
+    salt: Option<&[u8; 32]>,
+    metadata: Option<&Metadata>,
+) -> IOResult<(Hash, u64)> {
+    let fetched;
+    let metadata = match metadata {
+        Some(metadata) => Some(metadata),
+        None if contents_need_metadata(options) => {
+            fetched = stat(file_path)?;
+            Some(&fetched)
+        }
+        None => None,
+    };
+    let (hash, size) = hash_only_contents(file_path, options, salt, metadata)?;
+    match (options.include_ownership, metadata) {
+        (true, Some(metadata)) => Ok((with_ownership(hash, metadata)?, size)),
+        _ => Ok((hash, size)),
+    }
+}
+
+/// Whether `hash_contents` needs the file's metadata
+/// for any of `options`, beyond its contents.
+#[inline]
+fn contents_need_metadata(options: &HashOptions) -> bool {
+    options.mmap_min_size > 0 || options.mmap_max_size.is_some() || options.include_ownership
+}
+
+/// Binds the owning user and group from a file's `metadata` to its
+/// content `hash` (see `HashOptions::include_ownership`).
+#[cfg(unix)]
+fn with_ownership(hash: Hash, metadata: &Metadata) -> IOResult<Hash> {
+    use std::os::unix::fs::MetadataExt;
+    // Domain separated, so the result can't collide with any content hash.
+    let mut hasher = Hasher::new_derive_key("b3hash v1 ownership");
+    hasher.update(hash.as_bytes());
+    hasher.update(&metadata.uid().to_le_bytes());
+    hasher.update(&metadata.gid().to_le_bytes());
+    Ok(hasher.finalize())
+}
+
+#[cfg(not(unix))]
+fn with_ownership(_: Hash, _: &Metadata) -> IOResult<Hash> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "Ownership can only be hashed on Unix.",
+    ))
+}
+
+/// Number of regular files whose contents have been hashed, so tests can
+/// check what was skipped. Every test that hashes holds a `Scratch`, which
+/// keeps them from running at the same time and muddling the count.
+#[cfg(test)]
+pub static CONTENTS_HASHED: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of times a file being hashed has been stat'ed, so tests can
+/// check nothing gets stat'ed twice. Counted the same way as `CONTENTS_HASHED`.
+#[cfg(test)]
+pub static FILES_STATED: AtomicUsize = AtomicUsize::new(0);
+
+/// Stats the file at `file_path` (following symlinks), for hashing it.
+#[inline]
+pub fn stat(file_path: &Utf8Path) -> IOResult<Metadata> {
+    #[cfg(test)]
+    FILES_STATED.fetch_add(1, Ordering::Relaxed);
+    file_path.metadata()
+}
+
+/// Identical to `hash_contents`, but never covering the file's owner.
+/// `metadata` is only ever `None` when `contents_need_metadata` isn't set.
+#[inline]
+fn hash_only_contents(
+    file_path: &Utf8Path,
+    options: &HashOptions,
+    salt: Option<&[u8; 32]>,
+    metadata: Option<&Metadata>,
+) -> IOResult<(Hash, u64)> {
+    #[cfg(test)]
+    CONTENTS_HASHED.fetch_add(1, Ordering::Relaxed);
+    let mut hasher = salted_hasher(&options.mode, salt);
+    #[cfg(feature = "decompress")]
+    if options.decompress {
+        if let Some(size) = hash_decompressed(&mut hasher, file_path, options, metadata)? {
+            return Ok((hasher.finalize(), size));
+        }
+    }
+    match &options.content_transform {
+        // Transforms need the whole file as a slice (and produce a new one),
+        // so there's no mmap here. Files are read into memory one at a time
+        // per thread, which is the price of the transform being so general.
+        Some(transform) => {
+            let contents = std::fs::read(file_path)?;
+            hasher.update(&transform(&contents));
+            Ok((hasher.finalize(), contents.len() as u64))
+        }
+        None => {
+            let size = metadata.map_or(0, Metadata::len);
+            if size < options.mmap_min_size {
+                let mut file = File::open(file_path)?;
+                update_reader(&mut hasher, &mut file, read_buffer_size(options))?;
+            } else if options.mmap_max_size.is_some_and(|max| size > max) {
+                hasher.update_mmap_rayon(file_path.as_std_path())?;
+            } else {
+                // The file is opened, mapped, hashed, then unmapped and closed
+                // all within this call, so nothing outlives a single file and
+                // there's no address space or fd buildup in long-running processes.
+                hasher.update_mmap(file_path.as_std_path())?;
+            }
+            Ok((hasher.finalize(), content_len(&hasher, salt)))
+        }
+    }
+}
+
+/// A `Hasher` for `mode` that's already been fed `salt`, if there is one.
+///
+/// The salt is hashed like any other content, so `Hasher::count` includes
+/// it, which `content_len` takes back out.
+#[inline]
+fn salted_hasher(mode: &HashMode, salt: Option<&[u8; 32]>) -> Hasher {
+    let mut hasher = mode.hasher();
+    if let Some(salt) = salt {
+        hasher.update(salt);
+    }
+    hasher
+}
+
+/// A fresh salt for `HashOptions::salted`, straight from the OS's randomness.
+///
+/// The salt is recorded in the clear, so it only has to be unique,
+/// not secret, but that uniqueness has to hold across machines and
+/// processes, which nothing short of the OS's randomness guarantees.
+pub fn random_salt() -> IOResult<[u8; 32]> {
+    let mut salt = [0; 32];
+    getrandom::fill(&mut salt)?;
+    Ok(salt)
+}
+
+/// Number of bytes of actual file content fed into `hasher`, which was
+/// created by `salted_hasher` with `salt`.
+#[inline(always)]
+fn content_len(hasher: &Hasher, salt: Option<&[u8; 32]>) -> u64 {
+    hasher.count() - salt.map_or(0, |salt| salt.len() as u64)
+}
+
+/// Feeds the decompressed contents of the gzip or zstd compressed file at
+/// `file_path` into `hasher`, returning the (compressed) size of the file,
+/// which comes from `metadata` when the caller already stat'ed it.
+/// Returns `None` without touching `hasher` if the file isn't compressed.
+///
+/// Compression is detected by the magic bytes at the start of the file,
+/// since extensions are too easy to get wrong in either direction.
+#[cfg(feature = "decompress")]
+fn hash_decompressed(
+    hasher: &mut Hasher,
+    file_path: &Utf8Path,
+    options: &HashOptions,
+    metadata: Option<&Metadata>,
+) -> IOResult<Option<u64>> {
+    use std::io::{Seek, SeekFrom};
+    const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+    const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+    let mut file = File::open(file_path)?;
+    let mut magic = Vec::with_capacity(ZSTD_MAGIC.len());
+    (&mut file)
+        .take(ZSTD_MAGIC.len() as u64)
+        .read_to_end(&mut magic)?;
+    file.seek(SeekFrom::Start(0))?;
+    let size = match metadata {
+        Some(metadata) => metadata.len(),
+        None => {
+            #[cfg(test)]
+            FILES_STATED.fetch_add(1, Ordering::Relaxed);
+            file.metadata()?.len()
+        }
+    };
+    // Concatenated gzip members (as written by e.g. `pigz` or `cat a.gz
+    // b.gz`) decompress to the concatenated contents, same as `gunzip`.
+    let buffer_size = read_buffer_size(options);
+    if magic.starts_with(&GZIP_MAGIC) {
+        let mut decoder = flate2::read::MultiGzDecoder::new(file);
+        update_reader(hasher, &mut decoder, buffer_size)?;
+    } else if magic.starts_with(&ZSTD_MAGIC) {
+        let mut decoder = zstd::stream::read::Decoder::new(file)?;
+        update_reader(hasher, &mut decoder, buffer_size)?;
+    } else {
+        return Ok(None);
+    }
+    Ok(Some(size))
+}
+
+/// Identical to `hash_file`, but for special files (which are never
+/// size-only), failing with `ErrorKind::TimedOut` when reading takes
+/// longer than `timeout`.
+pub fn hash_special_file(
+    file_path: &Utf8Path,
+    stripped_file_path: &str,
+    options: &HashOptions,
+    salt: Option<&[u8; 32]>,
+    timeout: Duration,
+) -> IOResult<HashedFile> {
+    let modified = match options.record_mtime || options.subtree_stamps {
+        true => Some(stat(file_path)?.modified()?),
+        false => None,
+    };
+    let (hash, size) = read_special_file(file_path, options, salt, timeout)?;
+    Ok(HashedFile {
+        hash,
+        path: record_path(stripped_file_path, options),
+        size,
+        modified,
+        size_only: false,
+    })
+}
+
+/// Name of the threads special files are read on.
+const SPECIAL_READER_NAME: &str = "b3hash-special";
+
+/// Hashes everything read from the special file at `file_path` in the
+/// mode `options` says (salted with `salt`), returning the hash and the number of bytes read,
+/// or giving up once `timeout` has passed.
+///
+/// The read happens on its own thread, since just opening a FIFO blocks
+/// until something opens it for writing, and there's no portable way to
+/// open one without blocking. When we give up, that thread is told to stop
+/// reading, and a FIFO it's still waiting to open is opened for writing to
+/// let it go. Only a read that stays blocked on the special file itself
+/// (e.g. a FIFO whose writer never writes, or a terminal) keeps the thread
+/// around, until that read returns.
+fn read_special_file(
+    file_path: &Utf8Path,
+    options: &HashOptions,
+    salt: Option<&[u8; 32]>,
+    timeout: Duration,
+) -> IOResult<(Hash, u64)> {
+    let (sender, receiver) = mpsc::channel();
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let path = file_path.to_path_buf();
+    let mut hasher = salted_hasher(&options.mode, salt);
+    let buffer_size = read_buffer_size(options);
+    let reader_cancelled = Arc::clone(&cancelled);
+    let reading = move || {
+        let res = File::open(&path)
+            .and_then(|file| {
+                let mut reader = CancellableReader {
+                    inner: file,
+                    cancelled: &reader_cancelled,
+                };
+                update_reader(&mut hasher, &mut reader, buffer_size)
+            })
+            .map(|_| hasher);
+        // Nobody's listening anymore if we already timed out, which is fine.
+        let _ = sender.send(res);
+    };
+    std::thread::Builder::new()
+        .name(SPECIAL_READER_NAME.to_string())
+        .spawn(reading)?;
+    let hasher = match receiver.recv_timeout(timeout) {
+        Ok(res) => res?,
+        Err(_) => {
+            cancelled.store(true, Ordering::Relaxed);
+            unblock_fifo_open(file_path);
+            return Err(Error::new(
+                ErrorKind::TimedOut,
+                format!("Timed out reading special file '{}'.", file_path),
+            ));
+        }
+    };
+    Ok((hasher.finalize(), content_len(&hasher, salt)))
+}
+
+/// Reads from `inner` until `cancelled` is set, then fails every read
+/// instead, so endless special files (like `/dev/zero`) stop being read.
+struct CancellableReader<'a, R> {
+    inner: R,
+    cancelled: &'a AtomicBool,
+}
+
+impl<R: Read> Read for CancellableReader<'_, R> {
+    #[inline]
+    fn read(&mut self, buf: &mut [u8]) -> IOResult<usize> {
+        if self.cancelled.load(Ordering::Relaxed) {
+            return Err(Error::new(ErrorKind::TimedOut, "Reading was cancelled."));
+        }
+        self.inner.read(buf)
+    }
+}
+
+/// Lets go of a thread blocked opening the FIFO at `file_path` for reading,
+/// by opening it for writing (and closing it again right away). Opening
+/// without blocking just fails when nothing's waiting, so this never hangs.
+#[inline]
+fn unblock_fifo_open(file_path: &Utf8Path) {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+        if file_path
+            .metadata()
+            .is_ok_and(|meta| meta.file_type().is_fifo())
+        {
+            let _ = File::options()
+                .write(true)
+                .custom_flags(libc::O_NONBLOCK)
+                .open(file_path);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = file_path;
+}
+
+/// Feeds everything read from `reader` into `hasher`, `buffer_size`
+/// bytes at a time at most. Unlike `io::copy`, which always uses a small
+/// fixed buffer, the buffer size can be tuned to the storage.
+#[inline]
+fn update_reader<R: Read>(hasher: &mut Hasher, reader: &mut R, buffer_size: usize) -> IOResult<()> {
+    tee_reader(hasher, &mut [], reader, buffer_size)
+}
+
+/// Identical to `update_reader`, but also writing every byte read to each
+/// of `sinks` (e.g. other hashers), so they all see the same single read.
+pub fn tee_reader<R: Read>(
+    hasher: &mut Hasher,
+    sinks: &mut [&mut dyn Write],
+    reader: &mut R,
+    buffer_size: usize,
+) -> IOResult<()> {
+    let mut buf = vec![0; buffer_size];
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(len) => {
+                hasher.update(&buf[..len]);
+                for sink in sinks.iter_mut() {
+                    sink.write_all(&buf[..len])?;
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Buffer size for streamed reads (see `HashOptions::read_buffer_size`).
+#[inline(always)]
+pub fn read_buffer_size(options: &HashOptions) -> usize {
+    const DEFAULT_READ_BUFFER_SIZE: usize = 128 * 1024;
+    options
+        .read_buffer_size
+        .map_or(DEFAULT_READ_BUFFER_SIZE, NonZeroUsize::get)
+}
+
+/// Context for deriving `size_only_hash`es.
+const SIZE_ONLY_CONTEXT: &str = "b3hash v1 size only";
+
+/// Stands in for the hash of a file that was too large to content hash,
+/// so it still affects directory aggregates, and changes whenever the
+/// file's size does. Derived in its own domain, so it can never equal
+/// the content hash of any file (like one containing just those bytes).
+#[inline]
+pub fn size_only_hash(size: u64) -> Hash {
+    let mut hasher = Hasher::new_derive_key(SIZE_ONLY_CONTEXT);
+    hasher.update(&size.to_le_bytes());
+    hasher.finalize()
+}
+
+/// Folds the hash and path of each file in `hashed_files` into a single
+/// aggregate `Hash`, also returning the cumulative size of all the files
+/// (saturated at `u64::MAX`).
+///
+/// `hashed_files` must already be sorted by path, otherwise the aggregate
+/// is meaningless.
+///
+/// It's slightly faster to fold the bytes of each file's hash and name
+/// into a Vec<u8>, then hash that, because the hasher is able to use
+/// vector instructions more consistently on larger [u8]'s.
+/// But the difference is insignificant for small directories,
+/// and for large directories the time spent here is miniscule
+/// compared to overall directory file hashing, so this simple
+/// and in-place implementation is prefered by default.
+/// See `fold_hashed_files_via_buffer` for the other approach.
+pub fn fold_hashed_files<'a, I>(hashed_files: I) -> (Hash, u64)
+where
+    I: IntoIterator<Item = &'a HashedFile>,
+{
+    fold_hashed_files_into(Hasher::new(), hashed_files)
+}
+
+/// Identical to `fold_hashed_files`, but folding into `hasher`
+/// (e.g. a keyed one) rather than a fresh, plain one.
+pub fn fold_hashed_files_into<'a, I>(mut hasher: Hasher, hashed_files: I) -> (Hash, u64)
+where
+    I: IntoIterator<Item = &'a HashedFile>,
+{
+    let total_bytes_hashed = update_with_hashed_files(&mut hasher, hashed_files);
+    (hasher.finalize(), total_bytes_hashed)
+}
+
+/// Feeds the hash and path of each file in `hashed_files` into `hasher`,
+/// exactly like `fold_hashed_files` does, returning their cumulative size.
+/// Calling this on consecutive runs of sorted files is identical to
+/// folding them all at once.
+pub fn update_with_hashed_files<'a, I>(hasher: &mut Hasher, hashed_files: I) -> u64
+where
+    I: IntoIterator<Item = &'a HashedFile>,
+{
+    let mut total_bytes_hashed = 0;
+    for file in hashed_files {
+        hasher.update(file.hash.as_bytes());
+        hasher.update(file.path.as_bytes());
+        // Saturating rather than wrapping (or panicking in debug builds),
+        // since a nonsense total is worse than a pinned one.
+        total_bytes_hashed = file.size.saturating_add(total_bytes_hashed);
+    }
+    total_bytes_hashed
+}
+
+/// Identical to `fold_hashed_files`, but with each file's size fed into the
+/// hasher right after its path (see `HashOptions::aggregate_sizes`).
+pub fn fold_hashed_files_with_sizes(hashed_files: &[HashedFile]) -> (Hash, u64) {
+    let mut hasher = Hasher::new();
+    let mut total_bytes_hashed = 0;
+    for file in hashed_files {
+        hasher.update(file.hash.as_bytes());
+        hasher.update(file.path.as_bytes());
+        hasher.update(&file.size.to_le_bytes());
+        total_bytes_hashed = file.size.saturating_add(total_bytes_hashed);
+    }
+    (hasher.finalize(), total_bytes_hashed)
+}
+
+/// Folds `hashed_files` into the aggregate (and cumulative size)
+/// whichever way `options` asks for.
+#[inline]
+pub fn aggregate_with_options(hashed_files: &[HashedFile], options: &HashOptions) -> (Hash, u64) {
+    if options.aggregate_sizes {
+        fold_hashed_files_with_sizes(hashed_files)
+    } else if options.aggregate_via_buffer {
+        fold_hashed_files_via_buffer(hashed_files)
+    } else {
+        fold_hashed_files(hashed_files)
+    }
+}
+
+/// Identical to `fold_hashed_files`, but first copies the hash and path
+/// bytes of every file into a single buffer and hashes that in one go.
+///
+/// The hasher sees exactly the same byte stream either way, so the
+/// aggregate is identical. This trades a buffer the size of every hash
+/// and path combined for better vectorization on huge file lists.
+pub fn fold_hashed_files_via_buffer(hashed_files: &[HashedFile]) -> (Hash, u64) {
+    let buf_len = hashed_files
+        .iter()
+        .map(|file| blake3::OUT_LEN + file.path.len())
+        .sum();
+    let mut buf = Vec::with_capacity(buf_len);
+    let mut total_bytes_hashed = 0;
+    for file in hashed_files {
+        buf.extend_from_slice(file.hash.as_bytes());
+        buf.extend_from_slice(file.path.as_bytes());
+        total_bytes_hashed = file.size.saturating_add(total_bytes_hashed);
+    }
+    (blake3::hash(&buf), total_bytes_hashed)
+}
+
+/// The name of the directory at `dir_path`, falling back
+/// to `dir_path` itself when there isn't one (e.g. `..`).
+#[inline]
+pub fn dir_name(dir_path: &str) -> String {
+    Utf8Path::new(dir_path)
+        .file_name()
+        .unwrap_or(dir_path)
+        .to_string()
+}
+
+/// The actual name of the directory at `dir_path`, even when that's
+/// something like `.` or `..`, because it's resolved first.
+#[inline]
+pub fn resolved_dir_name(dir_path: &str) -> IOResult<String> {
+    let resolved = Utf8Path::new(dir_path).canonicalize_utf8()?;
+    Ok(dir_name(resolved.as_str()))
+}
+
+/// The recorded `Header::dir_name`, if it differs from the name of the
+/// directory at `dir_path` (see `ValidationReport::dir_name_mismatch`).
+#[inline]
+pub fn dir_name_mismatch(dir_path: &str, header: &Header) -> IOResult<Option<String>> {
+    match &header.dir_name {
+        Some(recorded) if *recorded != resolved_dir_name(dir_path)? => Ok(Some(recorded.clone())),
+        _ => Ok(None),
+    }
 }
 
 /// Windows always has to be so funny and unique >:(
 #[inline]
-fn oi_vei(s: &str) -> String {
+pub fn oi_vei(s: &str) -> String {
     if cfg!(windows) {
         s.chars()
             .map(|c| match c == WINDOWS_MOMENT {
@@ -102,97 +1089,540 @@ fn oi_vei(s: &str) -> String {
 ///
 /// It's possible to parallelize this operation, using `rayon::flat_map`,
 /// but doing so regresses performance significantly.
-pub fn serialize_hashed_files(hashed_files: Vec<HashedFile>) -> Vec<u8> {
-    /// 32MiB pre-allocation.
-    const STARTING_CAP: usize = 1 << 25;
+///
+/// The exact length of the output is known up front, so this never
+/// reallocates, and never over-allocates for small directories.
+pub fn serialize_hashed_files(hashed_files: &[HashedFile], header: &Header) -> Vec<u8> {
+    let len = hashed_files
+        .iter()
+        .map(|file| header.record_len(file))
+        .sum();
     hashed_files
-        .into_iter()
-        .fold(Vec::with_capacity(STARTING_CAP), |mut buf, file| {
-            // Prefer to_hex() over to_string() because it avoids heap allocation.
-            buf.extend(file.hash.to_hex().bytes());
-            // The char constants used are represented as ascii values,
-            // so forcing them into u8's and pushing them is fine.
-            buf.push(DELIM as u8);
-            buf.extend(file.path.bytes());
-            buf.push(NEWLINE as u8);
+        .iter()
+        .fold(Vec::with_capacity(len), |mut buf, file| {
+            header.write_record(&mut buf, file);
             buf
         })
 }
 
-/// Simultaneously parses **and** validates file hashes from `old_data`,
+/// Identical to `serialize_hashed_files`, but streams the records straight
+/// into `writer` rather than collecting them, so only one record is ever
+/// held in memory at a time. Wrap `writer` in a `BufWriter` if it doesn't
+/// buffer on its own, since every record is written separately.
+pub fn serialize_hashed_files_to<W: Write>(
+    hashed_files: &[HashedFile],
+    header: &Header,
+    writer: &mut W,
+) -> IOResult<()> {
+    // Reused for every record, so this only allocates for the longest path.
+    let mut buf = Vec::new();
+    for file in hashed_files {
+        buf.clear();
+        header.write_record(&mut buf, file);
+        writer.write_all(&buf)?;
+    }
+    Ok(())
+}
+
+/// Serializes `hashed_files` in the format `b3sum` (and the coreutils
+/// `*sum` tools) print and check: the hex hash, two spaces, then the path.
+/// If `total` is given, its hash and name are appended as one last line.
+///
+/// Paths containing a backslash or newline get escaped the same way those
+/// tools do it, with the whole line prefixed by a backslash. Files that
+/// were only recorded by size don't have a real hash, so they're left out.
+pub fn serialize_coreutils(hashed_files: &[HashedFile], total: Option<(&Hash, &str)>) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(hashed_files.len() * (crate::HASH_HEX_LEN + 64));
+    for file in hashed_files.iter().filter(|file| !file.size_only) {
+        write_coreutils_line(&mut buf, &file.hash, &file.path);
+    }
+    if let Some((hash, name)) = total {
+        write_coreutils_line(&mut buf, hash, name);
+    }
+    buf
+}
+
+#[inline]
+fn write_coreutils_line(buf: &mut Vec<u8>, hash: &Hash, path: &str) {
+    let escape = path.contains(['\\', '\n']);
+    if escape {
+        buf.push(b'\\');
+    }
+    buf.extend(hash.to_hex().as_bytes());
+    buf.extend(b"  ");
+    match escape {
+        true => buf.extend(path.replace('\\', "\\\\").replace('\n', "\\n").bytes()),
+        false => buf.extend(path.bytes()),
+    }
+    buf.push(b'\n');
+}
+
+/// What every `validate_data` call within one validation of a directory
+/// shares, however many blocks (or subtrees) its records come in: a single
+/// throttle, so `HashOptions::max_bytes_per_sec` caps the whole validation
+/// rather than each block, and the tree only being listed once to find
+/// which files are recorded under a normalized path.
+pub struct ValidationContext {
+    throttle: Option<Throttle>,
+    on_disk: HashMap<String, String>,
+    unchanged_dirs: HashSet<String>,
+}
+
+impl ValidationContext {
+    /// Records of files directly inside any of `unchanged_dirs`
+    /// are skipped entirely, without even checking they exist.
+    #[inline]
+    pub fn new(
+        dir_path: &str,
+        options: &HashOptions,
+        unchanged_dirs: HashSet<String>,
+    ) -> IOResult<Self> {
+        Ok(Self {
+            throttle: options.max_bytes_per_sec.map(Throttle::new),
+            on_disk: denormalized_paths(&oi_vei(dir_path), options)?,
+            unchanged_dirs,
+        })
+    }
+}
+
+/// Simultaneously parses **and** validates file hashes from `records`,
 /// returning a list of file paths which failed validation.
 ///
 /// Since each line contains both the file path relative to `dir_path`
 /// and the hash for said file, upon successfully parsing each line we
 /// can immedietely hash the associated file and compare hashes.
-pub fn validate_data(dir_path: &str, old_data: Vec<u8>) -> IOResult<Vec<String>> {
+///
+/// `context` must have been created for the same `dir_path` and `options`.
+pub fn validate_data<'a>(
+    dir_path: &str,
+    records: &'a str,
+    header: &Header,
+    options: &HashOptions,
+    context: &ValidationContext,
+) -> IOResult<Vec<Failure>> {
     // Caller may actually see these paths when files fail validation or errors
     // are returned, so we erase windows retardation if it exists.
     let dir_path_frfr = oi_vei(dir_path);
     let dir_path = dir_path_frfr.as_str();
 
+    // Each line first needs to be partitioned into it's parts:
+    // the hash itself, the file path the hash was derived from,
+    // and whatever extra columns the header says are present.
+    // Extra columns are purely informational and never validated.
+    let parse_line = |line: &'a str| -> IOResult<Option<(Expected, Record<'a>)>> {
+        match header.parse_record(line) {
+            Some(record) if context.unchanged_dirs.contains(parent_dir(&record.path)) => Ok(None),
+            Some(record) if options.trust_mtime && mtime_unchanged(dir_path, &record) => Ok(None),
+            Some(record) => Ok(Some((parse_expected(header, record.hash)?, record))),
+            // Delimiter wasn't found on current line (how tf???)
+            // so we cancel verification and propagate an error.
+            None => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Failed to find delimiter '{}' while parsing line '{}'.",
+                    DELIM, line
+                ),
+            )),
+        }
+    };
+    let salt = header.salt.as_ref();
+    let validate = |(expected, record): (Expected, Record)| {
+        let path = context
+            .on_disk
+            .get(&*record.path)
+            .map_or(&*record.path, String::as_str);
+        validate_or_fail(dir_path, path, &expected, options, salt, &context.throttle)
+    };
+
     // We're building a Vec<String> containing the names of files
     // which either are not present in our new data or whose
     // new Hash does not match the old Hash.
-    //
-    // SAFETY: Old hashfile data should always be valid utf8
-    // because we serialize into valid utf8. Users changing
-    // hashfile contents or not verifying the hashfile itself
-    // before using it to verify a directory is a user error.
-    unsafe { String::from_utf8_unchecked(old_data) }
-        .par_lines()
-        .filter_map(|line| {
-            // Each line first needs to be partitioned into it's two parts:
-            // the hash itself and the file path the hash was derived from.
-            match line.split_once(DELIM) {
-                // We want the hash to be a literal Hash value instead of
-                // the String representation of one, since Hash has a
-                // specialized eq() that's much faster than the eq() of String.
-                Some((hash, file_path)) => match Hash::from_hex(hash) {
-                    Ok(old_hash) => {
-                        // Since file paths are always stripped of their common prefix,
-                        // which is always the relative path to their root directory,
-                        // it needs to be re-added.
-                        let path = Utf8Path::new(dir_path).join(file_path);
-                        match path.try_exists() {
-                            Ok(true) => match Hasher::new().update_mmap(path.as_std_path()) {
-                                Ok(hasher) => {
-                                    let new_hash = hasher.finalize();
-                                    match hash_eq(&old_hash, &new_hash) {
-                                        true => None,
-                                        false => Some(Ok(path.into_string())),
-                                    }
-                                }
-                                Err(e) => Some(Err(e)),
-                            },
-                            // No errors but file doesn't exist, so we add
-                            // as one of the files that failed validation.
-                            Ok(false) => Some(Ok(path.into_string())),
-                            // Error'd while determining if file exists.
-                            // Only scenarios where I actually think this might
-                            // proc is if file/folder permission is denied.
-                            Err(e) => Some(Err(e)),
-                        }
-                    }
-                    // HexError needs to be explicitly converted to IOError.
-                    Err(e) => Some(Err(Error::new(ErrorKind::InvalidData, e))),
-                },
-                // Delimiter wasn't found on current line (how tf???)
-                // so we cancel verification and propagate an error.
-                None => Some(Err(Error::new(
+    match options.two_phase_validation {
+        false => records
+            .par_lines()
+            .filter_map(|line| match parse_line(line).transpose()? {
+                Ok(parsed) => validate(parsed),
+                Err(e) => Some(Err(e)),
+            })
+            .collect(),
+        true => {
+            // Parsing is pure CPU work, so it all happens up front
+            // and the second pass is nothing but (IO-bound) hashing.
+            let parsed: Vec<_> = records
+                .par_lines()
+                .filter_map(|line| parse_line(line).transpose())
+                .collect::<IOResult<_>>()?;
+            parsed.into_par_iter().filter_map(validate).collect()
+        }
+    }
+}
+
+/// Whether the file `record` describes still has the mtime it recorded,
+/// and the size too if one was recorded. Anything that can't be stat'ed
+/// counts as changed, so that validating it reports the problem.
+#[inline]
+fn mtime_unchanged(dir_path: &str, record: &Record) -> bool {
+    let Some(recorded) = record.mtime else {
+        return false;
+    };
+    let Ok(metadata) = Utf8Path::new(dir_path).join(&*record.path).metadata() else {
+        return false;
+    };
+    record.size.is_none_or(|size| size == metadata.len())
+        && metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .is_some_and(|since_epoch| since_epoch.as_secs() == recorded)
+}
+
+/// Validates just the files at the given relative `paths` against their
+/// records in the hashfile `blocks`, returning the paths (joined onto
+/// `dir_path`) of the ones that failed. Every other file is left alone.
+///
+/// Fails with `ErrorKind::NotFound` if any of `paths` isn't recorded.
+pub fn validate_selected(
+    dir_path: &str,
+    blocks: &[(Header, String)],
+    paths: &[&str],
+    options: &HashOptions,
+) -> IOResult<Vec<Failure>> {
+    let mut selected: BTreeMap<String, Option<(Expected, &Header)>> =
+        paths.iter().map(|path| (oi_vei(path), None)).collect();
+    for (header, block) in blocks {
+        for line in block.lines() {
+            let record = header.parse_record(line).ok_or_else(|| {
+                Error::new(
                     ErrorKind::InvalidInput,
                     format!(
                         "Failed to find delimiter '{}' while parsing line '{}'.",
                         DELIM, line
                     ),
-                ))),
+                )
+            })?;
+            if let Some(expected) = selected.get_mut(&*record.path) {
+                *expected = Some((parse_expected(header, record.hash)?, header));
             }
+        }
+    }
+    let selected = selected
+        .into_iter()
+        .map(|(path, expected)| match expected {
+            Some((expected, header)) => Ok((path, expected, header.salt)),
+            None => Err(Error::new(
+                ErrorKind::NotFound,
+                format!("'{}' isn't recorded in the hashfile.", path),
+            )),
+        })
+        .collect::<IOResult<Vec<_>>>()?;
+
+    let dir_path = oi_vei(dir_path);
+    let throttle = options.max_bytes_per_sec.map(Throttle::new);
+    selected
+        .par_iter()
+        .filter_map(|(path, expected, salt)| {
+            validate_or_fail(&dir_path, path, expected, options, salt.as_ref(), &throttle)
+        })
+        .collect()
+}
+
+/// Paths of the `failures` of validating `records` (see `validate_data`)
+/// whose live file is still the size their record says, meaning only their
+/// contents changed (see `ValidationReport::silent_corruption`). Always
+/// empty unless `header` says records have a size column.
+pub fn same_size_failures(
+    dir_path: &str,
+    records: &str,
+    header: &Header,
+    failures: &[Failure],
+) -> Vec<String> {
+    // Files that couldn't be read aren't known to have changed at all.
+    let failed: HashSet<&str> = failures
+        .iter()
+        .filter(|(_, failed)| matches!(failed, Failed::Changed))
+        .map(|(path, _)| path.as_str())
+        .collect();
+    if !header.size || failed.is_empty() {
+        return Vec::new();
+    }
+    let dir_path = oi_vei(dir_path);
+    records
+        .lines()
+        .filter_map(|line| header.parse_record(line))
+        .filter_map(|record| {
+            let path = Utf8Path::new(&dir_path).join(&*record.path);
+            if !failed.contains(path.as_str()) {
+                return None;
+            }
+            let live_size = path.metadata().ok()?.len();
+            (record.size == Some(live_size)).then(|| path.into_string())
         })
         .collect()
 }
 
+/// What a record says the file it describes should look like.
+pub enum Expected {
+    /// The file was too large to have its contents hashed,
+    /// so its size is all we have to go off of.
+    Size(u64),
+    Hash(Hash),
+}
+
+impl Expected {
+    /// Whether the freshly hashed `file` still looks like this.
+    #[inline]
+    pub fn matches(&self, file: &HashedFile) -> bool {
+        match self {
+            Self::Size(size) => file.size == *size,
+            // A size-only file can't be compared with a content hash.
+            Self::Hash(hash) => !file.size_only && hash_eq(hash, &file.hash),
+        }
+    }
+}
+
+/// Parses the hash column of a record under `header`.
+#[inline]
+pub fn parse_expected(header: &Header, hash: &str) -> IOResult<Expected> {
+    match hash.strip_prefix(SIZE_ONLY_PREFIX) {
+        Some(size) => size
+            .parse()
+            .map(Expected::Size)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e)),
+        // We want the hash to be a literal Hash value instead of
+        // the String representation of one, since Hash has a
+        // specialized eq() that's much faster than the eq() of String.
+        None => header.parse_hash(hash).map(Expected::Hash),
+    }
+}
+
+/// A (joined) path that failed validation, along with why.
+pub type Failure = (String, Failed);
+
+/// Why a file failed validation.
+pub enum Failed {
+    /// Its contents (or just its size, for size-only records) changed.
+    Changed,
+    /// It was recorded, but doesn't exist anymore.
+    Missing,
+    /// It couldn't be read, with the error responsible, which
+    /// `HashOptions::continue_on_error` turned into a failure
+    /// rather than propagating it.
+    Unreadable(Error),
+}
+
+/// Identical to `validate_record`, but with errors turned into failures
+/// when `HashOptions::continue_on_error` says so.
+#[inline]
+fn validate_or_fail(
+    dir_path: &str,
+    record_path: &str,
+    expected: &Expected,
+    options: &HashOptions,
+    salt: Option<&[u8; 32]>,
+    throttle: &Option<Throttle>,
+) -> Option<IOResult<Failure>> {
+    // Checked here, so cancelling is never turned into a failure.
+    if let Some(control) = &options.control {
+        if let Err(e) = control.checkpoint() {
+            return Some(Err(e));
+        }
+    }
+    match validate_record(dir_path, record_path, expected, options, salt, throttle) {
+        Ok(failure) => failure.map(Ok),
+        Err(e) => {
+            let path = Utf8Path::new(dir_path).join(record_path).into_string();
+            match options.continue_on_error {
+                // The path is already right next to the error here.
+                true => Some(Ok((path, Failed::Unreadable(e)))),
+                // The bare io::Error doesn't say which of possibly millions
+                // of files it came from, so that gets added (keeping the kind).
+                false => Some(Err(Error::new(
+                    e.kind(),
+                    format!("Failed to validate '{}': {}", path, e),
+                ))),
+            }
+        }
+    }
+}
+
+/// Validates the single file at relative `record_path` against `expected`,
+/// returning its (joined) path and why if it failed validation.
+#[inline]
+fn validate_record(
+    dir_path: &str,
+    record_path: &str,
+    expected: &Expected,
+    options: &HashOptions,
+    salt: Option<&[u8; 32]>,
+    throttle: &Option<Throttle>,
+) -> IOResult<Option<Failure>> {
+    // Since file paths are always stripped of their common prefix,
+    // which is always the relative path to their root directory,
+    // it needs to be re-added.
+    let path = Utf8Path::new(dir_path).join(record_path);
+    // Only scenarios where I actually think try_exists() might
+    // error is if file/folder permission is denied.
+    if !path.try_exists()? {
+        // No errors but file doesn't exist, so we add
+        // as one of the files that failed validation.
+        return Ok(Some((path.into_string(), Failed::Missing)));
+    }
+    let unchanged = match expected {
+        Expected::Size(size) => path.metadata()?.len() == *size,
+        Expected::Hash(old_hash) => {
+            let (hash, size) = match options.special_files {
+                // Only stat when it matters, since normally special files are
+                // never recorded (and update_mmap can read the odd device anyway).
+                SpecialFiles::Read { timeout } if !path.metadata()?.is_file() => {
+                    read_special_file(&path, options, salt, timeout)?
+                }
+                _ => hash_contents(&path, options, salt, None)?,
+            };
+            if let Some(throttle) = throttle {
+                throttle.consume(size);
+            }
+            hash_eq(old_hash, &hash)
+        }
+    };
+    Ok((!unchanged).then(|| (path.into_string(), Failed::Changed)))
+}
+
+/// Finds every live file beneath `dir_path` that isn't recorded in any of
+/// the hashfile `blocks`, returning their paths joined onto `dir_path`.
+pub fn find_added_files(
+    dir_path: &str,
+    blocks: &[(Header, String)],
+    options: &HashOptions,
+) -> IOResult<Vec<String>> {
+    let recorded: HashSet<Cow<str>> = blocks
+        .iter()
+        .flat_map(|(header, block)| {
+            block
+                .lines()
+                .filter_map(|line| header.parse_record(line))
+                .map(|record| record.path)
+        })
+        .collect();
+    let dir_path = oi_vei(dir_path);
+    // A lone shard only knows about the files within its own range.
+    let header = &blocks[0].0;
+    let mut added: Vec<String> = relative_file_paths(&dir_path, options)?
+        .into_iter()
+        .filter(|path| header.in_range(path) && !recorded.contains(path.as_str()))
+        .map(|path| Utf8Path::new(&dir_path).join(path).into_string())
+        .collect();
+    added.sort_unstable();
+    Ok(added)
+}
+
+/// Compares the per-directory file counts recorded in a hashfile against
+/// the live directory, returning every directory whose count differs.
+///
+/// Directories that only exist on one side are reported with
+/// a count of zero for the side they're missing from.
+pub fn validate_dir_counts(
+    dir_path: &str,
+    expected: &BTreeMap<String, usize>,
+    options: &HashOptions,
+) -> IOResult<Vec<CountMismatch>> {
+    let live_files = relative_file_paths(dir_path, options)?;
+    let found = count_files_per_dir(live_files.iter().map(String::as_str));
+    let mut dirs: BTreeSet<&String> = expected.keys().collect();
+    dirs.extend(found.keys());
+    Ok(dirs
+        .into_iter()
+        .filter_map(|dir| {
+            let expected = expected.get(dir).copied().unwrap_or(0);
+            let found = found.get(dir).copied().unwrap_or(0);
+            (expected != found).then(|| CountMismatch {
+                dir: dir.clone(),
+                expected,
+                found,
+            })
+        })
+        .collect())
+}
+
+/// Computes the stamp of every directory's subtree in the live directory at
+/// `dir_path` (see `subtree_stamps`), which only costs a stat per file.
+pub fn live_subtree_stamps(
+    dir_path: &str,
+    options: &HashOptions,
+) -> IOResult<BTreeMap<String, Hash>> {
+    // See hash_files() for why this is one longer than dir_path.
+    let prefix_len = dir_path.len() + 1;
+    // Stat'ed by their on-disk paths, but stamped by their recorded ones.
+    let mut live_files = get_files(dir_path.into(), EntryFilter::new(options))?
+        .into_par_iter()
+        .map(|file_path| {
+            let metadata = file_path.metadata()?;
+            Ok((
+                record_path(&file_path.as_str()[prefix_len..], options),
+                metadata,
+            ))
+        })
+        .collect::<IOResult<Vec<_>>>()?;
+    live_files.sort_unstable_by(|(x, _), (y, _)| x.cmp(y));
+    Ok(subtree_stamps(live_files.iter().map(|(path, meta)| {
+        (path.as_str(), meta.len(), meta.modified().ok())
+    })))
+}
+
+/// Number of hidden entries skipped while traversing `dir_path`.
+#[inline]
+pub fn hidden_entries(dir_path: &str) -> IOResult<usize> {
+    get_entries(dir_path.into(), None, EntryFilter::default()).map(|entries| entries.hidden)
+}
+
+/// Relative paths of every folder beneath `dir_path` without a single
+/// visible file (regular or special) anywhere beneath it, in path order.
+pub fn empty_folders(dir_path: &str) -> IOResult<Vec<String>> {
+    // See hash_files() for why this is one longer than dir_path.
+    let prefix_len = dir_path.len() + 1;
+    let entries = get_entries(dir_path.into(), None, EntryFilter::default())?;
+    let mut non_empty = HashSet::new();
+    for file in entries.files.iter().chain(&entries.special) {
+        // Every ancestor of a file is non-empty, up to (and excluding) the root.
+        let mut dir = file.parent();
+        while let Some(cur) = dir.filter(|dir| dir.as_str().len() >= prefix_len) {
+            if !non_empty.insert(cur) {
+                // Its ancestors were already marked by an earlier file.
+                break;
+            }
+            dir = cur.parent();
+        }
+    }
+    let mut empty: Vec<String> = entries
+        .folders
+        .iter()
+        .filter(|folder| folder.as_str().len() >= prefix_len)
+        .filter(|folder| !non_empty.contains(folder.as_path()))
+        .map(|folder| oi_vei(&folder.as_str()[prefix_len..]))
+        .collect();
+    empty.sort_unstable();
+    Ok(empty)
+}
+
+/// Relative path of the directory containing the file at relative `path`.
+#[inline]
+pub fn parent_dir(path: &str) -> &str {
+    path.rsplit_once('/').map_or("", |(dir, _)| dir)
+}
+
+/// Paths of all visible files beneath `dir_path`, relative to `dir_path`
+/// and normalized the same way as hashed file paths (see `record_path`).
+/// Entries are skipped (or not) the same way as when hashing.
+pub fn relative_file_paths(dir_path: &str, options: &HashOptions) -> IOResult<Vec<String>> {
+    // See hash_files() for why this is one longer than dir_path.
+    let prefix_len = dir_path.len() + 1;
+    Ok(get_files(dir_path.into(), EntryFilter::new(options))?
+        .iter()
+        .map(|file_path| record_path(&file_path.as_str()[prefix_len..], options))
+        .collect())
+}
+
 #[inline(always)]
-fn hash_eq(x: &Hash, y: &Hash) -> bool {
+pub fn hash_eq(x: &Hash, y: &Hash) -> bool {
     if cfg!(target_arch = "x86_64") {
         // Always constant time on x64 platforms, and faster
         // than provided Hash::eq.
@@ -203,3 +1633,690 @@ fn hash_eq(x: &Hash, y: &Hash) -> bool {
         x.eq(y)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::Scratch;
+    use crate::{create_hashfile_with_options, validate_hashfile_with_options, HashedDirectory};
+    use std::fs::File;
+    use std::num::NonZeroU64;
+
+    #[test]
+    fn trusted_mtime_still_catches_a_size_change() {
+        let scratch = Scratch::new();
+        scratch.write("tree/log.txt", "short");
+        scratch.write("tree/same.txt", "untouched");
+        let options = HashOptions {
+            record_mtime: true,
+            record_size: true,
+            trust_mtime: true,
+            ..Default::default()
+        };
+        create_hashfile_with_options("tree", &options).unwrap();
+
+        // Rewritten, then backdated, like a tool restoring the mtime would.
+        let modified = std::fs::metadata("tree/log.txt")
+            .unwrap()
+            .modified()
+            .unwrap();
+        scratch.write("tree/log.txt", "rather longer");
+        File::options()
+            .write(true)
+            .open("tree/log.txt")
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        let report = validate_hashfile_with_options("tree", &options).unwrap();
+        assert_eq!(report.failed_files, ["tree/log.txt"]);
+    }
+
+    #[cfg(feature = "unicode")]
+    #[test]
+    fn decomposed_names_validate_without_showing_up_as_added() {
+        let scratch = Scratch::new();
+        // "café" with its accent as a separate combining character.
+        scratch.write("tree/cafe\u{301}/menu.txt", "espresso");
+        scratch.write("tree/plain.txt", "plain");
+        let options = HashOptions {
+            normalize_unicode: true,
+            dir_counts: true,
+            ..Default::default()
+        };
+        create_hashfile_with_options("tree", &options).unwrap();
+        assert!(scratch.hashfile().contains("caf\u{e9}/menu.txt"));
+
+        let report = validate_hashfile_with_options("tree", &options).unwrap();
+        assert!(report.failed_files.is_empty());
+        assert!(report.added.is_empty());
+        assert!(report.count_mismatches.is_empty());
+
+        scratch.write("tree/cafe\u{301}/menu.txt", "decaf");
+        let report = validate_hashfile_with_options("tree", &options).unwrap();
+        assert_eq!(report.failed_files, ["tree/cafe\u{301}/menu.txt"]);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn hashing_leaks_neither_fds_nor_mappings() {
+        let scratch = Scratch::new();
+        // Comfortably above the size blake3 starts memory-mapping files at.
+        for i in 0..40 {
+            scratch.write(&format!("tree/{}.bin", i), vec![i as u8; 64 * 1024]);
+        }
+        let tree = scratch.path().join("tree").canonicalize().unwrap();
+        // Only what points into the tree counts, since other tests' threads
+        // come and go (along with their stacks) while this one runs.
+        let open_fds = || {
+            std::fs::read_dir("/proc/self/fd")
+                .unwrap()
+                .filter_map(|fd| std::fs::read_link(fd.unwrap().path()).ok())
+                .filter(|target| target.starts_with(&tree))
+                .count()
+        };
+        let tree_str = tree.to_str().unwrap();
+        let mappings = || {
+            std::fs::read_to_string("/proc/self/maps")
+                .unwrap()
+                .lines()
+                .filter(|line| line.contains(tree_str))
+                .count()
+        };
+        for _ in 0..25 {
+            crate::hash_directory("tree").unwrap();
+            assert_eq!(open_fds(), 0);
+            assert_eq!(mappings(), 0);
+        }
+    }
+
+    #[test]
+    fn files_over_max_content_size_are_only_checked_by_size() {
+        let scratch = Scratch::new();
+        scratch.write("tree/small.txt", "tiny");
+        scratch.write("tree/video.bin", [1u8; 100]);
+        let options = HashOptions {
+            max_content_size: Some(10),
+            ..Default::default()
+        };
+        create_hashfile_with_options("tree", &options).unwrap();
+        assert!(scratch.hashfile().contains("size:100 video.bin"));
+
+        // Same size, different contents: by design, nobody notices.
+        scratch.write("tree/video.bin", [2u8; 100]);
+        scratch.write("tree/small.txt", "TINY");
+        let report = validate_hashfile_with_options("tree", &options).unwrap();
+        assert_eq!(report.failed_files, ["tree/small.txt"]);
+
+        scratch.write("tree/video.bin", [2u8; 101]);
+        let report = validate_hashfile_with_options("tree", &options).unwrap();
+        assert_eq!(report.failed_files, ["tree/small.txt", "tree/video.bin"]);
+    }
+
+    #[test]
+    fn size_only_records_never_collide_with_content_hashes() {
+        let scratch = Scratch::new();
+        // Exactly the bytes an undomained stand-in would have been a hash of.
+        scratch.write("sized/f", [0u8; 100]);
+        scratch.write("hashed/f", 100u64.to_le_bytes());
+        let options = HashOptions {
+            max_content_size: Some(50),
+            ..Default::default()
+        };
+        let sized = crate::hash_directory_with_options("sized", &options).unwrap();
+        let hashed = crate::hash_directory_with_options("hashed", &options).unwrap();
+        assert!(sized.files[0].size_only && !hashed.files[0].size_only);
+        assert_eq!(hashed.files[0].hash, blake3::hash(&100u64.to_le_bytes()));
+        assert_ne!(sized.files[0].hash, hashed.files[0].hash);
+        assert_ne!(sized.hash, hashed.hash);
+    }
+
+    #[test]
+    fn mmap_window_never_changes_any_hash() {
+        let scratch = Scratch::new();
+        scratch.write("tree/tiny.txt", "tiny");
+        scratch.write("tree/mapped.bin", vec![3u8; 40 * 1024]);
+        scratch.write("tree/huge.bin", vec![5u8; 300 * 1024]);
+        let expected = crate::hash_directory("tree").unwrap();
+        // Whether or not each file was already stat'ed for something else.
+        for (record_mtime, max_in_flight_bytes) in [
+            (false, None),
+            (true, None),
+            (false, NonZeroU64::new(1 << 30)),
+        ] {
+            let options = HashOptions {
+                mmap_min_size: 16 * 1024,
+                mmap_max_size: Some(128 * 1024),
+                record_mtime,
+                max_in_flight_bytes,
+                ..Default::default()
+            };
+            let hashed = crate::hash_directory_with_options("tree", &options).unwrap();
+            assert_eq!(hashed.hash, expected.hash);
+            let hashes =
+                |dir: &HashedDirectory| dir.iter().map(|file| file.hash).collect::<Vec<_>>();
+            assert_eq!(hashes(&hashed), hashes(&expected));
+            create_hashfile_with_options("tree", &options).unwrap();
+            assert!(validate_hashfile_with_options("tree", &options)
+                .unwrap()
+                .is_valid());
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn ownership_changes_fail_validation_when_included() {
+        use std::os::unix::fs::MetadataExt;
+
+        let scratch = Scratch::new();
+        scratch.write("tree/owned.txt", "contents");
+        let options = HashOptions {
+            include_ownership: true,
+            ..Default::default()
+        };
+        let plain = crate::hash_directory("tree").unwrap();
+        let owned = crate::hash_directory_with_options("tree", &options).unwrap();
+        assert_ne!(owned.hash, plain.hash);
+        create_hashfile_with_options("tree", &options).unwrap();
+        assert!(validate_hashfile_with_options("tree", &options)
+            .unwrap()
+            .is_valid());
+
+        let metadata = std::fs::metadata("tree/owned.txt").unwrap();
+        let (uid, gid) = (metadata.uid(), metadata.gid());
+        // Handing a file to someone else takes root.
+        if std::os::unix::fs::chown("tree/owned.txt", Some(uid + 1), Some(gid)).is_err() {
+            return;
+        }
+        let report = validate_hashfile_with_options("tree", &options).unwrap();
+        assert_eq!(report.failed_files, ["tree/owned.txt"]);
+        // Plain hashes never cared who owns the file.
+        assert_eq!(crate::hash_directory("tree").unwrap().hash, plain.hash);
+    }
+
+    #[test]
+    fn deterministic_scheduling_picks_files_up_in_sorted_chunks() {
+        use std::sync::Mutex;
+
+        let scratch = Scratch::new();
+        let paths: Vec<String> = (0..64).map(|i| format!("f{:02}", i)).collect();
+        for path in &paths {
+            scratch.write(&format!("tree/{}", path), path);
+        }
+        let picked = Arc::new(Mutex::new(Vec::new()));
+        let record = Arc::clone(&picked);
+        let options = HashOptions {
+            deterministic_scheduling: true,
+            // Only used to see the order files are picked up in.
+            content_transform: Some(Box::new(move |contents: &[u8]| {
+                record.lock().unwrap().push(contents.to_vec());
+                contents.to_vec()
+            })),
+            ..Default::default()
+        };
+        let hashed_dir =
+            crate::with_threads(4, || crate::hash_directory_with_options("tree", &options))
+                .unwrap();
+        assert_eq!(hashed_dir.hash, crate::hash_directory("tree").unwrap().hash);
+
+        let picked: Vec<String> = picked
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|contents| String::from_utf8(contents.clone()).unwrap())
+            .collect();
+        assert_eq!(picked.len(), paths.len());
+        // Each worker claims the next 16 files and goes through them in order,
+        // so however the chunks interleave, each one is in sorted order.
+        for chunk in paths.chunks(16) {
+            let order: Vec<_> = picked.iter().filter(|p| chunk.contains(p)).collect();
+            assert!(order.iter().zip(chunk).all(|(p, c)| *p == c), "{:?}", order);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn fifos_are_handled_the_way_special_files_says() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let scratch = Scratch::new();
+        scratch.write("tree/a.txt", "a");
+        let fifo = scratch.path().join("tree/pipe");
+        let c_path = std::ffi::CString::new(fifo.as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o644) }, 0);
+        let with = |special_files| HashOptions {
+            special_files,
+            ..Default::default()
+        };
+
+        let skipped =
+            crate::hash_directory_with_options("tree", &with(SpecialFiles::Skip)).unwrap();
+        assert_eq!(skipped.skipped, ["pipe"]);
+        assert_eq!(skipped.files.len(), 1);
+
+        let err = crate::hash_directory_with_options("tree", &with(SpecialFiles::Error))
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::Unsupported);
+        assert!(err.to_string().contains("tree/pipe"));
+
+        let read = with(SpecialFiles::Read {
+            timeout: Duration::from_secs(10),
+        });
+        let writer = std::thread::spawn({
+            let fifo = fifo.clone();
+            move || std::fs::write(fifo, "piped").unwrap()
+        });
+        let hashed = crate::hash_directory_with_options("tree", &read).unwrap();
+        writer.join().unwrap();
+        let piped = hashed.files.iter().find(|f| f.path == "pipe").unwrap();
+        assert_eq!((piped.hash, piped.size), (blake3::hash(b"piped"), 5));
+
+        // Nothing ever writes, so this times out...
+        let timing_out = with(SpecialFiles::Read {
+            timeout: Duration::from_millis(50),
+        });
+        let err = crate::hash_directory_with_options("tree", &timing_out)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        // ...and the reading thread is let go rather than staying blocked
+        // opening the FIFO for the rest of the process.
+        #[cfg(target_os = "linux")]
+        {
+            let readers = || {
+                std::fs::read_dir("/proc/self/task")
+                    .unwrap()
+                    .filter_map(|task| {
+                        std::fs::read_to_string(task.unwrap().path().join("comm")).ok()
+                    })
+                    .filter(|name| name.trim_end() == SPECIAL_READER_NAME)
+                    .count()
+            };
+            let gone = (0..100).any(|_| {
+                std::thread::sleep(Duration::from_millis(10));
+                readers() == 0
+            });
+            assert!(gone);
+        }
+    }
+
+    #[test]
+    fn subtree_stamps_skip_hashing_unchanged_subtrees() {
+        let scratch = Scratch::new();
+        for path in [
+            "root.txt",
+            "sub1/a",
+            "sub1/b",
+            "sub2/c",
+            "sub2/d",
+            "sub2/deep/e",
+        ] {
+            scratch.write(&format!("tree/{}", path), path);
+        }
+        let options = HashOptions {
+            subtree_stamps: true,
+            ..Default::default()
+        };
+        create_hashfile_with_options("tree", &options).unwrap();
+        scratch.write("tree/sub1/a", "edited");
+
+        let before = CONTENTS_HASHED.load(Ordering::Relaxed);
+        let report = validate_hashfile_with_options("tree", &options).unwrap();
+        let hashed = CONTENTS_HASHED.load(Ordering::Relaxed) - before;
+        assert_eq!(report.failed_files, ["tree/sub1/a"]);
+        assert_eq!(report.unchanged_dirs, ["sub2"]);
+        // Only root.txt and sub1's files, never anything beneath sub2.
+        assert_eq!(hashed, 3);
+    }
+
+    #[test]
+    fn content_transform_hashes_the_transformed_bytes() {
+        let scratch = Scratch::new();
+        scratch.write("tree/lower.txt", "mixed Case");
+        scratch.write("tree/upper.txt", "ALREADY UPPER");
+        let options = HashOptions {
+            content_transform: Some(Box::new(|contents: &[u8]| contents.to_ascii_uppercase())),
+            ..Default::default()
+        };
+        let hashed_dir = crate::hash_directory_with_options("tree", &options).unwrap();
+        let lower = &hashed_dir.files[0];
+        assert_eq!(lower.hash, blake3::hash(b"MIXED CASE"));
+        // Still the size of the file itself, not of what was hashed.
+        assert_eq!(lower.size, 10);
+        let upper = &hashed_dir.files[1];
+        assert_eq!(
+            upper.hash,
+            crate::hash_directory("tree").unwrap().files[1].hash
+        );
+
+        create_hashfile_with_options("tree", &options).unwrap();
+        // Only the case changed, which the transform normalizes away.
+        scratch.write("tree/lower.txt", "MIXED case");
+        assert!(validate_hashfile_with_options("tree", &options)
+            .unwrap()
+            .is_valid());
+        let plain = validate_hashfile_with_options("tree", &HashOptions::default()).unwrap();
+        assert_eq!(plain.failed_files, ["tree/lower.txt"]);
+    }
+
+    #[test]
+    fn case_collisions_are_reported_when_asked_for() {
+        let scratch = Scratch::new();
+        scratch.write("tree/README.md", "upper");
+        scratch.write("tree/src/Lib.rs", "lib");
+        scratch.write("tree/Src/lib.rs", "other lib");
+        scratch.write("tree/unique.txt", "unique");
+        // Case sensitive filesystems (like this one) hash them just fine.
+        assert_eq!(crate::hash_directory("tree").unwrap().files.len(), 4);
+        scratch.write("tree/Readme.md", "mixed");
+        let options = HashOptions {
+            detect_case_collisions: true,
+            ..Default::default()
+        };
+        let err = crate::hash_directory_with_options("tree", &options)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        let message = err.to_string();
+        for path in ["README.md", "Readme.md", "Src/lib.rs", "src/Lib.rs"] {
+            assert!(message.contains(&format!("'{}'", path)), "{}", message);
+        }
+        assert!(!message.contains("unique.txt"));
+
+        std::fs::remove_file("tree/Readme.md").unwrap();
+        std::fs::remove_dir_all("tree/Src").unwrap();
+        assert!(crate::hash_directory_with_options("tree", &options).is_ok());
+    }
+
+    #[test]
+    fn trusted_mtime_skips_hashing_unchanged_files() {
+        let scratch = Scratch::new();
+        scratch.write("tree/a.txt", "a");
+        scratch.write("tree/b.txt", "b");
+        scratch.write("tree/sub/c.txt", "c");
+        let options = HashOptions {
+            record_mtime: true,
+            trust_mtime: true,
+            ..Default::default()
+        };
+        create_hashfile_with_options("tree", &options).unwrap();
+        let hashed_while = |options: &HashOptions| {
+            let before = CONTENTS_HASHED.load(Ordering::Relaxed);
+            let report = validate_hashfile_with_options("tree", options).unwrap();
+            (CONTENTS_HASHED.load(Ordering::Relaxed) - before, report)
+        };
+
+        let (hashed, report) = hashed_while(&options);
+        assert_eq!(hashed, 0);
+        assert!(report.failed_files.is_empty());
+
+        // A same-size write that keeps the mtime goes unnoticed...
+        let modified = std::fs::metadata("tree/b.txt").unwrap().modified().unwrap();
+        scratch.write("tree/b.txt", "B");
+        let file = File::options().write(true).open("tree/b.txt").unwrap();
+        file.set_modified(modified).unwrap();
+        let (hashed, report) = hashed_while(&options);
+        assert_eq!(hashed, 0);
+        assert!(report.failed_files.is_empty());
+
+        // ...but one that moves it gets hashed, and nothing else does.
+        file.set_modified(modified + Duration::from_secs(10))
+            .unwrap();
+        let (hashed, report) = hashed_while(&options);
+        assert_eq!(hashed, 1);
+        assert_eq!(report.failed_files, ["tree/b.txt"]);
+
+        let untrusting = HashOptions {
+            trust_mtime: false,
+            ..options
+        };
+        let (hashed, _) = hashed_while(&untrusting);
+        assert_eq!(hashed, 3);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn resolved_symlinks_hash_each_real_file_once() {
+        use std::os::unix::fs::symlink;
+
+        let scratch = Scratch::new();
+        scratch.write("tree/real.txt", "real");
+        scratch.write("tree/.hidden/target.txt", "hidden");
+        scratch.write("outside/ext.txt", "external");
+        symlink("real.txt", "tree/link.txt").unwrap();
+        std::fs::create_dir("tree/sub").unwrap();
+        symlink("../real.txt", "tree/sub/link.txt").unwrap();
+        symlink("../.hidden/target.txt", "tree/sub/hidden.txt").unwrap();
+        symlink("../outside/ext.txt", "tree/ext.txt").unwrap();
+        symlink("../../outside/ext.txt", "tree/sub/ext.txt").unwrap();
+        symlink("missing.txt", "tree/dangling.txt").unwrap();
+        symlink("sub", "tree/dir_link").unwrap();
+
+        let options = HashOptions {
+            resolve_symlinks: true,
+            ..Default::default()
+        };
+        let before = CONTENTS_HASHED.load(Ordering::Relaxed);
+        let (hashed, _) = hash_files("tree", &options).unwrap();
+        assert_eq!(CONTENTS_HASHED.load(Ordering::Relaxed) - before, 3);
+        let external = std::fs::canonicalize("outside/ext.txt").unwrap();
+        let paths: Vec<_> = hashed.iter().map(|file| file.path.as_str()).collect();
+        let mut expected = vec![".hidden/target.txt", "real.txt", external.to_str().unwrap()];
+        expected.sort_unstable();
+        assert_eq!(paths, expected);
+        let real = hashed.iter().find(|file| file.path == "real.txt").unwrap();
+        assert_eq!(real.hash, blake3::hash(b"real"));
+
+        // Without resolving, links are left out entirely.
+        let (hashed, _) = hash_files("tree", &HashOptions::default()).unwrap();
+        let paths: Vec<_> = hashed.iter().map(|file| file.path.as_str()).collect();
+        assert_eq!(paths, ["real.txt"]);
+    }
+
+    #[cfg(feature = "decompress")]
+    #[test]
+    fn decompressed_files_hash_like_the_original() {
+        use flate2::write::GzEncoder;
+        use std::io::Write;
+
+        let contents = "the same text, over and over. ".repeat(200);
+        let gzip = |data: &[u8]| {
+            let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(data).unwrap();
+            encoder.finish().unwrap()
+        };
+        let gzipped = gzip(contents.as_bytes());
+        let (head, tail) = contents.split_at(1000);
+        let multi_member = [gzip(head.as_bytes()), gzip(tail.as_bytes())].concat();
+        let zstded = zstd::encode_all(contents.as_bytes(), 3).unwrap();
+        let scratch = Scratch::new();
+        scratch.write("tree/plain.txt", &contents);
+        scratch.write("tree/file.gz", &gzipped);
+        scratch.write("tree/pigz.gz", &multi_member);
+        // Compression is detected by magic bytes, not the extension.
+        scratch.write("tree/misnamed.txt", &zstded);
+        scratch.write("tree/tiny", "x");
+        let options = HashOptions {
+            decompress: true,
+            ..Default::default()
+        };
+        let (hashed, _) = hash_files("tree", &options).unwrap();
+        let file = |path: &str| hashed.iter().find(|file| file.path == path).unwrap();
+        let expected = blake3::hash(contents.as_bytes());
+        for path in ["plain.txt", "file.gz", "pigz.gz", "misnamed.txt"] {
+            assert_eq!(file(path).hash, expected, "{}", path);
+        }
+        assert_eq!(file("file.gz").size, gzipped.len() as u64);
+        assert_eq!(file("misnamed.txt").size, zstded.len() as u64);
+        assert_eq!(file("tiny").hash, blake3::hash(b"x"));
+
+        let (raw, _) = hash_files("tree", &HashOptions::default()).unwrap();
+        let raw_gz = raw.iter().find(|file| file.path == "file.gz").unwrap();
+        assert_eq!(raw_gz.hash, blake3::hash(&gzipped));
+
+        // Starts like a gzip file, but isn't one.
+        scratch.write("tree/broken.gz", [0x1f, 0x8b, 0, 1, 2, 3]);
+        assert!(hash_files("tree", &options).is_err());
+    }
+
+    #[test]
+    fn validation_errors_name_the_file() {
+        let scratch = Scratch::new();
+        scratch.write("tree/a.txt", "a");
+        scratch.write("tree/sub/b.txt", "b");
+        create_hashfile_with_options("tree", &HashOptions::default()).unwrap();
+        // Root can read anything, so a folder in the file's place stands
+        // in for one that can't be read.
+        std::fs::remove_file("tree/sub/b.txt").unwrap();
+        std::fs::create_dir("tree/sub/b.txt").unwrap();
+        let kind = std::fs::read("tree/sub/b.txt").unwrap_err().kind();
+
+        let err = validate_hashfile_with_options("tree", &HashOptions::default())
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), kind);
+        let message = err.to_string();
+        assert!(message.contains("'tree/sub/b.txt'"), "{}", message);
+
+        let options = HashOptions {
+            continue_on_error: true,
+            ..Default::default()
+        };
+        let report = validate_hashfile_with_options("tree", &options).unwrap();
+        assert_eq!(report.read_errors.len(), 1);
+        assert_eq!(report.read_errors[0].path, "tree/sub/b.txt");
+    }
+
+    #[test]
+    fn hashing_stats_each_file_at_most_once_plus_the_progress_pre_scan() {
+        let scratch = Scratch::new();
+        scratch.write("tree/a.txt", "a");
+        scratch.write("tree/sub/b.txt", "b");
+        scratch.write("tree/sub/c.txt", "c");
+        let stats_while = |options: &HashOptions| {
+            let before = FILES_STATED.load(Ordering::Relaxed);
+            hash_files("tree", options).unwrap();
+            FILES_STATED.load(Ordering::Relaxed) - before
+        };
+        assert_eq!(stats_while(&HashOptions::default()), 0);
+        let wants_metadata = || HashOptions {
+            record_mtime: true,
+            mmap_min_size: 1 << 20,
+            include_ownership: cfg!(unix),
+            max_in_flight_bytes: NonZeroU64::new(1 << 30),
+            ..Default::default()
+        };
+        assert_eq!(stats_while(&wants_metadata()), 3);
+        let with_progress = HashOptions {
+            progress: Some(Box::new(|_| {})),
+            ..wants_metadata()
+        };
+        assert_eq!(stats_while(&with_progress), 6);
+    }
+
+    #[cfg(feature = "decompress")]
+    #[test]
+    fn decompressing_reuses_the_files_metadata() {
+        let scratch = Scratch::new();
+        let zstded = zstd::encode_all(&b"contents"[..], 3).unwrap();
+        scratch.write("tree/a.zst", &zstded);
+        scratch.write("tree/b.zst", &zstded);
+        let stats_while = |options: &HashOptions| {
+            let before = FILES_STATED.load(Ordering::Relaxed);
+            hash_files("tree", options).unwrap();
+            FILES_STATED.load(Ordering::Relaxed) - before
+        };
+        let decompress = HashOptions {
+            decompress: true,
+            ..Default::default()
+        };
+        // Nothing else needs the metadata, so only the size does.
+        assert_eq!(stats_while(&decompress), 2);
+        let with_mtime = HashOptions {
+            decompress: true,
+            record_mtime: true,
+            ..Default::default()
+        };
+        assert_eq!(stats_while(&with_mtime), 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn regular_files_only_names_the_first_irregular_entry() {
+        let scratch = Scratch::new();
+        scratch.write("tree/a.txt", "a");
+        scratch.write("tree/sub/b.txt", "b");
+        let options = HashOptions {
+            regular_files_only: true,
+            // Neither of these get a say.
+            resolve_symlinks: true,
+            special_files: SpecialFiles::Skip,
+            ..Default::default()
+        };
+        assert_eq!(hash_files("tree", &options).unwrap().0.len(), 2);
+
+        std::os::unix::fs::symlink("../a.txt", "tree/sub/link.txt").unwrap();
+        let err = hash_files("tree", &options).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::Unsupported);
+        let message = err.to_string();
+        assert!(
+            message.contains("symlink 'tree/sub/link.txt'"),
+            "{}",
+            message
+        );
+
+        let fifo = std::ffi::CString::new("tree/sub/a_fifo").unwrap();
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o644) }, 0);
+        let message = hash_files("tree", &options).err().unwrap().to_string();
+        assert!(
+            message.contains("special file 'tree/sub/a_fifo'"),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn read_buffer_size_only_changes_how_much_is_read_at_once() {
+        /// Hands out `contents`, recording how much each read asked for.
+        struct Recording<'a> {
+            contents: &'a [u8],
+            asked_for: Vec<usize>,
+        }
+        impl Read for Recording<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> IOResult<usize> {
+                self.asked_for.push(buf.len());
+                self.contents.read(buf)
+            }
+        }
+
+        let contents: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        let mut reader = Recording {
+            contents: &contents,
+            asked_for: Vec::new(),
+        };
+        let mut hasher = Hasher::new();
+        update_reader(&mut hasher, &mut reader, 4096).unwrap();
+        assert_eq!(hasher.finalize(), blake3::hash(&contents));
+        assert!(reader.asked_for.iter().all(|&len| len == 4096));
+        assert_eq!(reader.asked_for.len(), contents.len().div_ceil(4096) + 1);
+
+        let scratch = Scratch::new();
+        scratch.write("tree/big.bin", &contents);
+        scratch.write("tree/small.txt", "small");
+        let hashes = |read_buffer_size| {
+            let options = HashOptions {
+                read_buffer_size: NonZeroUsize::new(read_buffer_size),
+                // Streams every file, rather than mapping it.
+                mmap_min_size: u64::MAX,
+                ..Default::default()
+            };
+            let (hashed, _) = hash_files("tree", &options).unwrap();
+            hashed.into_iter().map(|file| file.hash).collect::<Vec<_>>()
+        };
+        let expected = hashes(0);
+        assert_eq!(expected[0], blake3::hash(&contents));
+        for read_buffer_size in [1, 7, 4096, 1 << 20] {
+            assert_eq!(hashes(read_buffer_size), expected);
+        }
+    }
+}
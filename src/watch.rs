@@ -0,0 +1,225 @@
+use crate::fs::{get_files, EntryFilter};
+use crate::incremental::DirectoryHasher;
+use crate::options::HashOptions;
+use crate::types::HashedFile;
+use crate::util::{hash_file, oi_vei};
+use crate::{hash_directory, IOResult};
+use blake3::Hash;
+use camino::{Utf8Path, Utf8PathBuf};
+use notify::{RecursiveMode, Watcher};
+use std::collections::BTreeSet;
+use std::io::{Error, ErrorKind};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::time::Duration;
+
+/// How long the directory has to stay quiet before we re-hash anything.
+/// Editors and copy tools tend to fire off several events per file
+/// (create, modify, modify, close...), and there's no point re-hashing
+/// a file once for each of them.
+const DEBOUNCE: Duration = Duration::from_millis(250);
+
+/// Everything that changed in the watched directory since the last update.
+pub struct WatchUpdate {
+    /// Files that were created or modified, freshly re-hashed.
+    pub changed: Vec<HashedFile>,
+    /// Relative paths of files that no longer exist.
+    pub removed: Vec<String>,
+    /// The up-to-date directory aggregate, identical to what a fresh
+    /// `hash_directory` would produce once the directory settles.
+    pub hash: Hash,
+}
+
+/// Hashes the directory at `dir_path`, then watches it for changes and
+/// re-hashes only the files that changed, calling `callback` with each
+/// (debounced) batch of updates.
+///
+/// Follows the same visibility rules as `hash_directory`, so changes to
+/// hidden entries are ignored. Blocks until `callback` returns `false`.
+pub fn watch_directory<F>(dir_path: &str, mut callback: F) -> IOResult<()>
+where
+    F: FnMut(&WatchUpdate) -> bool,
+{
+    let (sender, receiver) = channel();
+    let mut watcher = notify::recommended_watcher(sender).map_err(notify_error)?;
+    // Watch before the initial hash, so nothing that changes
+    // while it's running gets missed.
+    watcher
+        .watch(
+            Utf8Path::new(dir_path).as_std_path(),
+            RecursiveMode::Recursive,
+        )
+        .map_err(notify_error)?;
+    // Events come back with absolute paths, so that's what we strip.
+    let root = Utf8Path::new(dir_path).canonicalize_utf8()?;
+    let mut hasher = DirectoryHasher::from(hash_directory(dir_path)?);
+
+    loop {
+        let mut paths = BTreeSet::new();
+        // Block until something happens, then keep collecting
+        // until things have been quiet for DEBOUNCE.
+        let mut event = match receiver.recv() {
+            Ok(event) => event,
+            Err(_) => return Ok(()),
+        };
+        loop {
+            let received = event.map_err(notify_error)?;
+            // Our own reads (including the initial hash) show up as
+            // access events, and those never change anything.
+            if !received.kind.is_access() {
+                for path in received.paths {
+                    if let Ok(path) = Utf8PathBuf::from_path_buf(path) {
+                        paths.insert(path);
+                    }
+                }
+            }
+            event = match receiver.recv_timeout(DEBOUNCE) {
+                Ok(event) => event,
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            };
+        }
+
+        let mut update = WatchUpdate {
+            changed: Vec::new(),
+            removed: Vec::new(),
+            hash: hasher.hash(),
+        };
+        for path in paths {
+            apply_change(&root, &path, &mut hasher, &mut update)?;
+        }
+        if update.changed.is_empty() && update.removed.is_empty() {
+            continue;
+        }
+        update.hash = hasher.hash();
+        if !callback(&update) {
+            return Ok(());
+        }
+    }
+}
+
+/// Brings `hasher` up to date with whatever is currently at `path`.
+fn apply_change(
+    root: &Utf8Path,
+    path: &Utf8Path,
+    hasher: &mut DirectoryHasher,
+    update: &mut WatchUpdate,
+) -> IOResult<()> {
+    const HIDDEN_ENTRY_PREFIX: char = '.';
+    let Ok(relative) = path.strip_prefix(root) else {
+        return Ok(());
+    };
+    // Anything hidden, or beneath something hidden, was never hashed.
+    if relative.as_str().is_empty()
+        || relative
+            .components()
+            .any(|c| c.as_str().starts_with(HIDDEN_ENTRY_PREFIX))
+    {
+        return Ok(());
+    }
+    let relative = oi_vei(relative.as_str());
+
+    let hashed = match path.symlink_metadata() {
+        Ok(meta) if meta.is_file() => {
+            hash_file(path, &relative, &HashOptions::default(), None).map(|file| vec![file])
+        }
+        // A whole folder was created or moved in.
+        Ok(meta) if meta.is_dir() => get_files(path, EntryFilter::default()).map(|files| {
+            let prefix_len = root.as_str().len() + 1;
+            files
+                .iter()
+                .filter_map(|file_path| {
+                    let stripped = &file_path.as_str()[prefix_len..];
+                    // Files that vanish in the meantime get their own event.
+                    hash_file(file_path, stripped, &HashOptions::default(), None).ok()
+                })
+                .collect()
+        }),
+        Ok(_) => return Ok(()),
+        Err(e) => Err(e),
+    };
+    match hashed {
+        Ok(files) => {
+            for file in files {
+                add_if_changed(file, hasher, update);
+            }
+        }
+        // Gone by the time it was looked at (or hashed), which is a removal either way.
+        // We can't tell whether a file or a folder disappeared, so remove both.
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            update
+                .removed
+                .extend(hasher.remove_file(&relative).map(|f| f.path));
+            update
+                .removed
+                .extend(hasher.remove_dir(&relative).into_iter().map(|f| f.path));
+        }
+        Err(e) => return Err(e),
+    }
+    Ok(())
+}
+
+/// Adds `file` to `hasher`, only reporting it if it's new or its hash changed.
+#[inline]
+fn add_if_changed(file: HashedFile, hasher: &mut DirectoryHasher, update: &mut WatchUpdate) {
+    let unchanged = hasher
+        .add_file(file.clone())
+        .is_some_and(|old| old.hash == file.hash);
+    if !unchanged {
+        update.changed.push(file);
+    }
+}
+
+/// `notify::Error` needs to be explicitly converted to IOError.
+fn notify_error(e: notify::Error) -> Error {
+    match e.kind {
+        notify::ErrorKind::Io(e) => e,
+        _ => Error::other(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::Scratch;
+    use std::thread;
+
+    #[test]
+    fn reports_the_new_aggregate_after_each_change() {
+        let scratch = Scratch::new();
+        scratch.write("tree/a.txt", "a");
+        scratch.write("tree/sub/b.txt", "b");
+        let tree = scratch.path().join("tree");
+        let editor = thread::spawn(move || {
+            // Give the watcher time to start, and each change time to settle.
+            thread::sleep(Duration::from_millis(600));
+            std::fs::write(tree.join("a.txt"), "edited").unwrap();
+            thread::sleep(Duration::from_millis(900));
+            std::fs::remove_file(tree.join("sub/b.txt")).unwrap();
+        });
+        let mut updates = Vec::new();
+        watch_directory("tree", |update| {
+            updates.push((
+                update
+                    .changed
+                    .iter()
+                    .map(|f| f.path.clone())
+                    .collect::<Vec<_>>(),
+                update.removed.clone(),
+                update.hash,
+                hash_directory("tree").unwrap().hash,
+            ));
+            updates.len() < 2
+        })
+        .unwrap();
+        editor.join().unwrap();
+
+        let (changed, removed, hash, live) = &updates[0];
+        assert_eq!(changed, &["a.txt"]);
+        assert!(removed.is_empty());
+        assert_eq!(hash, live);
+        let (changed, removed, hash, live) = &updates[1];
+        assert!(changed.is_empty());
+        assert_eq!(removed, &["sub/b.txt"]);
+        assert_eq!(hash, live);
+    }
+}
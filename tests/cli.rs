@@ -0,0 +1,127 @@
+//! End-to-end checks of the `b3hash` binary's output.
+#![cfg(feature = "cli")]
+
+use std::path::Path;
+use std::process::Output;
+use tempfile::TempDir;
+
+/// Runs the binary with `args` inside `dir`, where its hashfile lives.
+fn b3hash(dir: &Path, args: &[&str]) -> Output {
+    std::process::Command::new(env!("CARGO_BIN_EXE_b3hash"))
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+/// A scratch directory containing `tree/` with each of `files` in it.
+fn scratch(files: &[(&str, &str)]) -> TempDir {
+    let dir = TempDir::new().unwrap();
+    for (path, contents) in files {
+        let path = dir.path().join("tree").join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+    dir
+}
+
+#[test]
+fn validate_json_is_the_whole_report() {
+    let dir = scratch(&[("a.txt", "a"), ("sub/b.txt", "b")]);
+    assert!(b3hash(dir.path(), &["create", "tree"]).status.success());
+    std::fs::write(dir.path().join("tree/a.txt"), "changed").unwrap();
+    std::fs::write(dir.path().join("tree/sub/new.txt"), "new").unwrap();
+
+    let output = b3hash(dir.path(), &["validate", "tree", "--format", "json"]);
+    assert!(!output.status.success());
+    let report: serde_json::Value = serde_json::from_str(&stdout(&output)).unwrap();
+    assert_eq!(report["failed_files"], serde_json::json!(["tree/a.txt"]));
+    assert_eq!(report["added"], serde_json::json!(["tree/sub/new.txt"]));
+    assert_eq!(report["count_mismatches"], serde_json::json!([]));
+    assert_eq!(report["read_errors"], serde_json::json!([]));
+    assert!(report["structure_changed"].is_null());
+}
+
+#[test]
+fn hash_json_lists_every_file() {
+    let dir = scratch(&[("a.txt", "a"), ("sub/b.txt", "b")]);
+    let output = b3hash(dir.path(), &["hash", "tree", "--format", "json"]);
+    assert!(output.status.success());
+    let hashed: serde_json::Value = serde_json::from_str(&stdout(&output)).unwrap();
+    let expected = b3hash::hash_directory(dir.path().join("tree").to_str().unwrap()).unwrap();
+    assert_eq!(
+        hashed["hash"],
+        serde_json::json!(expected.hash.to_hex().as_str())
+    );
+    let paths: Vec<_> = hashed["files"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|file| file["path"].as_str().unwrap())
+        .collect();
+    assert_eq!(paths, ["a.txt", "sub/b.txt"]);
+}
+
+#[test]
+fn hash_prints_more_at_each_verbosity() {
+    let dir = scratch(&[("a.txt", "a"), ("sub/b.txt", "b")]);
+    let expected = b3hash::hash_directory(dir.path().join("tree").to_str().unwrap()).unwrap();
+    let checksum = expected.hash.to_hex();
+
+    let quiet = b3hash(dir.path(), &["hash", "tree", "--quiet"]);
+    assert!(quiet.status.success());
+    assert_eq!(stdout(&quiet), format!("{}\n", checksum));
+
+    let normal = stdout(&b3hash(dir.path(), &["hash", "tree"]));
+    assert!(normal.contains(&format!("Directory checksum: {}\n", checksum)));
+    assert!(normal.contains("File count: 2\n"));
+    assert!(!normal.contains("Files:"));
+
+    let verbose = stdout(&b3hash(dir.path(), &["hash", "tree", "--verbose"]));
+    assert!(verbose.contains(&format!("Directory checksum: {}\n", checksum)));
+    assert!(verbose.contains("File count: 2\n"));
+    let listing = verbose.split_once("Files:\n").unwrap().1;
+    let lines: Vec<_> = listing.lines().collect();
+    let expected_lines: Vec<_> = expected
+        .iter()
+        .map(|file| format!("{} {}", file.hash.to_hex(), file.path))
+        .collect();
+    assert_eq!(lines, expected_lines);
+}
+
+#[test]
+fn validate_prints_only_failures_when_quiet() {
+    let dir = scratch(&[("a.txt", "a"), ("sub/b.txt", "b")]);
+    let create = b3hash(dir.path(), &["create", "tree", "--quiet"]);
+    assert!(create.status.success());
+    assert_eq!(stdout(&create), "");
+
+    let quiet = b3hash(dir.path(), &["validate", "tree", "-q"]);
+    assert!(quiet.status.success());
+    assert_eq!(stdout(&quiet), "");
+    let normal = stdout(&b3hash(dir.path(), &["validate", "tree"]));
+    assert!(normal.starts_with("All files validated\n"));
+
+    std::fs::write(dir.path().join("tree/a.txt"), "changed").unwrap();
+    let quiet = b3hash(dir.path(), &["validate", "tree", "-q"]);
+    assert!(!quiet.status.success());
+    assert_eq!(stdout(&quiet), "tree/a.txt\n");
+    let normal = b3hash(dir.path(), &["validate", "tree"]);
+    assert!(!normal.status.success());
+    assert_eq!(
+        stdout(&normal),
+        "Validation failed:\ntree/a.txt\nFiles failed: 1\n"
+    );
+}
+
+#[test]
+fn quiet_and_verbose_conflict() {
+    let dir = scratch(&[("a.txt", "a")]);
+    let output = b3hash(dir.path(), &["hash", "tree", "-q", "-v"]);
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+}